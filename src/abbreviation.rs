@@ -0,0 +1,332 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Shortcuts users type in place of longer text, like `brb` for "be right back" or `mm` for
+//! "my mom". Each user keeps their own table, and every composed message is expanded with it.
+//! Only whole words expand, and an expansion follows the case the shortcut was written in.
+
+use std::collections::HashMap;
+
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, user, Error};
+
+/// The longest abbreviation, in characters.
+const MAX_ABBREVIATION_CHARS: usize = 20;
+/// The longest expansion, in characters.
+const MAX_EXPANSION_CHARS: usize = 200;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list_abbreviations = warp::get()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("abbreviations"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_abbreviations);
+
+    let set_abbreviation = warp::post()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("abbreviations"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(set_abbreviation);
+
+    let delete_abbreviation = warp::delete()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("abbreviations"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(delete_abbreviation);
+
+    let expand = warp::get()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("abbreviations"))
+        .and(warp::path("expand"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(expand);
+
+    list_abbreviations
+        .or(set_abbreviation)
+        .or(delete_abbreviation)
+        .or(expand)
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Abbreviation {
+    /// What the user types. Matching ignores case.
+    pub abbreviation: String,
+    /// What it stands for.
+    pub expansion: String,
+}
+
+impl Abbreviation {
+    fn validate(mut self) -> Result<Self, Error> {
+        self.abbreviation = self.abbreviation.trim().to_string();
+        self.expansion = self.expansion.trim().to_string();
+        if self.abbreviation.is_empty()
+            || self.abbreviation.chars().count() > MAX_ABBREVIATION_CHARS
+            || !self.abbreviation.chars().all(char::is_alphanumeric)
+            || self.expansion.is_empty()
+            || self.expansion.chars().count() > MAX_EXPANSION_CHARS
+        {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(self)
+    }
+}
+
+/// Whether `c` can be part of a word. Apostrophes, hyphens and underscores join letters into a
+/// single word, so `mm` does not expand inside `mm-hmm`.
+fn in_word(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '\'' | '\u{2019}' | '-' | '_')
+}
+
+/// Where each word in `text` starts and ends, leaving out the joining characters at its edges
+/// so a quoted word is still found.
+fn words(text: &str) -> Vec<(usize, usize)> {
+    let edge = |c: char| !c.is_alphanumeric();
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (in_word(c), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                let word = &text[s..i];
+                let core = word.trim_matches(edge);
+                if !core.is_empty() {
+                    let core_start = s + word.len() - word.trim_start_matches(edge).len();
+                    spans.push((core_start, core_start + core.len()));
+                }
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    spans
+}
+
+/// Writes `expansion` in the case `written` was typed in: capitalized when it was, and in
+/// capitals when it was all capitals. Otherwise the expansion is kept as it was saved, so
+/// expansions holding names keep their capitals.
+fn match_case(written: &str, expansion: &str) -> String {
+    let letters = written
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .collect::<Vec<char>>();
+    match letters.as_slice() {
+        [first, rest @ ..] if first.is_uppercase() => {
+            if !rest.is_empty() && rest.iter().all(|c| c.is_uppercase()) {
+                expansion.to_uppercase()
+            } else {
+                let mut chars = expansion.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            }
+        }
+        _ => expansion.to_string(),
+    }
+}
+
+/// A user's abbreviations, ready to expand.
+#[derive(Debug, Default, Clone)]
+pub struct Abbreviations {
+    /// The expansion of each lowercased abbreviation.
+    entries: HashMap<String, String>,
+}
+
+impl Abbreviations {
+    pub fn new(abbreviations: &[Abbreviation]) -> Self {
+        Abbreviations {
+            entries: abbreviations
+                .iter()
+                .map(|a| (a.abbreviation.to_lowercase(), a.expansion.clone()))
+                .collect(),
+        }
+    }
+
+    /// Expands every whole word in `text` that is one of the abbreviations.
+    pub fn expand(&self, text: &str) -> String {
+        if self.entries.is_empty() {
+            return text.to_string();
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut copied = 0;
+        for (start, end) in words(text) {
+            let word = &text[start..end];
+            if !word.chars().all(char::is_alphanumeric) {
+                continue;
+            }
+            if let Some(expansion) = self.entries.get(&word.to_lowercase()) {
+                out.push_str(&text[copied..start]);
+                out.push_str(&match_case(word, expansion));
+                copied = end;
+            }
+        }
+        out.push_str(&text[copied..]);
+        out
+    }
+}
+
+async fn abbreviation_list(conn: &db::Conn, uid: i32) -> Result<Vec<Abbreviation>, Error> {
+    Ok(conn
+        .query(
+            r#"
+            SELECT abbreviation, expansion FROM abbreviations
+            WHERE user_id = $1
+            ORDER BY lower(abbreviation)
+            "#,
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| Abbreviation {
+            abbreviation: row.get("abbreviation"),
+            expansion: row.get("expansion"),
+        })
+        .collect())
+}
+
+/// Reads the abbreviations of the user `uid`.
+pub async fn abbreviations(conn: &db::Conn, uid: i32) -> Result<Abbreviations, Error> {
+    Ok(Abbreviations::new(&abbreviation_list(conn, uid).await?))
+}
+
+pub async fn list_abbreviations(
+    username: String,
+    _actor: String,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(&abbreviation_list(&conn, uid).await?))
+}
+
+/// Adds an abbreviation, or replaces the one written the same way.
+pub async fn set_abbreviation(
+    username: String,
+    _actor: String,
+    abbreviation: Abbreviation,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    let abbreviation = abbreviation.validate()?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let created: bool = conn
+        .query_one(
+            r#"
+            INSERT INTO abbreviations (user_id, abbreviation, expansion) VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, lower(abbreviation))
+                DO UPDATE SET abbreviation = EXCLUDED.abbreviation, expansion = EXCLUDED.expansion
+            RETURNING xmax = 0 AS created
+            "#,
+            &[&uid, &abbreviation.abbreviation, &abbreviation.expansion],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .get("created");
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(with_status(json(&abbreviation), status))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AbbreviationQuery {
+    pub abbreviation: String,
+}
+
+pub async fn delete_abbreviation(
+    username: String,
+    _actor: String,
+    query: AbbreviationQuery,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let deleted = conn
+        .execute(
+            r#"
+            DELETE FROM abbreviations
+            WHERE user_id = $1 AND lower(abbreviation) = lower($2)
+            "#,
+            &[&uid, &query.abbreviation.trim()],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if deleted == 0 {
+        return Err(Error::NotFound.into());
+    }
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExpandQuery {
+    pub text: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Expansion {
+    pub text: String,
+    /// The text with the user's abbreviations expanded.
+    pub expanded: String,
+}
+
+pub async fn expand(
+    username: String,
+    _actor: String,
+    query: ExpandQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let expanded = abbreviations(&conn, uid).await?.expand(&query.text);
+    Ok(json(&Expansion {
+        text: query.text,
+        expanded,
+    }))
+}
@@ -0,0 +1,232 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Recordings tiles can play instead of synthesized speech. They are checked by their content
+//! like images, stored the same way once per distinct content in the configured [`Storage`], and
+//! collected once no tile plays them.
+
+use std::io::Cursor;
+
+use mobc_postgres::tokio_postgres::Transaction;
+use symphonia::core::{
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+    units::TimeBase,
+};
+
+use crate::{db, storage::Storage, util, Error};
+
+/// The largest recording accepted, in bytes.
+pub const MAX_AUDIO_BYTES: usize = 5 * 1024 * 1024;
+/// The longest recording accepted, in seconds.
+pub const MAX_AUDIO_SECONDS: f64 = 60.0;
+
+/// An uploaded recording, not yet stored.
+pub struct Audio {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    pub hash: String,
+}
+
+impl Audio {
+    /// Checks an upload really is a recording in a supported format, the one it was declared as
+    /// if a type was given, and within the size and length limits. The recording keeps the type
+    /// found in its content, never the declared one.
+    pub async fn from_upload(data: Vec<u8>, declared_type: Option<&str>) -> Result<Self, Error> {
+        // Measuring a recording reads every packet of it, so it runs off the executor.
+        let declared_type = declared_type.map(str::to_string);
+        tokio::task::spawn_blocking(move || Audio::check_upload(data, declared_type.as_deref()))
+            .await
+            .map_err(|e| Error::IOError(e.into()))?
+    }
+
+    fn check_upload(data: Vec<u8>, declared_type: Option<&str>) -> Result<Self, Error> {
+        if data.len() > MAX_AUDIO_BYTES {
+            return Err(Error::UploadTooLarge(format!(
+                "recording is larger than {} bytes",
+                MAX_AUDIO_BYTES
+            )));
+        }
+        let content_type = sniff(&data).ok_or_else(|| {
+            Error::InvalidUpload(
+                "recording is not an MP3, WAV, Ogg, FLAC, MP4 or WebM recording".to_string(),
+            )
+        })?;
+        if let Some(declared) = declared_type {
+            // Recorders like to add the codec, as in `audio/webm;codecs=opus`.
+            let declared = declared
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase();
+            let declared = match declared.as_str() {
+                "audio/mp3" => "audio/mpeg",
+                "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => "audio/wav",
+                "audio/x-m4a" | "audio/m4a" => "audio/mp4",
+                "audio/x-flac" => "audio/flac",
+                "video/webm" => "audio/webm",
+                other => other,
+            };
+            if declared != content_type {
+                return Err(Error::InvalidUpload(format!(
+                    "recording was declared as {} but its content is {}",
+                    declared, content_type
+                )));
+            }
+        }
+        let seconds = duration(&data, content_type).ok_or_else(|| {
+            Error::InvalidUpload("recording is corrupt or of unknown length".to_string())
+        })?;
+        if seconds > MAX_AUDIO_SECONDS {
+            return Err(Error::UploadTooLarge(format!(
+                "recording is {:.1} seconds long, but may be at most {} seconds",
+                seconds, MAX_AUDIO_SECONDS
+            )));
+        }
+        let hash = util::hash(data.as_slice());
+        Ok(Audio {
+            data,
+            content_type: content_type.to_string(),
+            hash,
+        })
+    }
+}
+
+/// Works out the type of a recording from its content.
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"ID3") || (data.len() > 1 && data[0] == 0xff && data[1] & 0xe0 == 0xe0) {
+        Some("audio/mpeg")
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WAVE"[..]) {
+        Some("audio/wav")
+    } else if data.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if data.starts_with(b"fLaC") {
+        Some("audio/flac")
+    } else if data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        Some("audio/webm")
+    } else if data.get(4..8) == Some(&b"ftyp"[..]) {
+        Some("audio/mp4")
+    } else {
+        None
+    }
+}
+
+/// Reads how long a recording is, in seconds. Recordings that don't say so in their headers, as
+/// browsers tend to make them, are measured by the end of their last packet.
+fn duration(data: &[u8], content_type: &str) -> Option<f64> {
+    let mut hint = Hint::new();
+    hint.mime_type(content_type);
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data.to_vec())), Default::default());
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+    let track = probed.format.default_track()?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let time_base = params
+        .time_base
+        .or_else(|| params.sample_rate.map(|rate| TimeBase::new(1, rate)))?;
+    let frames = match params.n_frames {
+        Some(frames) => frames,
+        None => {
+            let mut end = 0;
+            while let Ok(packet) = probed.format.next_packet() {
+                if packet.track_id() == track_id {
+                    end = end.max(packet.ts() + packet.dur());
+                }
+            }
+            end
+        }
+    };
+    let time = time_base.calc_time(frames);
+    Some(time.seconds as f64 + time.frac)
+}
+
+/// Makes sure `audio` is stored, reusing the existing copy if there is one. The recording is
+/// locked until the transaction ends so it can't be collected before a tile refers to it.
+pub(crate) async fn store(
+    tx: &Transaction<'_>,
+    storage: &dyn Storage,
+    audio: &Audio,
+) -> Result<(), Error> {
+    let existing = tx
+        .execute(
+            "SELECT 1 FROM recordings WHERE hash = $1 FOR UPDATE",
+            &[&audio.hash],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if existing == 0 {
+        storage.put(&audio.hash, &audio.data).await?;
+        tx.execute(
+            r#"
+            INSERT INTO recordings (hash, content_type) VALUES ($1, $2)
+            ON CONFLICT (hash) DO UPDATE SET hash = EXCLUDED.hash
+            "#,
+            &[&audio.hash, &audio.content_type],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    }
+    Ok(())
+}
+
+/// Reads the contents and type of the recording `hash`.
+pub(crate) async fn load(
+    conn: &db::Conn,
+    storage: &dyn Storage,
+    hash: &str,
+) -> Result<(Vec<u8>, String), Error> {
+    let row = conn
+        .query_opt(
+            "SELECT content_type FROM recordings WHERE hash = $1",
+            &[&hash],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    Ok((storage.get(hash).await?, row.get("content_type")))
+}
+
+/// Deletes those of the `released` recordings no tile plays any more.
+pub(crate) async fn collect(
+    conn: &mut db::Conn,
+    storage: &dyn Storage,
+    released: &[String],
+) -> Result<(), Error> {
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    let rows = tx
+        .query(
+            "DELETE FROM recordings WHERE hash = ANY($1) AND ref_count <= 0 RETURNING hash",
+            &[&released],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    // The contents go before the rows are released, so a concurrent upload of the same
+    // recording waits for them to be gone and stores them afresh.
+    for row in rows.iter() {
+        storage.delete(row.get("hash")).await?;
+    }
+    tx.commit().await.map_err(Error::DBError)?;
+    Ok(())
+}
@@ -38,7 +38,7 @@ use warp::{
 use crate::{db, guard, Error};
 
 const TOKEN_EXPIRATION: u64 = 604800;
-const SALT_SIZE: usize = 16;
+pub(crate) const SALT_SIZE: usize = 16;
 
 pub fn api(
     db_pool: db::Pool,
@@ -169,7 +169,7 @@ impl BearerToken {
     }
 }
 
-fn secure_hash(s: String) -> String {
+pub(crate) fn secure_hash(s: String) -> String {
     let mut hasher = Sha3::sha3_256();
     hasher.input_str(s.as_str());
     hasher.result_str()
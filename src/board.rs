@@ -0,0 +1,373 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Boards: the pages of tiles a user picks from. Each category of their tiles is a board, and the
+//! server generates a few more from how the user speaks: their favorites, the tiles they used
+//! most recently and the ones they used most this week. Every board is listed alike, with the URL
+//! it is read from, so clients show generated boards just as they show the others. Generated
+//! boards are read under `boards/generated`, apart from categories, so no category can hide one
+//! or be hidden by one.
+
+use std::collections::HashSet;
+
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, Json},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    db, guard,
+    tile::{self, Tile, TilePage, TileQuery, TILE_COLUMNS},
+    user, Error,
+};
+
+/// How many tiles a generated board shows unless asked for more.
+const DEFAULT_BOARD_SIZE: i64 = 24;
+const MAX_BOARD_SIZE: i64 = 200;
+/// How many days of use count towards the most used board.
+pub(crate) const FREQUENT_DAYS: i32 = 7;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list_boards = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("boards"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_boards);
+
+    let read_generated_board = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("boards"))
+        .and(warp::path("generated"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(read_generated_board);
+
+    let read_board = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("boards"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(read_board);
+
+    let add_favorite = warp::put()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("tiles"))
+        .and(warp::path::param())
+        .and(warp::path("favorite"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(add_favorite);
+
+    let remove_favorite = warp::delete()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("tiles"))
+        .and(warp::path::param())
+        .and(warp::path("favorite"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool))
+        .and_then(remove_favorite);
+
+    list_boards
+        .or(read_generated_board)
+        .or(read_board)
+        .or(add_favorite)
+        .or(remove_favorite)
+}
+
+/// The boards the server fills in itself. Categories may share their names, as they are read
+/// from a path of their own.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GeneratedBoard {
+    /// The tiles the user marked as favorites, in the order they were added.
+    Favorites,
+    /// The tiles used most recently, newest first.
+    Recent,
+    /// The tiles used most over the past week.
+    Frequent,
+}
+
+impl GeneratedBoard {
+    pub const ALL: [GeneratedBoard; 3] = [
+        GeneratedBoard::Favorites,
+        GeneratedBoard::Recent,
+        GeneratedBoard::Frequent,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GeneratedBoard::Favorites => "favorites",
+            GeneratedBoard::Recent => "recent",
+            GeneratedBoard::Frequent => "frequent",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        GeneratedBoard::ALL
+            .iter()
+            .copied()
+            .find(|board| board.name() == name)
+    }
+
+    /// The tiles on the board, as rows ranked by a `rank` column, where `$1` is the user.
+    fn source(self) -> String {
+        match self {
+            GeneratedBoard::Favorites => r#"
+                SELECT tile_id, ROW_NUMBER() OVER (ORDER BY added_at, tile_id) AS rank
+                FROM favorite_tiles
+                WHERE user_id = $1
+            "#
+            .to_string(),
+            GeneratedBoard::Recent => r#"
+                SELECT tile_id, ROW_NUMBER() OVER (ORDER BY MAX(last_used_at) DESC, tile_id) AS rank
+                FROM tile_activity
+                WHERE user_id = $1
+                GROUP BY tile_id
+            "#
+            .to_string(),
+            GeneratedBoard::Frequent => format!(
+                r#"
+                SELECT tile_id, ROW_NUMBER() OVER (
+                    ORDER BY SUM(uses) DESC, MAX(last_used_at) DESC, tile_id
+                ) AS rank
+                FROM tile_activity
+                WHERE user_id = $1 AND used_on > CURRENT_DATE - {}
+                GROUP BY tile_id
+                "#,
+                FREQUENT_DAYS
+            ),
+        }
+    }
+
+    /// Reads up to `limit` tiles on the board of the user `uid`, with how many there are in all.
+    async fn tiles(self, conn: &db::Conn, uid: i32, limit: i64) -> Result<TilePage, Error> {
+        let rows = conn
+            .query(
+                format!(
+                    r#"
+                    SELECT {}, COUNT(*) OVER () AS total
+                    FROM tiles
+                    INNER JOIN ({}) AS board ON board.tile_id = tiles.id
+                    WHERE (tiles.user_id IS NULL OR tiles.user_id = $1)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM hidden_tiles
+                            WHERE hidden_tiles.user_id = $1 AND hidden_tiles.tile_id = tiles.id
+                        )
+                    ORDER BY board.rank
+                    LIMIT $2
+                    "#,
+                    TILE_COLUMNS,
+                    self.source()
+                )
+                .as_str(),
+                &[&uid, &limit],
+            )
+            .await
+            .map_err(Error::DBError)?;
+        let total = rows.first().map_or(0, |row| row.get("total"));
+        let mut tiles = rows.iter().map(Tile::from).collect::<Vec<Tile>>();
+        tile::personalize(conn, uid, &mut tiles).await?;
+        Ok(TilePage {
+            tiles,
+            total,
+            next_cursor: None,
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Board {
+    pub name: String,
+    /// Where the board's tiles are read, up to a `limit` of them, whichever kind it is.
+    pub url: String,
+    /// Whether the server fills the board in, rather than it being a category of tiles.
+    pub generated: bool,
+    /// How many tiles are on the board.
+    pub total: i64,
+}
+
+/// The phrase of each tile the user `uid` marked as a favorite, and whether it is in the library.
+pub(crate) async fn favorites(conn: &db::Conn, uid: i32) -> Result<HashSet<(String, bool)>, Error> {
+    Ok(conn
+        .query(
+            r#"
+            SELECT tiles.phrase, tiles.user_id IS NULL AS library
+            FROM favorite_tiles
+            INNER JOIN tiles ON tiles.id = favorite_tiles.tile_id
+            WHERE favorite_tiles.user_id = $1
+            "#,
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| (row.get("phrase"), row.get("library")))
+        .collect())
+}
+
+/// Lists the generated boards, then a board for each category of the tiles the user can see.
+pub async fn list_boards(username: String, pool: db::Pool) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let mut boards = Vec::new();
+    for board in GeneratedBoard::ALL.iter() {
+        boards.push(Board {
+            name: board.name().to_string(),
+            url: format!("/api/user/{}/boards/generated/{}", username, board.name()),
+            generated: true,
+            total: board.tiles(&conn, uid, 1).await?.total,
+        });
+    }
+    boards.extend(
+        conn.query(
+            r#"
+            SELECT category, COUNT(*) AS total
+            FROM tiles, UNNEST(categories) AS category
+            WHERE (user_id IS NULL OR user_id = $1)
+                AND NOT EXISTS (
+                    SELECT 1 FROM hidden_tiles
+                    WHERE hidden_tiles.user_id = $1 AND hidden_tiles.tile_id = tiles.id
+                )
+            GROUP BY category
+            ORDER BY category
+            "#,
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| Board {
+            url: format!(
+                "/api/user/{}/boards/{}",
+                username,
+                row.get::<_, &str>("category")
+            ),
+            name: row.get("category"),
+            generated: false,
+            total: row.get("total"),
+        }),
+    );
+    Ok(json(&boards))
+}
+
+/// Reads the tiles on the generated board `name`. Generated boards keep their own order and fit
+/// on one page, so they only take a `limit`.
+pub async fn read_generated_board(
+    username: String,
+    name: String,
+    query: TileQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let board = GeneratedBoard::from_name(&name).ok_or(Error::NotFound)?;
+    let limit = query.limit.unwrap_or(DEFAULT_BOARD_SIZE);
+    if !(1..=MAX_BOARD_SIZE).contains(&limit)
+        || query.phrase.is_some()
+        || query.category.is_some()
+        || query.sort.is_some()
+        || query.cursor.is_some()
+    {
+        return Err(Error::MalformedRequest.into());
+    }
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(&board.tiles(&conn, uid, limit).await?))
+}
+
+/// Reads the tiles on the category board `name`, which page and sort like any tile listing.
+pub async fn read_board(
+    username: String,
+    name: String,
+    mut query: TileQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    query.category = Some(name);
+    Ok(json(&tile::query_tiles(&conn, Some(uid), &query).await?))
+}
+
+/// Marks the tile `phrase` the user sees, their own or else the library's, as a favorite.
+pub async fn add_favorite(
+    username: String,
+    phrase: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let tile_id: i32 = conn
+        .query_opt(
+            r#"
+            SELECT id FROM tiles
+            WHERE (user_id = $1 OR user_id IS NULL)
+                AND phrase = $2
+                AND NOT EXISTS (
+                    SELECT 1 FROM hidden_tiles
+                    WHERE hidden_tiles.user_id = $1 AND hidden_tiles.tile_id = tiles.id
+                )
+            ORDER BY user_id NULLS LAST
+            LIMIT 1
+            "#,
+            &[&uid, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?
+        .get("id");
+    conn.execute(
+        r#"
+        INSERT INTO favorite_tiles (user_id, tile_id) VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        &[&uid, &tile_id],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn remove_favorite(
+    username: String,
+    phrase: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    conn.execute(
+        r#"
+        DELETE FROM favorite_tiles
+        USING tiles
+        WHERE favorite_tiles.tile_id = tiles.id
+            AND favorite_tiles.user_id = $1
+            AND tiles.phrase = $2
+        "#,
+        &[&uid, &phrase],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,145 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Caregivers are other accounts, such as parents and therapists, that a user trusts to manage
+//! their vocabulary on their behalf.
+
+use jsonwebtoken::DecodingKey;
+use warp::{
+    http::StatusCode,
+    reply::{json, Json},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, user, Error};
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list_caregivers = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("caregivers"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_caregivers);
+
+    let add_caregiver = warp::put()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("caregivers"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(add_caregiver);
+
+    let remove_caregiver = warp::delete()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("caregivers"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool))
+        .and_then(remove_caregiver);
+
+    list_caregivers.or(add_caregiver).or(remove_caregiver)
+}
+
+/// Whether `actor` may manage the resources of `username`, either by being that user or one of
+/// their caregivers.
+pub async fn can_manage(conn: &db::Conn, actor: &str, username: &str) -> Result<bool, Error> {
+    if actor == username {
+        return Ok(true);
+    }
+    Ok(conn
+        .query_opt(
+            r#"
+            SELECT 1
+            FROM caregivers
+            INNER JOIN users AS u ON u.id = caregivers.user_id
+            INNER JOIN users AS c ON c.id = caregivers.caregiver_id
+            WHERE u.username = $1 AND c.username = $2
+            "#,
+            &[&username, &actor],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .is_some())
+}
+
+async fn list_caregivers(username: String, pool: db::Pool) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(
+        &conn
+            .query(
+                r#"
+                SELECT username
+                FROM caregivers
+                INNER JOIN users
+                ON users.id = caregivers.caregiver_id
+                WHERE caregivers.user_id = $1
+                ORDER BY username ASC
+                "#,
+                &[&uid],
+            )
+            .await
+            .map_err(Error::DBError)?
+            .iter()
+            .map(|row| row.get("username"))
+            .collect::<Vec<String>>(),
+    ))
+}
+
+async fn add_caregiver(
+    username: String,
+    caregiver: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let caregiver_id = user::user_id(&conn, &caregiver).await?;
+    if uid == caregiver_id {
+        return Err(Rejection::from(Error::MalformedRequest));
+    }
+    conn.execute(
+        r#"
+        INSERT INTO caregivers (user_id, caregiver_id) VALUES ($1, $2)
+        ON CONFLICT DO NOTHING
+        "#,
+        &[&uid, &caregiver_id],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
+
+async fn remove_caregiver(
+    username: String,
+    caregiver: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let caregiver_id = user::user_id(&conn, &caregiver).await?;
+    conn.execute(
+        "DELETE FROM caregivers WHERE user_id = $1 AND caregiver_id = $2",
+        &[&uid, &caregiver_id],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,201 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Copying tiles, whole boards or an entire vocabulary from one account into another.
+
+use std::collections::HashSet;
+
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    caregiver, db, guard, template,
+    tile::{Tile, TILE_COLUMNS},
+    user, Error,
+};
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("copy"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool))
+        .and_then(copy_tiles)
+}
+
+/// What to do with a copied tile whose phrase the destination already uses.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+    /// Copy nothing and report the collisions.
+    Fail,
+    /// Copy everything else.
+    Skip,
+    /// Copy the tile under the first free name of the form "phrase (n)".
+    Rename,
+}
+
+/// Copies tiles from `from` into the user in the path. Without a `tile` or `category` every tile
+/// `from` owns is copied.
+#[derive(Serialize, Deserialize)]
+pub struct CopyRequest {
+    pub from: String,
+    pub tile: Option<String>,
+    pub category: Option<String>,
+    pub on_conflict: Option<ConflictStrategy>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Renamed {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct CopyReport {
+    pub copied: Vec<Tile>,
+    pub collisions: Vec<String>,
+    pub skipped: Vec<String>,
+    pub renamed: Vec<Renamed>,
+}
+
+fn free_name(phrase: &str, taken: &HashSet<String>) -> String {
+    (2..)
+        .map(|n| format!("{} ({})", phrase, n))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("some name is free")
+}
+
+async fn copy_tiles(
+    username: String,
+    actor: String,
+    req: CopyRequest,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    let mut conn = db::get_db_conn(&pool).await?;
+    if !caregiver::can_manage(&conn, &actor, &req.from).await? {
+        return Err(Rejection::from(Error::Unauthorized));
+    }
+    let src = user::user_id(&conn, &req.from).await?;
+    let dst = user::user_id(&conn, &username).await?;
+
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    let sources = tx
+        .query(
+            r#"
+            SELECT id, phrase
+            FROM tiles
+            WHERE user_id = $1
+                AND ($2::TEXT IS NULL OR phrase = $2)
+                AND ($3::TEXT IS NULL OR $3 = ANY(categories))
+            ORDER BY position ASC, id ASC
+            "#,
+            &[&src, &req.tile, &req.category],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if sources.is_empty() && (req.tile.is_some() || req.category.is_some()) {
+        return Err(Rejection::from(Error::NotFound));
+    }
+    let mut taken = tx
+        .query("SELECT phrase FROM tiles WHERE user_id = $1", &[&dst])
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| row.get("phrase"))
+        .collect::<HashSet<String>>();
+
+    let strategy = req.on_conflict.unwrap_or(ConflictStrategy::Fail);
+    let mut report: CopyReport = Default::default();
+    for source in sources.iter() {
+        let id: i32 = source.get("id");
+        let phrase: String = source.get("phrase");
+        let name = if !taken.contains(&phrase) {
+            phrase
+        } else {
+            match strategy {
+                ConflictStrategy::Fail => {
+                    report.collisions.push(phrase);
+                    continue;
+                }
+                ConflictStrategy::Skip => {
+                    report.skipped.push(phrase);
+                    continue;
+                }
+                ConflictStrategy::Rename => {
+                    let to = free_name(&phrase, &taken);
+                    report.renamed.push(Renamed {
+                        from: phrase,
+                        to: to.clone(),
+                    });
+                    to
+                }
+            }
+        };
+        if !report.collisions.is_empty() {
+            // Keep looking for collisions, but the copy is already doomed.
+            continue;
+        }
+        // Images and recordings are shared by hash, so only the tile itself is copied.
+        let row = tx
+            .query_one(
+                format!(
+                    r#"
+                    INSERT INTO tiles (
+                        user_id, phrase, image_hash, categories, speech, position, audio_hash,
+                        inflections, slots
+                    )
+                    SELECT $1, $2, image_hash, categories, speech, (
+                        SELECT COALESCE(MAX(position) + 1, 0) FROM tiles WHERE user_id = $1
+                    ), audio_hash, inflections, slots
+                    FROM tiles
+                    WHERE id = $3
+                    RETURNING {}
+                    "#,
+                    TILE_COLUMNS
+                )
+                .as_str(),
+                &[&dst, &name, &id],
+            )
+            .await
+            .map_err(Error::DBError)?;
+        taken.insert(name);
+        report.copied.push(Tile::from(&row));
+    }
+
+    if !report.collisions.is_empty() {
+        report.copied.clear();
+        return Ok(with_status(json(&report), StatusCode::CONFLICT));
+    }
+    // Templates are checked once everything is copied, as their slots may be filled by tiles
+    // copied along with them.
+    for tile in report.copied.iter() {
+        template::check(&tx, Some(dst), tile).await?;
+    }
+    tx.commit().await.map_err(Error::DBError)?;
+    Ok(with_status(json(&report), StatusCode::OK))
+}
@@ -0,0 +1,321 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Completes words typed on a keyboard board. Word lists for each language are bundled with the
+//! server, so completion never needs a network, and the words a user says most, along with the
+//! personal words they add, like names, rank ahead of the rest.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, preferences, user, Error};
+
+/// The bundled word lists by language code, most frequent word first.
+const WORD_LISTS: &[(&str, &str)] = &[
+    ("en", include_str!("../data/words_en.txt")),
+    ("es", include_str!("../data/words_es.txt")),
+];
+/// The language completed when neither the request nor the user's voice names a bundled one.
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// The longest prefix or personal word accepted, in characters.
+const MAX_WORD_CHARS: usize = 50;
+/// How much adding a personal word counts, in uses.
+const PERSONAL_WEIGHT: f64 = 1.0;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let complete = warp::get()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("dictionary"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(complete);
+
+    let list_words = warp::get()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("dictionary"))
+        .and(warp::path("words"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_words);
+
+    let add_word = warp::post()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("dictionary"))
+        .and(warp::path("words"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(add_word);
+
+    let delete_word = warp::delete()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("dictionary"))
+        .and(warp::path("words"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(delete_word);
+
+    complete.or(list_words).or(add_word).or(delete_word)
+}
+
+/// The bundled word list for `language`, if there is one.
+fn word_list(language: &str) -> Option<&'static [&'static str]> {
+    static LISTS: OnceLock<HashMap<&'static str, Vec<&'static str>>> = OnceLock::new();
+    LISTS
+        .get_or_init(|| {
+            WORD_LISTS
+                .iter()
+                .map(|(language, list)| {
+                    let words = list
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .collect();
+                    (*language, words)
+                })
+                .collect()
+        })
+        .get(language)
+        .map(Vec::as_slice)
+}
+
+/// Whether `word` is a single word: letters and digits, joined by apostrophes or hyphens.
+fn is_word(word: &str) -> bool {
+    !word.is_empty()
+        && word.chars().count() <= MAX_WORD_CHARS
+        && word
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '\'' || c == '-')
+        && word.starts_with(char::is_alphanumeric)
+        && word.ends_with(char::is_alphanumeric)
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Word {
+    /// The word as the user spells it, capitals included.
+    pub word: String,
+}
+
+impl Word {
+    fn validate(mut self) -> Result<Self, Error> {
+        self.word = self.word.trim().to_string();
+        if !is_word(&self.word) {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(self)
+    }
+}
+
+async fn personal_words(conn: &db::Conn, uid: i32) -> Result<Vec<Word>, Error> {
+    Ok(conn
+        .query(
+            "SELECT word FROM personal_words WHERE user_id = $1 ORDER BY lower(word)",
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| Word {
+            word: row.get("word"),
+        })
+        .collect())
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CompleteQuery {
+    /// The start of the word being typed.
+    pub prefix: String,
+    /// The language to complete in. Defaults to the language of the user's voice.
+    pub lang: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    pub word: String,
+    /// Whether the user added the word themselves.
+    pub personal: bool,
+    pub score: f64,
+}
+
+/// Lists words starting with the prefix, the ones the user says most first, then their
+/// personal words, then the rest by how common they are.
+pub async fn complete(
+    username: String,
+    _actor: String,
+    query: CompleteQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let prefix = query.prefix.trim().to_lowercase();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if prefix.is_empty()
+        || prefix.chars().count() > MAX_WORD_CHARS
+        || !(1..=MAX_LIMIT).contains(&limit)
+    {
+        return Err(Error::MalformedRequest.into());
+    }
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let words = match query.lang {
+        Some(lang) => word_list(&lang).ok_or(Error::MalformedRequest)?,
+        None => {
+            let preferences = preferences::load(&conn, uid).await?;
+            word_list(preferences.language())
+                .or_else(|| word_list(DEFAULT_LANGUAGE))
+                .unwrap_or_default()
+        }
+    };
+
+    let mut completions: HashMap<String, Completion> = HashMap::new();
+    for (rank, word) in words.iter().enumerate() {
+        if word.starts_with(&prefix) {
+            completions.insert(
+                word.to_string(),
+                Completion {
+                    word: word.to_string(),
+                    personal: false,
+                    score: (words.len() - rank) as f64 / words.len() as f64,
+                },
+            );
+        }
+    }
+    for word in personal_words(&conn, uid).await? {
+        let key = word.word.to_lowercase();
+        if key.starts_with(&prefix) {
+            let completion = completions.entry(key).or_insert(Completion {
+                word: word.word.clone(),
+                personal: true,
+                score: 0.0,
+            });
+            completion.word = word.word;
+            completion.personal = true;
+            completion.score += PERSONAL_WEIGHT;
+        }
+    }
+    for row in conn
+        .query(
+            "SELECT word, count FROM ngrams
+            WHERE user_id = $1 AND context = '' AND left(word, length($2)) = $2",
+            &[&uid, &prefix],
+        )
+        .await
+        .map_err(Error::DBError)?
+    {
+        let word: String = row.get("word");
+        let count: i32 = row.get("count");
+        completions
+            .entry(word.clone())
+            .or_insert(Completion {
+                word,
+                personal: false,
+                score: 0.0,
+            })
+            .score += count as f64;
+    }
+
+    let mut completions = completions.into_values().collect::<Vec<Completion>>();
+    completions.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.word.cmp(&b.word)));
+    completions.truncate(limit);
+    Ok(json(&completions))
+}
+
+pub async fn list_words(
+    username: String,
+    _actor: String,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(&personal_words(&conn, uid).await?))
+}
+
+/// Adds a personal word, or respells the one differing only in case.
+pub async fn add_word(
+    username: String,
+    _actor: String,
+    word: Word,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    let word = word.validate()?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let created: bool = conn
+        .query_one(
+            r#"
+            INSERT INTO personal_words (user_id, word) VALUES ($1, $2)
+            ON CONFLICT (user_id, lower(word)) DO UPDATE SET word = EXCLUDED.word
+            RETURNING xmax = 0 AS created
+            "#,
+            &[&uid, &word.word],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .get("created");
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(with_status(json(&word), status))
+}
+
+pub async fn delete_word(
+    username: String,
+    _actor: String,
+    query: Word,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let deleted = conn
+        .execute(
+            "DELETE FROM personal_words WHERE user_id = $1 AND lower(word) = lower($2)",
+            &[&uid, &query.word.trim()],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if deleted == 0 {
+        return Err(Error::NotFound.into());
+    }
+    Ok(StatusCode::OK)
+}
@@ -38,6 +38,12 @@ pub enum Error {
     MalformedRequest,
     #[error("not found")]
     NotFound,
+    #[error("{0}")]
+    InvalidUpload(String),
+    #[error("{0}")]
+    UploadTooLarge(String),
+    #[error("{0}")]
+    SpeechUnavailable(String),
 }
 
 impl reject::Reject for Error {}
@@ -49,6 +55,8 @@ impl From<Error> for Rejection {
 }
 
 pub async fn handle_rejects(err: Rejection) -> Result<impl Reply, Infallible> {
+    // Upload and speech problems are explained to the client, everything else only gets a status.
+    let mut message = String::new();
     let code = if err.is_not_found() {
         StatusCode::NOT_FOUND
     } else if let Some(_) = err.find::<warp::filters::body::BodyDeserializeError>() {
@@ -74,14 +82,29 @@ pub async fn handle_rejects(err: Rejection) -> Result<impl Reply, Infallible> {
                 }
             }
             Error::MalformedRequest => StatusCode::BAD_REQUEST,
+            Error::InvalidUpload(m) => {
+                message = m.clone();
+                StatusCode::BAD_REQUEST
+            }
+            Error::UploadTooLarge(m) => {
+                message = m.clone();
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Error::SpeechUnavailable(m) => {
+                message = m.clone();
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             Error::NotFound => StatusCode::NOT_FOUND,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        message = "request body is too large".to_string();
+        StatusCode::PAYLOAD_TOO_LARGE
     } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
         StatusCode::METHOD_NOT_ALLOWED
     } else {
         StatusCode::INTERNAL_SERVER_ERROR
     };
 
-    Ok(warp::reply::with_status("", code))
+    Ok(warp::reply::with_status(message, code))
 }
@@ -22,7 +22,14 @@ use jsonwebtoken::{DecodingKey, EncodingKey};
 use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection};
 
-use crate::{auth::BearerToken, db, Error};
+use crate::{
+    auth::BearerToken,
+    caregiver, db,
+    share::{self, ShareGrant, ShareQuery},
+    speech::Speaker,
+    storage::SharedStorage,
+    Error,
+};
 
 pub fn with_db(
     db_pool: db::Pool,
@@ -30,6 +37,18 @@ pub fn with_db(
     warp::any().map(move || db_pool.clone())
 }
 
+pub fn with_storage(
+    storage: SharedStorage,
+) -> impl Filter<Extract = (SharedStorage,), Error = Infallible> + Clone {
+    warp::any().map(move || storage.clone())
+}
+
+pub fn with_speaker(
+    speaker: Speaker,
+) -> impl Filter<Extract = (Speaker,), Error = Infallible> + Clone {
+    warp::any().map(move || speaker.clone())
+}
+
 pub fn with_jwt_priv_key(
     priv_key: EncodingKey,
 ) -> impl Filter<Extract = (EncodingKey,), Error = Infallible> + Clone {
@@ -80,6 +99,26 @@ pub fn optional_authentic_token(
     token_branch.or(pub_branch).unify()
 }
 
+async fn verify_share(query: ShareQuery, pool: db::Pool) -> Result<Option<ShareGrant>, Rejection> {
+    match query.share {
+        Some(token) => {
+            let conn = db::get_db_conn(&pool).await?;
+            Ok(Some(
+                share::verify(&conn, token, query.pin, query.key).await?,
+            ))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Extracts the grant of a share link passed in the `share` (and `pin` or `key`) query
+/// parameters, if any.
+pub fn optional_share(
+    db_pool: db::Pool,
+) -> impl Filter<Extract = (Option<ShareGrant>,), Error = Rejection> + Clone {
+    warp::query().and(with_db(db_pool)).and_then(verify_share)
+}
+
 async fn user_and_token_match(user: String, tok: BearerToken) -> Result<String, Rejection> {
     if user == tok.username {
         Ok(tok.username)
@@ -97,6 +136,58 @@ pub fn user_resource(
         .and_then(user_and_token_match)
 }
 
+async fn user_and_token_manage(
+    user: String,
+    tok: BearerToken,
+    pool: db::Pool,
+) -> Result<(String, String), Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    if caregiver::can_manage(&conn, &tok.username, &user).await? {
+        Ok((user, tok.username))
+    } else {
+        Err(Rejection::from(Error::Unauthorized))
+    }
+}
+
+/// Like [`user_resource`], but also admits the user's caregivers. Extracts the user the resource
+/// belongs to, then the user making the request.
+pub fn managed_user_resource(
+    pub_key: DecodingKey<'static>,
+    db_pool: db::Pool,
+) -> impl Filter<Extract = (String, String), Error = Rejection> + Clone {
+    warp::path::path("user")
+        .and(warp::path::param())
+        .and(authentic_token_header(pub_key))
+        .and(with_db(db_pool))
+        .and_then(user_and_token_manage)
+        .untuple_one()
+}
+
+async fn token_is_admin(tok: BearerToken, pool: db::Pool) -> Result<String, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let row = conn
+        .query_opt(
+            "SELECT is_admin FROM users WHERE username = $1",
+            &[&tok.username],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    match row {
+        Some(row) if row.get::<_, bool>("is_admin") => Ok(tok.username),
+        _ => Err(Rejection::from(Error::Unauthorized)),
+    }
+}
+
+/// Requires the bearer of the token to be an administrator, extracting their username.
+pub fn admin(
+    pub_key: DecodingKey<'static>,
+    db_pool: db::Pool,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    authentic_token_header(pub_key)
+        .and(with_db(db_pool))
+        .and_then(token_is_admin)
+}
+
 pub fn optional_user_resource(
     pub_key: DecodingKey<'static>,
 ) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
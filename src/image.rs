@@ -0,0 +1,378 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Images are stored once per distinct content, keyed by their hash, no matter how many tiles
+//! use them. The database counts the tiles referring to each image, and images nobody refers to
+//! any more are collected after tiles are deleted or given a different image. The contents
+//! themselves live in the configured [`Storage`].
+//!
+//! Scaled down and converted variants of raster images are rendered the first time they are
+//! asked for and stored alongside the original until it is collected.
+
+use std::io::Cursor;
+
+use ::image::{DynamicImage, ImageFormat, ImageReader};
+use mobc_postgres::tokio_postgres::Transaction;
+use serde::{Deserialize, Serialize};
+
+use crate::{db, metadata, storage::Storage, svg, util, Error};
+
+/// The largest image accepted, in bytes.
+pub const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+/// The longest edge accepted for raster images, in pixels.
+pub const MAX_IMAGE_DIMENSION: u32 = 8192;
+
+/// The edge lengths, in pixels, images can be scaled down to.
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 256, 512];
+
+/// How large vector images are drawn when no size is asked for.
+const RASTER_SIZE: u32 = 512;
+
+/// The formats images can be converted to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Png,
+    Webp,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Webp => "webp",
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Format::Png => ImageFormat::Png,
+            Format::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// Asks for an image scaled to fit within `size` pixels and/or converted to `format`. Scaled
+/// images are PNG unless another format is given.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageQuery {
+    pub size: Option<u32>,
+    pub format: Option<Format>,
+}
+
+impl ImageQuery {
+    /// Identifies what this query gives for the image `hash`, which never changes.
+    pub fn version(&self, hash: &str) -> String {
+        let mut version = hash.to_string();
+        if let Some(size) = self.size {
+            version.push_str(format!("-{}", size).as_str());
+        }
+        if let Some(format) = self.format {
+            version.push_str(format!("-{}", format.name()).as_str());
+        }
+        version
+    }
+}
+
+/// An uploaded image, not yet stored.
+pub struct Image {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    pub hash: String,
+}
+
+impl Image {
+    pub fn new(data: Vec<u8>, content_type: String) -> Self {
+        let hash = util::hash(data.as_slice());
+        Image {
+            data,
+            content_type,
+            hash,
+        }
+    }
+
+    /// Checks an upload really is an image of a supported type, the one it was declared as if a
+    /// type was given, and within the size limits. The image keeps the type found in its content,
+    /// never the declared one, and loses any metadata or unsafe SVG content.
+    pub async fn from_upload(data: Vec<u8>, declared_type: Option<&str>) -> Result<Self, Error> {
+        // Decoding and re-encoding a large image takes a while, so it runs off the executor.
+        let declared_type = declared_type.map(str::to_string);
+        tokio::task::spawn_blocking(move || Image::check_upload(data, declared_type.as_deref()))
+            .await
+            .map_err(|e| Error::IOError(e.into()))?
+    }
+
+    fn check_upload(data: Vec<u8>, declared_type: Option<&str>) -> Result<Self, Error> {
+        if data.len() > MAX_IMAGE_BYTES {
+            return Err(Error::UploadTooLarge(format!(
+                "image is larger than {} bytes",
+                MAX_IMAGE_BYTES
+            )));
+        }
+        let content_type = sniff(&data).ok_or_else(|| {
+            Error::InvalidUpload("image is not a PNG, JPEG, GIF, WebP or SVG image".to_string())
+        })?;
+        if let Some(declared) = declared_type {
+            let declared = match declared.trim().to_ascii_lowercase().as_str() {
+                "image/jpg" | "image/pjpeg" => "image/jpeg".to_string(),
+                other => other.to_string(),
+            };
+            if declared != content_type {
+                return Err(Error::InvalidUpload(format!(
+                    "image was declared as {} but its content is {}",
+                    declared, content_type
+                )));
+            }
+        }
+        if content_type == svg::CONTENT_TYPE {
+            let clean = svg::sanitize(&data)?;
+            return Ok(Image::new(clean, content_type.to_string()));
+        }
+        let (width, height) = ImageReader::new(Cursor::new(&data))
+            .with_guessed_format()
+            .map_err(Error::IOError)?
+            .into_dimensions()
+            .map_err(|_| Error::InvalidUpload("image is corrupt".to_string()))?;
+        if width > MAX_IMAGE_DIMENSION || height > MAX_IMAGE_DIMENSION {
+            return Err(Error::UploadTooLarge(format!(
+                "image is {}x{} pixels, but may be at most {} pixels on each side",
+                width, height, MAX_IMAGE_DIMENSION
+            )));
+        }
+        let data = metadata::strip(data, content_type)?;
+        Ok(Image::new(data, content_type.to_string()))
+    }
+}
+
+/// Works out the type of an image from its content.
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    match ::image::guess_format(data) {
+        Ok(ImageFormat::Png) => return Some("image/png"),
+        Ok(ImageFormat::Jpeg) => return Some("image/jpeg"),
+        Ok(ImageFormat::Gif) => return Some("image/gif"),
+        Ok(ImageFormat::WebP) => return Some("image/webp"),
+        _ => (),
+    }
+    // SVG is XML text, so look for an svg root element after any prolog, doctype and comments.
+    let text = std::str::from_utf8(data).ok()?;
+    let mut rest = text.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        if rest.starts_with("<svg") {
+            return Some("image/svg+xml");
+        }
+        let end = if rest.starts_with("<?") {
+            rest.find("?>")? + 2
+        } else if rest.starts_with("<!--") {
+            rest.find("-->")? + 3
+        } else if rest.starts_with("<!") {
+            // A doctype may carry declarations of its own between brackets.
+            let close = rest.find('>')?;
+            match rest.find('[') {
+                Some(open) if open < close => open + rest[open..].find("]")? + 1,
+                _ => close + 1,
+            }
+        } else {
+            return None;
+        };
+        rest = rest[end..].trim_start();
+    }
+}
+
+/// Makes sure `image` is stored, reusing the existing copy if there is one. The image is locked
+/// until the transaction ends so it can't be collected before a tile refers to it.
+pub(crate) async fn store(
+    tx: &Transaction<'_>,
+    storage: &dyn Storage,
+    image: &Image,
+) -> Result<(), Error> {
+    let existing = tx
+        .execute(
+            "SELECT 1 FROM images WHERE hash = $1 FOR UPDATE",
+            &[&image.hash],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if existing == 0 {
+        storage.put(&image.hash, &image.data).await?;
+        tx.execute(
+            r#"
+            INSERT INTO images (hash, content_type) VALUES ($1, $2)
+            ON CONFLICT (hash) DO UPDATE SET hash = EXCLUDED.hash
+            "#,
+            &[&image.hash, &image.content_type],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    }
+    Ok(())
+}
+
+/// Reads the contents and type of the image `hash`.
+pub(crate) async fn load(
+    conn: &db::Conn,
+    storage: &dyn Storage,
+    hash: &str,
+) -> Result<(Vec<u8>, String), Error> {
+    let content_type = content_type(conn, hash).await?;
+    Ok((storage.get(hash).await?, content_type))
+}
+
+/// Reads the type the image `hash` was stored as.
+async fn content_type(conn: &db::Conn, hash: &str) -> Result<String, Error> {
+    Ok(conn
+        .query_opt("SELECT content_type FROM images WHERE hash = $1", &[&hash])
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?
+        .get("content_type"))
+}
+
+/// Reads the image `hash` as asked for by `query`, rendering the variant if it doesn't exist yet.
+/// Images that can't be decoded here, like vector drawings, are always served as uploaded.
+pub(crate) async fn load_variant(
+    conn: &db::Conn,
+    storage: &dyn Storage,
+    hash: &str,
+    query: &ImageQuery,
+) -> Result<(Vec<u8>, String), Error> {
+    if let Some(size) = query.size {
+        if !THUMBNAIL_SIZES.contains(&size) {
+            return Err(Error::MalformedRequest);
+        }
+    }
+    // The original is only read once it's needed, as variants are usually cached.
+    let content_type = content_type(conn, hash).await?;
+    let source_format =
+        ImageFormat::from_mime_type(&content_type).filter(ImageFormat::reading_enabled);
+    let vector = content_type == svg::CONTENT_TYPE;
+    let format = match (query.size, query.format) {
+        (_, Some(format)) if source_format.is_some() || vector => format,
+        (Some(_), None) if source_format.is_some() => Format::Png,
+        // Vector images scale on their own and are only drawn when another format is asked for.
+        _ => return Ok((storage.get(hash).await?, content_type)),
+    };
+
+    let key =
+        util::hash(format!("{}/{}.{}", hash, query.size.unwrap_or(0), format.name()).as_bytes());
+    let cached = conn
+        .query_opt("SELECT 1 FROM image_variants WHERE key = $1", &[&key])
+        .await
+        .map_err(Error::DBError)?;
+    if cached.is_some() {
+        match storage.get(&key).await {
+            Ok(variant) => return Ok((variant, format.image_format().to_mime_type().into())),
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let data = storage.get(hash).await?;
+    let size = query.size;
+    let rendered = tokio::task::spawn_blocking(move || {
+        let variant = render(&data, source_format, size, format);
+        (data, variant)
+    })
+    .await;
+    let variant = match rendered {
+        Ok((_, Some(variant))) => variant,
+        // A broken upload is still served as it is.
+        Ok((data, None)) => return Ok((data, content_type)),
+        Err(_) => return load(conn, storage, hash).await,
+    };
+    storage.put(&key, &variant).await?;
+    conn.execute(
+        r#"
+        INSERT INTO image_variants (key, source_hash) VALUES ($1, $2)
+        ON CONFLICT (key) DO NOTHING
+        "#,
+        &[&key, &hash],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok((variant, format.image_format().to_mime_type().into()))
+}
+
+/// Decodes an image of `source_format`, or draws it when it's a vector image, then scales and
+/// encodes it as asked.
+fn render(
+    data: &[u8],
+    source_format: Option<ImageFormat>,
+    size: Option<u32>,
+    format: Format,
+) -> Option<Vec<u8>> {
+    let mut img = match source_format {
+        Some(source_format) => ::image::load_from_memory_with_format(data, source_format).ok()?,
+        None => svg::rasterize(data, size.unwrap_or(RASTER_SIZE))?,
+    };
+    if let Some(size) = size {
+        // Small images are never blown up.
+        if img.width() > size || img.height() > size {
+            img = img.thumbnail(size, size);
+        }
+    }
+    if format == Format::Webp {
+        // The WebP encoder only takes 8 bit color.
+        img = DynamicImage::ImageRgba8(img.to_rgba8());
+    }
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, format.image_format()).ok()?;
+    Some(out.into_inner())
+}
+
+/// Deletes those of the `released` images no tile refers to any more, along with their
+/// variants.
+pub(crate) async fn collect(
+    conn: &mut db::Conn,
+    storage: &dyn Storage,
+    released: &[String],
+) -> Result<(), Error> {
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    let hashes = tx
+        .query(
+            "SELECT hash FROM images WHERE hash = ANY($1) AND ref_count <= 0 FOR UPDATE",
+            &[&released],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| row.get("hash"))
+        .collect::<Vec<String>>();
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    let variants = tx
+        .query(
+            "DELETE FROM image_variants WHERE source_hash = ANY($1) RETURNING key",
+            &[&hashes],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    tx.execute("DELETE FROM images WHERE hash = ANY($1)", &[&hashes])
+        .await
+        .map_err(Error::DBError)?;
+    // The contents go before the rows are released, so a concurrent upload of the same image
+    // waits for them to be gone and stores them afresh.
+    for hash in hashes.iter() {
+        storage.delete(hash).await?;
+    }
+    for row in variants.iter() {
+        storage.delete(row.get("key")).await?;
+    }
+    tx.commit().await.map_err(Error::DBError)?;
+    Ok(())
+}
@@ -13,12 +13,28 @@ CREATE TABLE IF NOT EXISTS user_auths (
             ON DELETE CASCADE
             ON UPDATE CASCADE
 );
+CREATE TABLE IF NOT EXISTS images (
+    hash TEXT PRIMARY KEY,
+    content_type TEXT NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS image_variants (
+    key TEXT PRIMARY KEY,
+    source_hash TEXT NOT NULL,
+    CONSTRAINT fk_source
+        FOREIGN KEY (source_hash)
+            REFERENCES images(hash)
+            ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS image_variants_by_source ON image_variants (source_hash);
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    data BYTEA NOT NULL
+);
 CREATE TABLE IF NOT EXISTS tiles (
     id SERIAL PRIMARY KEY,
-    user_id SERIAL,
+    user_id INTEGER,
     phrase TEXT NOT NULL,
-    image BYTEA NOT NULL,
-    image_type TEXT NOT NULL,
     image_hash TEXT NOT NULL,
     categories TEXT[] NOT NULL,
     UNIQUE (user_id, phrase),
@@ -26,5 +42,342 @@ CREATE TABLE IF NOT EXISTS tiles (
         FOREIGN KEY (user_id)
             REFERENCES users(id)
             ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_image
+        FOREIGN KEY (image_hash)
+            REFERENCES images(hash)
+);
+ALTER TABLE tiles ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE tiles ADD COLUMN IF NOT EXISTS position INTEGER NOT NULL DEFAULT 0;
+-- Listings mix the library with a user's own tiles, so their orders are indexed across owners.
+DROP INDEX IF EXISTS tiles_by_created;
+DROP INDEX IF EXISTS tiles_by_position;
+CREATE INDEX IF NOT EXISTS tiles_by_creation ON tiles (created_at, id);
+CREATE INDEX IF NOT EXISTS tiles_by_manual_position ON tiles (position, id);
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE EXTENSION IF NOT EXISTS unaccent;
+ALTER TABLE tiles ADD COLUMN IF NOT EXISTS speech TEXT;
+CREATE OR REPLACE FUNCTION search_normalize(TEXT) RETURNS TEXT AS $$
+    SELECT lower(public.unaccent('public.unaccent', $1))
+$$ LANGUAGE SQL IMMUTABLE;
+CREATE OR REPLACE FUNCTION tile_search_text(TEXT, TEXT, TEXT[]) RETURNS TEXT AS $$
+    SELECT search_normalize(concat_ws(' ', $1, $2, array_to_string($3, ' ')))
+$$ LANGUAGE SQL IMMUTABLE;
+CREATE INDEX IF NOT EXISTS tiles_search
+    ON tiles USING GIN (tile_search_text(phrase, speech, categories) gin_trgm_ops);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE tiles ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE tiles ALTER COLUMN user_id DROP DEFAULT;
+DROP SEQUENCE IF EXISTS tiles_user_id_seq;
+CREATE UNIQUE INDEX IF NOT EXISTS tiles_library_phrase ON tiles (phrase) WHERE user_id IS NULL;
+CREATE TABLE IF NOT EXISTS hidden_tiles (
+    user_id INTEGER NOT NULL,
+    tile_id INTEGER NOT NULL,
+    PRIMARY KEY (user_id, tile_id),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_tile
+        FOREIGN KEY (tile_id)
+            REFERENCES tiles(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE TABLE IF NOT EXISTS tile_uses (
+    user_id INTEGER NOT NULL,
+    tile_id INTEGER NOT NULL,
+    use_count INTEGER NOT NULL DEFAULT 1,
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, tile_id),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_tile
+        FOREIGN KEY (tile_id)
+            REFERENCES tiles(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+-- Move use counts off tiles, where there was no room for each user's count of library tiles.
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'tiles' AND column_name = 'use_count'
+    ) THEN
+        INSERT INTO tile_uses (user_id, tile_id, use_count, last_used_at)
+        SELECT user_id, id, use_count, last_used_at FROM tiles
+        WHERE user_id IS NOT NULL AND use_count > 0 AND last_used_at IS NOT NULL
+        ON CONFLICT DO NOTHING;
+        ALTER TABLE tiles DROP COLUMN use_count;
+        ALTER TABLE tiles DROP COLUMN last_used_at;
+    END IF;
+END
+$$;
+CREATE TABLE IF NOT EXISTS caregivers (
+    user_id INTEGER NOT NULL,
+    caregiver_id INTEGER NOT NULL,
+    PRIMARY KEY (user_id, caregiver_id),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_caregiver
+        FOREIGN KEY (caregiver_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE TABLE IF NOT EXISTS shares (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    token TEXT UNIQUE NOT NULL,
+    category TEXT,
+    pin_hash TEXT,
+    salt TEXT,
+    expires_at TIMESTAMPTZ NOT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+-- Move images stored inline on tiles into the shared image table.
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'tiles' AND column_name = 'image'
+    ) THEN
+        INSERT INTO images (hash, content_type)
+        SELECT DISTINCT ON (image_hash) image_hash, image_type FROM tiles
+        ON CONFLICT DO NOTHING;
+        INSERT INTO blobs (hash, data)
+        SELECT DISTINCT ON (image_hash) image_hash, image FROM tiles
+        ON CONFLICT DO NOTHING;
+        UPDATE images SET ref_count = (
+            SELECT COUNT(*) FROM tiles WHERE tiles.image_hash = images.hash
+        );
+        ALTER TABLE tiles DROP COLUMN image;
+        ALTER TABLE tiles DROP COLUMN image_type;
+        ALTER TABLE tiles ADD CONSTRAINT fk_image
+            FOREIGN KEY (image_hash) REFERENCES images(hash);
+    END IF;
+END
+$$;
+-- Move image contents out of the image table, where they can be kept in other storage.
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_name = 'images' AND column_name = 'data'
+    ) THEN
+        INSERT INTO blobs (hash, data) SELECT hash, data FROM images
+        ON CONFLICT DO NOTHING;
+        ALTER TABLE images DROP COLUMN data;
+    END IF;
+END
+$$;
+CREATE INDEX IF NOT EXISTS tiles_by_image ON tiles (image_hash);
+CREATE OR REPLACE FUNCTION count_image_refs() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.image_hash = NEW.image_hash THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        UPDATE images SET ref_count = ref_count + 1 WHERE hash = NEW.image_hash;
+    END IF;
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE images SET ref_count = ref_count - 1 WHERE hash = OLD.image_hash;
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS tiles_image_refs ON tiles;
+CREATE TRIGGER tiles_image_refs
+    AFTER INSERT OR DELETE OR UPDATE OF image_hash ON tiles
+    FOR EACH ROW EXECUTE FUNCTION count_image_refs();
+CREATE TABLE IF NOT EXISTS recordings (
+    hash TEXT PRIMARY KEY,
+    content_type TEXT NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 0
+);
+ALTER TABLE tiles ADD COLUMN IF NOT EXISTS audio_hash TEXT
+    CONSTRAINT fk_audio REFERENCES recordings(hash);
+CREATE INDEX IF NOT EXISTS tiles_by_audio ON tiles (audio_hash);
+CREATE OR REPLACE FUNCTION count_recording_refs() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.audio_hash IS NOT DISTINCT FROM NEW.audio_hash THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') AND NEW.audio_hash IS NOT NULL THEN
+        UPDATE recordings SET ref_count = ref_count + 1 WHERE hash = NEW.audio_hash;
+    END IF;
+    IF TG_OP IN ('UPDATE', 'DELETE') AND OLD.audio_hash IS NOT NULL THEN
+        UPDATE recordings SET ref_count = ref_count - 1 WHERE hash = OLD.audio_hash;
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS tiles_recording_refs ON tiles;
+CREATE TRIGGER tiles_recording_refs
+    AFTER INSERT OR DELETE OR UPDATE OF audio_hash ON tiles
+    FOR EACH ROW EXECUTE FUNCTION count_recording_refs();
+CREATE TABLE IF NOT EXISTS pronunciations (
+    user_id INTEGER NOT NULL,
+    word TEXT NOT NULL,
+    replacement TEXT NOT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS pronunciations_by_word ON pronunciations (user_id, lower(word));
+CREATE TABLE IF NOT EXISTS preferences (
+    user_id INTEGER PRIMARY KEY,
+    document JSONB NOT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE TABLE IF NOT EXISTS messages (
+    user_id INTEGER NOT NULL,
+    device TEXT NOT NULL,
+    parts JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, device),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE TABLE IF NOT EXISTS utterances (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    device TEXT NOT NULL,
+    text TEXT NOT NULL,
+    spoken_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE INDEX IF NOT EXISTS utterances_by_user ON utterances (user_id, spoken_at, id);
+CREATE TABLE IF NOT EXISTS usage_events (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    tile_id INTEGER,
+    phrase TEXT,
+    board TEXT,
+    device TEXT,
+    text TEXT NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_tile
+        FOREIGN KEY (tile_id)
+            REFERENCES tiles(id)
+            ON DELETE SET NULL
+);
+CREATE INDEX IF NOT EXISTS usage_events_by_user ON usage_events (user_id, id);
+CREATE INDEX IF NOT EXISTS usage_events_by_time ON usage_events (user_id, occurred_at);
+CREATE TABLE IF NOT EXISTS ngram_models (
+    user_id INTEGER PRIMARY KEY,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE TABLE IF NOT EXISTS ngrams (
+    user_id INTEGER NOT NULL,
+    context TEXT NOT NULL,
+    word TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (user_id, context, word),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+-- The first word a tile says, as predictions know tiles by it.
+CREATE OR REPLACE FUNCTION first_word(TEXT) RETURNS TEXT AS $$
+    SELECT rtrim(lower(substring($1 FROM '[[:alnum:]][[:alnum:]'']*')), '''')
+$$ LANGUAGE SQL IMMUTABLE;
+CREATE INDEX IF NOT EXISTS tiles_by_first_word ON tiles (first_word(COALESCE(speech, phrase)));
+CREATE TABLE IF NOT EXISTS personal_words (
+    user_id INTEGER NOT NULL,
+    word TEXT NOT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS personal_words_by_word ON personal_words (user_id, lower(word));
+CREATE TABLE IF NOT EXISTS abbreviations (
+    user_id INTEGER NOT NULL,
+    abbreviation TEXT NOT NULL,
+    expansion TEXT NOT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE UNIQUE INDEX IF NOT EXISTS abbreviations_by_abbreviation
+    ON abbreviations (user_id, lower(abbreviation));
+ALTER TABLE tiles ADD COLUMN IF NOT EXISTS inflections JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE tiles ADD COLUMN IF NOT EXISTS slots JSONB NOT NULL DEFAULT '{}';
+CREATE TABLE IF NOT EXISTS favorite_tiles (
+    user_id INTEGER NOT NULL,
+    tile_id INTEGER NOT NULL,
+    added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, tile_id),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_tile
+        FOREIGN KEY (tile_id)
+            REFERENCES tiles(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+);
+CREATE TABLE IF NOT EXISTS tile_activity (
+    user_id INTEGER NOT NULL,
+    tile_id INTEGER NOT NULL,
+    used_on DATE NOT NULL DEFAULT CURRENT_DATE,
+    uses INTEGER NOT NULL DEFAULT 1,
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, tile_id, used_on),
+    CONSTRAINT fk_user
+        FOREIGN KEY (user_id)
+            REFERENCES users(id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+    CONSTRAINT fk_tile
+        FOREIGN KEY (tile_id)
+            REFERENCES tiles(id)
+            ON DELETE CASCADE
             ON UPDATE CASCADE
 );
+ALTER TABLE shares ADD COLUMN IF NOT EXISTS failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE shares ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ;
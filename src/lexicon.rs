@@ -0,0 +1,324 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Pronunciations users teach the system for words speech engines get wrong, like the names of
+//! family, pets and places. Each replaces a word, or a run of words, with a respelling wherever
+//! text is spoken, whether by the server or by the browser. Replacements are plain text, though
+//! eSpeak NG also understands phonemes written between `[[` and `]]`.
+
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, user, Error};
+
+/// The longest word, or run of words, a pronunciation may replace, in characters.
+const MAX_WORD_CHARS: usize = 100;
+/// The longest replacement, in characters.
+const MAX_REPLACEMENT_CHARS: usize = 200;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list_pronunciations = warp::get()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("pronunciations"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_pronunciations);
+
+    let set_pronunciation = warp::post()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("pronunciations"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(set_pronunciation);
+
+    let delete_pronunciation = warp::delete()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("pronunciations"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(delete_pronunciation);
+
+    let preview = warp::get()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("pronunciations"))
+        .and(warp::path("preview"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(preview);
+
+    list_pronunciations
+        .or(set_pronunciation)
+        .or(delete_pronunciation)
+        .or(preview)
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Pronunciation {
+    /// The word as it is written. Matching ignores case.
+    pub word: String,
+    /// What to say instead.
+    pub replacement: String,
+}
+
+impl Pronunciation {
+    fn validate(mut self) -> Result<Self, Error> {
+        self.word = self.word.trim().to_string();
+        self.replacement = self.replacement.trim().to_string();
+        let words = tokens(&self.word);
+        if words.is_empty()
+            || words[0].0 != 0
+            || words[words.len() - 1].1 != self.word.len()
+            || self.word.chars().count() > MAX_WORD_CHARS
+            || self.replacement.is_empty()
+            || self.replacement.chars().count() > MAX_REPLACEMENT_CHARS
+        {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(self)
+    }
+}
+
+/// Where each word in `text` starts and ends. Apostrophes and hyphens separate words, so a
+/// pronunciation for a name also applies to its possessive.
+fn tokens(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                spans.push((s, i));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+/// Compares the text between words, treating any run of whitespace alike.
+fn same_gap(a: &str, b: &str) -> bool {
+    a.split_whitespace().eq(b.split_whitespace())
+        && a.starts_with(char::is_whitespace) == b.starts_with(char::is_whitespace)
+        && a.ends_with(char::is_whitespace) == b.ends_with(char::is_whitespace)
+}
+
+/// A user's pronunciations, ready to apply.
+#[derive(Debug, Default, Clone)]
+pub struct Lexicon {
+    /// The lowercased words and the gaps between them of each entry, with its replacement,
+    /// longest entry first so runs of words win over their parts.
+    entries: Vec<(Vec<String>, Vec<String>, String)>,
+}
+
+impl Lexicon {
+    pub fn new(pronunciations: &[Pronunciation]) -> Self {
+        let mut entries = pronunciations
+            .iter()
+            .map(|p| {
+                let spans = tokens(&p.word);
+                let words = spans
+                    .iter()
+                    .map(|(s, e)| p.word[*s..*e].to_lowercase())
+                    .collect::<Vec<String>>();
+                let gaps = spans
+                    .windows(2)
+                    .map(|pair| p.word[pair[0].1..pair[1].0].to_string())
+                    .collect::<Vec<String>>();
+                (words, gaps, p.replacement.clone())
+            })
+            .filter(|(words, _, _)| !words.is_empty())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.0.len()));
+        Lexicon { entries }
+    }
+
+    /// Replaces every whole word, or run of words, in `text` the lexicon has a pronunciation for.
+    pub fn apply(&self, text: &str) -> String {
+        if self.entries.is_empty() {
+            return text.to_string();
+        }
+        let spans = tokens(text);
+        let words = spans
+            .iter()
+            .map(|(s, e)| text[*s..*e].to_lowercase())
+            .collect::<Vec<String>>();
+        let mut out = String::with_capacity(text.len());
+        let mut copied = 0;
+        let mut i = 0;
+        while i < spans.len() {
+            let found = self.entries.iter().find(|(entry, gaps, _)| {
+                i + entry.len() <= spans.len()
+                    && entry.iter().zip(&words[i..]).all(|(a, b)| a == b)
+                    && gaps
+                        .iter()
+                        .enumerate()
+                        .all(|(j, gap)| same_gap(gap, &text[spans[i + j].1..spans[i + j + 1].0]))
+            });
+            match found {
+                Some((entry, _, replacement)) => {
+                    out.push_str(&text[copied..spans[i].0]);
+                    out.push_str(replacement);
+                    copied = spans[i + entry.len() - 1].1;
+                    i += entry.len();
+                }
+                None => i += 1,
+            }
+        }
+        out.push_str(&text[copied..]);
+        out
+    }
+}
+
+async fn pronunciations(conn: &db::Conn, uid: i32) -> Result<Vec<Pronunciation>, Error> {
+    Ok(conn
+        .query(
+            "SELECT word, replacement FROM pronunciations WHERE user_id = $1 ORDER BY lower(word)",
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| Pronunciation {
+            word: row.get("word"),
+            replacement: row.get("replacement"),
+        })
+        .collect())
+}
+
+/// Reads the lexicon of the user `uid`.
+pub async fn lexicon(conn: &db::Conn, uid: i32) -> Result<Lexicon, Error> {
+    Ok(Lexicon::new(&pronunciations(conn, uid).await?))
+}
+
+pub async fn list_pronunciations(
+    username: String,
+    _actor: String,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(&pronunciations(&conn, uid).await?))
+}
+
+/// Adds a pronunciation, or replaces the one for the same word.
+pub async fn set_pronunciation(
+    username: String,
+    _actor: String,
+    pronunciation: Pronunciation,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    let pronunciation = pronunciation.validate()?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let created: bool = conn
+        .query_one(
+            r#"
+            INSERT INTO pronunciations (user_id, word, replacement) VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, lower(word))
+                DO UPDATE SET word = EXCLUDED.word, replacement = EXCLUDED.replacement
+            RETURNING xmax = 0 AS created
+            "#,
+            &[&uid, &pronunciation.word, &pronunciation.replacement],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .get("created");
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(with_status(json(&pronunciation), status))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WordQuery {
+    pub word: String,
+}
+
+pub async fn delete_pronunciation(
+    username: String,
+    _actor: String,
+    query: WordQuery,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let deleted = conn
+        .execute(
+            "DELETE FROM pronunciations WHERE user_id = $1 AND lower(word) = lower($2)",
+            &[&uid, &query.word.trim()],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if deleted == 0 {
+        return Err(Error::NotFound.into());
+    }
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PreviewQuery {
+    pub text: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Preview {
+    pub text: String,
+    /// The text as it will be spoken.
+    pub spoken: String,
+}
+
+pub async fn preview(
+    username: String,
+    _actor: String,
+    query: PreviewQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let spoken = lexicon(&conn, uid).await?.apply(&query.text);
+    Ok(json(&Preview {
+        text: query.text,
+        spoken,
+    }))
+}
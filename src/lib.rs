@@ -24,8 +24,30 @@ use warp::{Filter, Reply};
 pub mod guard;
 pub mod util;
 
+pub mod abbreviation;
+pub mod audio;
 pub mod auth;
+pub mod board;
+pub mod caregiver;
+pub mod copy;
+pub mod dictionary;
+pub mod image;
+pub mod lexicon;
+pub mod library;
+pub mod media;
+pub mod message;
+pub mod metadata;
+pub mod morphology;
+pub mod predict;
+pub mod preferences;
+pub mod report;
+pub mod share;
+pub mod speech;
+pub mod storage;
+pub mod svg;
+pub mod template;
 pub mod tile;
+pub mod usage;
 pub mod user;
 
 pub mod db;
@@ -38,13 +60,26 @@ pub enum JWTConfig {
     RSAFiles { private: String, public: String },
 }
 
+/// How the application is set up, apart from its database. A random JWT secret is used when none
+/// is given.
+#[derive(Default)]
+pub struct Config {
+    pub jwt: Option<JWTConfig>,
+    pub storage: storage::StorageConfig,
+    pub speech: speech::SpeechConfig,
+}
+
 pub async fn app(
     db_pool: db::Pool,
-    maybe_jwt: Option<JWTConfig>,
+    config: Config,
 ) -> Result<impl Filter<Extract = impl Reply, Error = Infallible> + Clone, Error> {
     db::init_db(&db_pool).await?;
+    let storage = storage::open(&config.storage, db_pool.clone()).await?;
+    let speaker = speech::Speaker::open(&config.speech, db_pool.clone()).await?;
 
-    let jwt = maybe_jwt.unwrap_or_else(|| JWTConfig::Secret(auth::random_string(32)));
+    let jwt = config
+        .jwt
+        .unwrap_or_else(|| JWTConfig::Secret(auth::random_string(32)));
     let (jwt_priv, jwt_pub): (EncodingKey, DecodingKey<'static>) = match jwt {
         JWTConfig::Secret(secret) => (
             EncodingKey::from_secret(secret.as_bytes()),
@@ -57,13 +92,49 @@ pub async fn app(
     };
 
     let auth_api = auth::api(db_pool.clone(), jwt_priv);
-    let tile_api = tile::api(db_pool.clone(), jwt_pub.clone());
+    let tile_api = tile::api(db_pool.clone(), storage.clone(), jwt_pub.clone());
+    let template_api = template::api(db_pool.clone(), jwt_pub.clone());
+    let board_api = board::api(db_pool.clone(), jwt_pub.clone());
+    let library_api = library::api(db_pool.clone(), storage, jwt_pub.clone());
+    let caregiver_api = caregiver::api(db_pool.clone(), jwt_pub.clone());
+    let copy_api = copy::api(db_pool.clone(), jwt_pub.clone());
+    let share_api = share::api(db_pool.clone(), jwt_pub.clone());
+    let lexicon_api = lexicon::api(db_pool.clone(), jwt_pub.clone());
+    let preferences_api = preferences::api(db_pool.clone(), jwt_pub.clone());
+    let message_api = message::api(db_pool.clone(), jwt_pub.clone());
+    let dictionary_api = dictionary::api(db_pool.clone(), jwt_pub.clone());
+    let abbreviation_api = abbreviation::api(db_pool.clone(), jwt_pub.clone());
+    let predict_api = predict::api(db_pool.clone(), jwt_pub.clone());
+    let usage_api = usage::api(db_pool.clone(), jwt_pub.clone());
+    let report_api = report::api(db_pool.clone(), jwt_pub.clone());
+    let speech_api = speech::api(db_pool.clone(), speaker, jwt_pub.clone());
     let user_api = user::api(db_pool, jwt_pub);
 
     let api = warp::path("api")
         // Limit to 4MiB
         // .and(warp::body::content_length_limit(4194304))
-        .and(auth_api.or(tile_api).or(user_api));
+        .and(
+            auth_api
+                .or(tile_api)
+                .or(template_api)
+                .or(board_api)
+                .or(library_api)
+                .or(caregiver_api)
+                .or(copy_api)
+                .or(share_api)
+                .or(lexicon_api)
+                .or(preferences_api)
+                .or(message_api)
+                .or(predict_api)
+                .or(dictionary_api)
+                .or(abbreviation_api)
+                .or(usage_api)
+                .or(report_api)
+                .or(speech_api)
+                .or(user_api),
+        )
+        // Boxing keeps the futures of the many routes off the stack.
+        .boxed();
 
     let gui_lib = warp::path!("elm.js").map(|| {
         warp::reply::with_header(
@@ -0,0 +1,288 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The curated system library: tiles without an owner that every user sees alongside their own.
+//! Administrators publish library tiles, and users can hide the ones they don't want or copy one
+//! into their own vocabulary to customize it.
+
+use jsonwebtoken::DecodingKey;
+use warp::{
+    http::StatusCode,
+    multipart::FormData,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    db, guard,
+    storage::SharedStorage,
+    template,
+    tile::{self, Tile, TILE_COLUMNS},
+    user, Error,
+};
+
+pub fn api(
+    db_pool: db::Pool,
+    storage: SharedStorage,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let publish_tile = warp::post()
+        .and(warp::path("library"))
+        .and(guard::admin(jwt_key.clone(), db_pool.clone()))
+        .and(warp::path("tiles"))
+        .and(warp::path::end())
+        .and(tile::tile_form())
+        .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage.clone()))
+        .and_then(publish_tile);
+
+    let update_library_tile = warp::patch()
+        .and(warp::path("library"))
+        .and(guard::admin(jwt_key.clone(), db_pool.clone()))
+        .and(warp::path("tiles"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(tile::tile_form())
+        .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage.clone()))
+        .and_then(update_library_tile);
+
+    let delete_library_tile = warp::delete()
+        .and(warp::path("library"))
+        .and(guard::admin(jwt_key.clone(), db_pool.clone()))
+        .and(warp::path("tiles"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage))
+        .and_then(delete_library_tile);
+
+    let list_hidden = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("library"))
+        .and(warp::path("hidden"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_hidden);
+
+    let hide_tile = warp::put()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("library"))
+        .and(warp::path::param())
+        .and(warp::path("hidden"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(hide_tile);
+
+    let unhide_tile = warp::delete()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("library"))
+        .and(warp::path::param())
+        .and(warp::path("hidden"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(unhide_tile);
+
+    let copy_tile = warp::post()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("library"))
+        .and(warp::path::param())
+        .and(warp::path("copy"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool))
+        .and_then(copy_tile);
+
+    publish_tile
+        .or(update_library_tile)
+        .or(delete_library_tile)
+        .or(list_hidden)
+        .or(hide_tile)
+        .or(unhide_tile)
+        .or(copy_tile)
+}
+
+pub async fn publish_tile(
+    _admin: String,
+    form: FormData,
+    pool: db::Pool,
+    storage: SharedStorage,
+) -> Result<WithStatus<Json>, Rejection> {
+    let tile = tile::decode_tile_form(form).await?;
+    let mut conn = db::get_db_conn(&pool).await?;
+    let tile = tile::insert_tile(&mut conn, storage.as_ref(), None, tile).await?;
+    Ok(with_status(json(&tile), StatusCode::CREATED))
+}
+
+pub async fn update_library_tile(
+    _admin: String,
+    phrase: String,
+    form: FormData,
+    pool: db::Pool,
+    storage: SharedStorage,
+) -> Result<Json, Rejection> {
+    let tile = tile::decode_tile_form(form).await?;
+    let mut conn = db::get_db_conn(&pool).await?;
+    Ok(json(
+        &tile::update_tile(&mut conn, storage.as_ref(), None, &phrase, tile).await?,
+    ))
+}
+
+pub async fn delete_library_tile(
+    _admin: String,
+    phrase: String,
+    pool: db::Pool,
+    storage: SharedStorage,
+) -> Result<StatusCode, Rejection> {
+    let mut conn = db::get_db_conn(&pool).await?;
+    tile::delete_tile(&mut conn, storage.as_ref(), None, &phrase).await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn list_hidden(username: String, pool: db::Pool) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(
+        &conn
+            .query(
+                format!(
+                    r#"
+                    SELECT {}
+                    FROM tiles
+                    INNER JOIN hidden_tiles
+                    ON hidden_tiles.tile_id = tiles.id
+                    WHERE hidden_tiles.user_id = $1
+                    ORDER BY phrase ASC
+                    "#,
+                    TILE_COLUMNS
+                )
+                .as_str(),
+                &[&uid],
+            )
+            .await
+            .map_err(Error::DBError)?
+            .iter()
+            .map(Tile::from)
+            .collect::<Vec<Tile>>(),
+    ))
+}
+
+pub async fn hide_tile(
+    username: String,
+    phrase: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let hidden = conn
+        .execute(
+            r#"
+            INSERT INTO hidden_tiles (user_id, tile_id)
+            SELECT $1, id FROM tiles WHERE user_id IS NULL AND phrase = $2
+            ON CONFLICT DO NOTHING
+            "#,
+            &[&uid, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if hidden == 0 {
+        // Either the tile is already hidden or there is no such library tile.
+        conn.query_opt(
+            "SELECT id FROM tiles WHERE user_id IS NULL AND phrase = $1",
+            &[&phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    }
+    Ok(StatusCode::OK)
+}
+
+pub async fn unhide_tile(
+    username: String,
+    phrase: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    conn.execute(
+        r#"
+        DELETE FROM hidden_tiles
+        USING tiles
+        WHERE hidden_tiles.tile_id = tiles.id
+            AND hidden_tiles.user_id = $1
+            AND tiles.user_id IS NULL
+            AND tiles.phrase = $2
+        "#,
+        &[&uid, &phrase],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn copy_tile(
+    username: String,
+    phrase: String,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    // The user's own tile is what stands in the way, so it is what a conflict responds with.
+    if let Some(row) = tx
+        .query_opt(
+            format!(
+                "SELECT {} FROM tiles WHERE user_id = $1 AND phrase = $2",
+                TILE_COLUMNS
+            )
+            .as_str(),
+            &[&uid, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+    {
+        return Ok(with_status(json(&Tile::from(&row)), StatusCode::CONFLICT));
+    }
+    let row = tx
+        .query_opt(
+            format!(
+                r#"
+                INSERT INTO tiles (
+                    user_id, phrase, image_hash, categories, speech, position, audio_hash,
+                    inflections, slots
+                )
+                SELECT $1, phrase, image_hash, categories, speech, (
+                    SELECT COALESCE(MAX(position) + 1, 0) FROM tiles WHERE user_id = $1
+                ), audio_hash, inflections, slots
+                FROM tiles
+                WHERE user_id IS NULL AND phrase = $2
+                RETURNING {}
+                "#,
+                TILE_COLUMNS
+            )
+            .as_str(),
+            &[&uid, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    let copied = Tile::from(&row);
+    template::check(&tx, Some(uid), &copied).await?;
+    tx.commit().await.map_err(Error::DBError)?;
+    Ok(with_status(json(&copied), StatusCode::CREATED))
+}
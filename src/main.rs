@@ -16,9 +16,9 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::env;
+use std::{env, path::PathBuf, process, sync::Arc};
 
-use open_comm::{app, db, JWTConfig};
+use open_comm::{app, db, speech, storage, Config, JWTConfig};
 
 const DEFAULT_DATABASE_URL: &'static str = "postgres://postgres@0.0.0.0:5432";
 
@@ -27,6 +27,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let db_url = env::var("DATABASE_URL").unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string());
     let db_pool = db::create_pool(db_url.as_str())?;
 
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate-images") {
+        return migrate_images(db_pool, &args[2..]).await;
+    }
+
     let jwt = match env::var("JWT_SECRET") {
         Ok(secret) => Some(JWTConfig::Secret(secret)),
         _ => match (env::var("JWT_PRIVATE_KEY"), env::var("JWT_PUBLIC_KEY")) {
@@ -34,9 +39,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             _ => None,
         },
     };
+    let storage = match env::var("IMAGE_STORAGE") {
+        Ok(spec) => spec.parse()?,
+        _ => storage::StorageConfig::default(),
+    };
 
-    let app_routes = app(db_pool, jwt).await.expect("app initialized properly");
+    // eSpeak NG speaks unless told otherwise; `none` leaves speech to the browser.
+    let engine: Option<speech::SharedEngine> = match env::var("SPEECH_ENGINE") {
+        Ok(spec) if spec == "none" => None,
+        Ok(spec) => match spec.strip_prefix("espeak-ng:") {
+            Some(program) => Some(Arc::new(speech::Espeak::new(program))),
+            None if spec == "espeak-ng" => Some(Arc::new(speech::Espeak::default())),
+            None => return Err(format!("unknown speech engine \"{}\"", spec).into()),
+        },
+        _ => Some(Arc::new(speech::Espeak::default())),
+    };
+    let speech = speech::SpeechConfig {
+        engine,
+        cache: env::var("SPEECH_CACHE").ok().map(PathBuf::from),
+        cache_bytes: env::var("SPEECH_CACHE_BYTES")
+            .ok()
+            .map(|bytes| bytes.parse())
+            .transpose()?,
+    };
+
+    let app_routes = app(
+        db_pool,
+        Config {
+            jwt,
+            storage,
+            speech,
+        },
+    )
+    .await
+    .expect("app initialized properly");
 
     warp::serve(app_routes).run(([0, 0, 0, 0], 8080)).await;
     Ok(())
 }
+
+/// `open-comm migrate-images <from> <to>` moves every stored image between backends, each given
+/// as `postgres` or `fs:<directory>`.
+async fn migrate_images(
+    db_pool: db::Pool,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (from, to) = match args {
+        [from, to] => (
+            from.parse::<storage::StorageConfig>()?,
+            to.parse::<storage::StorageConfig>()?,
+        ),
+        _ => {
+            eprintln!("usage: open-comm migrate-images <from> <to>");
+            process::exit(2);
+        }
+    };
+    // Moving contents onto themselves would delete every one of them.
+    if storage::same_backend(&from, &to).await? {
+        eprintln!("open-comm migrate-images: <from> and <to> are the same storage");
+        process::exit(2);
+    }
+    db::init_db(&db_pool).await?;
+    let from = storage::open(&from, db_pool.clone()).await?;
+    let to = storage::open(&to, db_pool.clone()).await?;
+    let moved = storage::migrate(&db_pool, from.as_ref(), to.as_ref()).await?;
+    println!("moved {} images", moved);
+    Ok(())
+}
@@ -0,0 +1,153 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! HTTP responses for stored media. Stored content is addressed by its hash and never changes,
+//! so clients may keep it for good, revalidate it by tag, and fetch large files in pieces.
+
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reply::Response,
+    Filter, Rejection,
+};
+
+/// Media may be cached for a year without asking again. It is private because most of it is
+/// only visible to its owner.
+const CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// The request headers that make a media request conditional or partial.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    pub if_none_match: Option<String>,
+    pub if_range: Option<String>,
+    pub range: Option<String>,
+}
+
+pub fn conditions() -> impl Filter<Extract = (Conditions,), Error = Rejection> + Clone {
+    warp::header::optional("if-none-match")
+        .and(warp::header::optional("if-range"))
+        .and(warp::header::optional("range"))
+        .map(|if_none_match, if_range, range| Conditions {
+            if_none_match,
+            if_range,
+            range,
+        })
+}
+
+/// The strong entity tag for content identified by `id`.
+pub fn etag(id: &str) -> String {
+    format!("\"{}\"", id)
+}
+
+/// Whether `etag` is among the tags the client already has.
+fn matches(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn cache_headers(res: &mut Response, etag: &str) {
+    let headers = res.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(CACHE_CONTROL),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+}
+
+/// A bodiless 304 response when the client already has the content tagged `etag`. This can be
+/// checked before loading the content at all.
+pub fn not_modified(conditions: &Conditions, etag: &str) -> Option<Response> {
+    match &conditions.if_none_match {
+        Some(tags) if matches(tags, etag) => {
+            let mut res = Response::new(Vec::new().into());
+            *res.status_mut() = StatusCode::NOT_MODIFIED;
+            cache_headers(&mut res, etag);
+            Some(res)
+        }
+        _ => None,
+    }
+}
+
+/// Parses a single `bytes` range against content of `len` bytes, giving the first and last
+/// byte. Only single ranges are served; anything else gets the whole content.
+fn parse_range(range: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_at(spec.find('-')?);
+    let (start, end) = (start.trim(), end[1..].trim());
+    let range = if start.is_empty() {
+        // The last `end` bytes.
+        let suffix = end.parse::<usize>().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start = start.parse::<usize>().ok()?;
+        let end = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            end.parse::<usize>().ok()?.min(len.saturating_sub(1))
+        };
+        if start >= len || start > end {
+            return Some(Err(()));
+        }
+        (start, end)
+    };
+    Some(Ok(range))
+}
+
+/// Responds with `data`, or the part of it asked for by a range request.
+pub fn respond(data: Vec<u8>, content_type: &str, etag: &str, conditions: &Conditions) -> Response {
+    let len = data.len();
+    // A range only applies to the version of the content the client already has part of.
+    let range = match (&conditions.range, &conditions.if_range) {
+        (Some(range), Some(tag)) if tag.trim() == etag => parse_range(range, len),
+        (Some(range), None) => parse_range(range, len),
+        _ => None,
+    };
+    let mut res = match range {
+        None => Response::new(data.into()),
+        Some(Ok((start, end))) => {
+            let mut res = Response::new(data[start..=end].to_vec().into());
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len)) {
+                res.headers_mut().insert(header::CONTENT_RANGE, value);
+            }
+            res
+        }
+        Some(Err(())) => {
+            let mut res = Response::new(Vec::new().into());
+            *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+                res.headers_mut().insert(header::CONTENT_RANGE, value);
+            }
+            res
+        }
+    };
+    if let Ok(value) = HeaderValue::from_str(content_type) {
+        res.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+    cache_headers(&mut res, etag);
+    res
+}
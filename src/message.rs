@@ -0,0 +1,450 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The sentence strip: users build a message from several tiles before speaking it. Each device
+//! keeps its own message in progress on the server, so it survives the device reloading, and
+//! every message spoken is kept as the user's history.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::types::Json as SqlJson;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    abbreviation::{self, Abbreviations},
+    db, guard, lexicon,
+    morphology::{self, Inflection},
+    predict, preferences,
+    usage::{self, Activation},
+    user, Error,
+};
+
+/// The most tiles a message may hold.
+const MAX_PARTS: usize = 100;
+/// The longest device name, in characters.
+const MAX_DEVICE_CHARS: usize = 64;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let read_message = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("message"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(read_message);
+
+    let append_tile = warp::post()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("message"))
+        .and(warp::path::param())
+        .and(warp::path("tiles"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(append_tile);
+
+    let delete_last = warp::delete()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("message"))
+        .and(warp::path::param())
+        .and(warp::path("tiles"))
+        .and(warp::path("last"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(delete_last);
+
+    let clear_message = warp::delete()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("message"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(clear_message);
+
+    let speak_message = warp::post()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("message"))
+        .and(warp::path::param())
+        .and(warp::path("speak"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(speak_message);
+
+    let list_utterances = warp::get()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("utterances"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(list_utterances);
+
+    read_message
+        .or(append_tile)
+        .or(delete_last)
+        .or(clear_message)
+        .or(speak_message)
+        .or(list_utterances)
+}
+
+/// A tile in a message, as it was when it was added.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Part {
+    pub phrase: String,
+    /// What the tile says.
+    pub text: String,
+    /// The tile's own forms for modifiers pressed after it.
+    #[serde(default)]
+    pub inflections: BTreeMap<Inflection, String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub device: String,
+    pub parts: Vec<Part>,
+    /// The parts put together into a sentence, with the user's abbreviations expanded.
+    pub text: String,
+    /// The text as it is spoken, with the user's pronunciations applied.
+    pub spoken: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AppendTile {
+    pub phrase: String,
+    /// The board, or category, the tile was pressed on.
+    #[serde(default)]
+    pub board: Option<String>,
+}
+
+/// Whether `part` is only punctuation that closes what came before, like `,` or `?!`.
+fn is_closing(part: &str) -> bool {
+    part.chars()
+        .all(|c| matches!(c, '.' | ',' | '!' | '?' | ';' | ':' | ')' | ']' | '%'))
+}
+
+/// Whether `part` is only punctuation that opens what comes after, like `(`.
+fn is_opening(part: &str) -> bool {
+    part.chars().all(|c| matches!(c, '(' | '[' | '$'))
+}
+
+/// Puts the text of each part together into a sentence. Parts are separated by single spaces,
+/// except before closing punctuation, contractions and possessives (`'s`, `n't`), and after
+/// opening brackets. The first letter of each sentence is capitalized.
+pub fn compose<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut text = String::new();
+    let mut glue_next = true;
+    for part in parts {
+        let part = part.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if part.is_empty() {
+            continue;
+        }
+        let attaches = is_closing(&part) || part.starts_with('\'') || part.starts_with("n't");
+        if !glue_next && !attaches {
+            text.push(' ');
+        }
+        let sentence_start = text
+            .trim_end()
+            .chars()
+            .last()
+            .is_none_or(|c| matches!(c, '.' | '!' | '?'));
+        if sentence_start {
+            let mut chars = part.chars();
+            if let Some(first) = chars.next() {
+                text.extend(first.to_uppercase());
+                text.push_str(chars.as_str());
+            }
+        } else {
+            text.push_str(&part);
+        }
+        glue_next = is_opening(&part);
+    }
+    text
+}
+
+/// The text of each of `parts`, with modifier tiles, like `+ed`, applied to the part before
+/// them in `language`. A tile's own forms win over the language's rules, but only for the first
+/// modifier pressed after it.
+fn inflect(language: &str, parts: &[Part]) -> Vec<String> {
+    let mut texts: Vec<(String, Option<&Part>)> = Vec::new();
+    for part in parts {
+        match (Inflection::from_modifier(&part.text), texts.last_mut()) {
+            (Some(inflection), Some((text, tile))) => {
+                *text = match tile
+                    .take()
+                    .and_then(|tile| tile.inflections.get(&inflection))
+                {
+                    Some(form) => form.clone(),
+                    None => morphology::inflect_text(language, text, inflection),
+                };
+            }
+            // A modifier with nothing before it has nothing to change.
+            (Some(_), None) => (),
+            (None, _) => texts.push((part.text.clone(), Some(part))),
+        }
+    }
+    texts.into_iter().map(|(text, _)| text).collect()
+}
+
+/// Puts the text of `parts` together in `language`, expanding the user's abbreviations once the
+/// sentences are capitalized, so a shortcut starting one begins the expansion with a capital.
+fn text(language: &str, abbreviations: &Abbreviations, parts: &[Part]) -> String {
+    abbreviations.expand(&compose(
+        inflect(language, parts).iter().map(String::as_str),
+    ))
+}
+
+/// Device names come from the client, which picks one per device and remembers it.
+pub(crate) fn check_device(device: &str) -> Result<(), Error> {
+    if device.is_empty()
+        || device.chars().count() > MAX_DEVICE_CHARS
+        || !device
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(Error::MalformedRequest);
+    }
+    Ok(())
+}
+
+/// Reads the parts of the message in progress on `device`.
+async fn parts(conn: &db::Conn, uid: i32, device: &str) -> Result<Vec<Part>, Error> {
+    Ok(conn
+        .query_opt(
+            "SELECT parts FROM messages WHERE user_id = $1 AND device = $2",
+            &[&uid, &device],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .map(|row| row.get::<_, SqlJson<Vec<Part>>>("parts").0)
+        .unwrap_or_default())
+}
+
+async fn message(
+    conn: &db::Conn,
+    uid: i32,
+    device: String,
+    parts: Vec<Part>,
+) -> Result<Message, Error> {
+    let preferences = preferences::load(conn, uid).await?;
+    let abbreviations = abbreviation::abbreviations(conn, uid).await?;
+    let text = text(preferences.language(), &abbreviations, &parts);
+    let spoken = lexicon::lexicon(conn, uid).await?.apply(&text);
+    Ok(Message {
+        device,
+        parts,
+        text,
+        spoken,
+    })
+}
+
+pub async fn read_message(
+    username: String,
+    device: String,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    check_device(&device)?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let parts = parts(&conn, uid, &device).await?;
+    Ok(json(&message(&conn, uid, device, parts).await?))
+}
+
+pub async fn append_tile(
+    username: String,
+    device: String,
+    append: AppendTile,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    check_device(&device)?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let tile = usage::activate(
+        &conn,
+        uid,
+        &Activation {
+            phrase: append.phrase,
+            board: append.board,
+            device: Some(device.clone()),
+        },
+    )
+    .await?;
+    let part = Part {
+        phrase: tile.phrase,
+        text: tile.text,
+        inflections: tile.inflections,
+    };
+    let row = conn
+        .query_opt(
+            r#"
+            INSERT INTO messages (user_id, device, parts)
+            VALUES ($1, $2, jsonb_build_array($3::JSONB))
+            ON CONFLICT (user_id, device) DO UPDATE
+                SET parts = messages.parts || EXCLUDED.parts, updated_at = NOW()
+                WHERE jsonb_array_length(messages.parts) < $4
+            RETURNING parts
+            "#,
+            &[&uid, &device, &SqlJson(&part), &(MAX_PARTS as i32)],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::MalformedRequest)?;
+    let parts = row.get::<_, SqlJson<Vec<Part>>>("parts").0;
+    Ok(json(&message(&conn, uid, device, parts).await?))
+}
+
+pub async fn delete_last(
+    username: String,
+    device: String,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    check_device(&device)?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let parts = conn
+        .query_opt(
+            r#"
+            UPDATE messages SET parts = parts - -1, updated_at = NOW()
+            WHERE user_id = $1 AND device = $2
+            RETURNING parts
+            "#,
+            &[&uid, &device],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .map(|row| row.get::<_, SqlJson<Vec<Part>>>("parts").0)
+        .unwrap_or_default();
+    Ok(json(&message(&conn, uid, device, parts).await?))
+}
+
+pub async fn clear_message(
+    username: String,
+    device: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    check_device(&device)?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    conn.execute(
+        "DELETE FROM messages WHERE user_id = $1 AND device = $2",
+        &[&uid, &device],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
+
+/// Finishes the message in progress: it is logged and cleared, and returned for the device to
+/// speak.
+pub async fn speak_message(
+    username: String,
+    device: String,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    check_device(&device)?;
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let preferences = preferences::load(&conn, uid).await?;
+    let abbreviations = abbreviation::abbreviations(&conn, uid).await?;
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    let parts = tx
+        .query_opt(
+            "DELETE FROM messages WHERE user_id = $1 AND device = $2 RETURNING parts",
+            &[&uid, &device],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .map(|row| row.get::<_, SqlJson<Vec<Part>>>("parts").0)
+        .unwrap_or_default();
+    let text = text(preferences.language(), &abbreviations, &parts);
+    if text.is_empty() {
+        return Err(Error::MalformedRequest.into());
+    }
+    if preferences.keep_history {
+        tx.execute(
+            "INSERT INTO utterances (user_id, device, text) VALUES ($1, $2, $3)",
+            &[&uid, &device, &text],
+        )
+        .await
+        .map_err(Error::DBError)?;
+        predict::learn(&tx, uid, &text).await?;
+    }
+    tx.commit().await.map_err(Error::DBError)?;
+    usage::log_utterance(&conn, uid, &device, &text).await?;
+    Ok(with_status(
+        json(&message(&conn, uid, device, parts).await?),
+        StatusCode::CREATED,
+    ))
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Utterance {
+    pub device: String,
+    pub text: String,
+    pub spoken_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UtteranceQuery {
+    pub limit: Option<i64>,
+}
+
+/// Lists the messages the user spoke, newest first.
+pub async fn list_utterances(
+    username: String,
+    query: UtteranceQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Error::MalformedRequest.into());
+    }
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let utterances = conn
+        .query(
+            r#"
+            SELECT device, text, spoken_at FROM utterances
+            WHERE user_id = $1
+            ORDER BY spoken_at DESC, id DESC
+            LIMIT $2
+            "#,
+            &[&uid, &limit],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| Utterance {
+            device: row.get("device"),
+            text: row.get("text"),
+            spoken_at: row.get("spoken_at"),
+        })
+        .collect::<Vec<Utterance>>();
+    Ok(json(&utterances))
+}
@@ -0,0 +1,260 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Photos straight from a phone carry EXIF, XMP and IPTC metadata, including where they were
+//! taken. All of it is removed from uploads before they are stored. Metadata is cut out of the
+//! file where possible so the pictures themselves are untouched; photos that are only upright
+//! thanks to their orientation tag are turned the right way and encoded again instead.
+
+use std::io::Cursor;
+
+use ::image::{
+    codecs::jpeg::JpegEncoder, metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat,
+    ImageReader,
+};
+
+use crate::Error;
+
+/// The quality photos are encoded with again after being turned.
+const JPEG_QUALITY: u8 = 90;
+
+fn corrupt() -> Error {
+    Error::InvalidUpload("image is corrupt".to_string())
+}
+
+/// Removes the metadata from an image of type `content_type`.
+pub fn strip(data: Vec<u8>, content_type: &str) -> Result<Vec<u8>, Error> {
+    let format = match ImageFormat::from_mime_type(content_type) {
+        Some(format) => format,
+        None => return Ok(data),
+    };
+    if format != ImageFormat::Gif {
+        let mut decoder = ImageReader::with_format(Cursor::new(&data), format)
+            .into_decoder()
+            .map_err(|_| corrupt())?;
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        if orientation != Orientation::NoTransforms {
+            let mut img = DynamicImage::from_decoder(decoder).map_err(|_| corrupt())?;
+            img.apply_orientation(orientation);
+            return encode(&img, format);
+        }
+    }
+    match format {
+        ImageFormat::Jpeg => strip_jpeg(&data),
+        ImageFormat::Png => strip_png(&data),
+        ImageFormat::WebP => strip_webp(&data),
+        ImageFormat::Gif => strip_gif(&data),
+        _ => return Ok(data),
+    }
+    .ok_or_else(corrupt)
+}
+
+/// Encodes a turned image, which leaves out all metadata.
+fn encode(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, Error> {
+    let mut out = Cursor::new(Vec::new());
+    let written = match format {
+        ImageFormat::Jpeg => {
+            img.write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))
+        }
+        ImageFormat::WebP => DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut out, format),
+        _ => img.write_to(&mut out, format),
+    };
+    written.map_err(|_| corrupt())?;
+    Ok(out.into_inner())
+}
+
+fn read_u16_be(data: &[u8], at: usize) -> Option<usize> {
+    Some(u16::from_be_bytes([*data.get(at)?, *data.get(at + 1)?]) as usize)
+}
+
+fn read_u32_be(data: &[u8], at: usize) -> Option<usize> {
+    Some(u32::from_be_bytes([
+        *data.get(at)?,
+        *data.get(at + 1)?,
+        *data.get(at + 2)?,
+        *data.get(at + 3)?,
+    ]) as usize)
+}
+
+fn read_u32_le(data: &[u8], at: usize) -> Option<usize> {
+    Some(u32::from_le_bytes([
+        *data.get(at)?,
+        *data.get(at + 1)?,
+        *data.get(at + 2)?,
+        *data.get(at + 3)?,
+    ]) as usize)
+}
+
+/// Drops the APP1 (EXIF, XMP), APP13 (IPTC) and comment segments of a JPEG. Color profiles and
+/// the JFIF and Adobe segments stay, since they change how the picture looks, but only where
+/// they belong, before the first scan. Nothing after the end of the image is kept.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut out = data[..2].to_vec();
+    let mut at = 2;
+    let mut scanned = false;
+    loop {
+        if *data.get(at)? != 0xff {
+            return None;
+        }
+        // Markers may be padded with any number of fill bytes.
+        while *data.get(at + 1)? == 0xff {
+            at += 1;
+        }
+        let marker = data[at + 1];
+        if marker == 0xd9 {
+            out.extend_from_slice(&[0xff, 0xd9]);
+            return Some(out);
+        }
+        let end = at + 2 + read_u16_be(data, at + 2)?;
+        let segment = data.get(at..end)?;
+        let metadata = match marker {
+            0xe1 | 0xed | 0xfe => true,
+            0xe0..=0xef => scanned,
+            _ => false,
+        };
+        if !metadata {
+            out.extend_from_slice(segment);
+        }
+        at = end;
+        if marker == 0xda {
+            // The compressed picture follows the start of scan and is copied as it is, up to the
+            // next marker. Stuffed bytes and restart markers are part of it.
+            scanned = true;
+            let start = at;
+            loop {
+                match data.get(at..)?.iter().position(|&byte| byte == 0xff) {
+                    Some(ff) => at += ff,
+                    None => {
+                        // A picture cut short is still shown as far as it goes.
+                        out.extend_from_slice(&data[start..]);
+                        return Some(out);
+                    }
+                }
+                match data.get(at + 1) {
+                    Some(0x00) | Some(0xd0..=0xd7) => at += 2,
+                    _ => break,
+                }
+            }
+            out.extend_from_slice(&data[start..at]);
+        }
+    }
+}
+
+/// Drops the EXIF, text and timestamp chunks of a PNG.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut out = SIGNATURE.to_vec();
+    let mut at = SIGNATURE.len();
+    while at < data.len() {
+        // Length, type, data and checksum.
+        let end = at + 12 + read_u32_be(data, at)?;
+        let chunk = data.get(at..end)?;
+        if !matches!(
+            &chunk[4..8],
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"
+        ) {
+            out.extend_from_slice(chunk);
+        }
+        at = end;
+    }
+    Some(out)
+}
+
+/// Drops the EXIF and XMP chunks of a WebP and the flags announcing them.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut out = data[..12].to_vec();
+    let mut at = 12;
+    while at < data.len() {
+        // Chunks are padded to an even length.
+        let size = read_u32_le(data, at + 4)?;
+        let end = (at + 8 + size + size % 2).min(data.len());
+        let chunk = data.get(at..end)?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => (),
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                *chunk.get_mut(8)? &= !0b1100;
+                out.extend_from_slice(&chunk);
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        at = end;
+    }
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+/// Drops the comment and XMP extensions of a GIF.
+fn strip_gif(data: &[u8]) -> Option<Vec<u8>> {
+    // Skips a run of data sub-blocks starting at `at`, returning where they end.
+    fn sub_blocks(data: &[u8], mut at: usize) -> Option<usize> {
+        loop {
+            let size = *data.get(at)? as usize;
+            at += 1 + size;
+            if size == 0 {
+                return Some(at);
+            }
+        }
+    }
+    // Also skips a color table following a block whose packed field is `flags`.
+    fn color_table(flags: u8) -> usize {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 0x07) + 1)
+        } else {
+            0
+        }
+    }
+
+    let mut at = 13 + color_table(*data.get(10)?);
+    let mut out = data.get(..at)?.to_vec();
+    loop {
+        match *data.get(at)? {
+            0x3b => {
+                out.push(0x3b);
+                return Some(out);
+            }
+            0x21 => {
+                let label = *data.get(at + 1)?;
+                let end = sub_blocks(data, at + 2)?;
+                let xmp = label == 0xff && data.get(at + 3..at + 14) == Some(&b"XMP DataXMP"[..]);
+                if label != 0xfe && !xmp {
+                    out.extend_from_slice(&data[at..end]);
+                }
+                at = end;
+            }
+            0x2c => {
+                // Descriptor, color table, code size, then the picture itself.
+                let start = at;
+                at += 10 + color_table(*data.get(at + 9)?) + 1;
+                at = sub_blocks(data, at)?;
+                out.extend_from_slice(data.get(start..at)?);
+            }
+            _ => return None,
+        }
+    }
+}
@@ -0,0 +1,400 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Inflecting words for the modifier keys on core vocabulary boards. A modifier tile, like `+s`
+//! or `+ed`, changes the word on the tile pressed before it: `go` `+ed` says "went". Each
+//! language has its own rules and irregular words, and a tile can override any form it takes.
+
+use serde::{Deserialize, Serialize};
+
+/// The longest form a tile may override an inflection with, in characters.
+pub const MAX_FORM_CHARS: usize = 100;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Inflection {
+    /// More than one of a thing, or what one other person does: `+s`.
+    Plural,
+    /// Something already done: `+ed`.
+    Past,
+    /// Something being done: `+ing`.
+    Progressive,
+    /// Belonging to someone: `+'s`.
+    Possessive,
+}
+
+impl Inflection {
+    /// The inflection a modifier tile applies, read from what the tile says.
+    pub fn from_modifier(text: &str) -> Option<Self> {
+        match text.trim() {
+            "+s" | "+es" => Some(Inflection::Plural),
+            "+ed" | "+d" => Some(Inflection::Past),
+            "+ing" => Some(Inflection::Progressive),
+            "+'s" | "+\u{2019}s" => Some(Inflection::Possessive),
+            _ => None,
+        }
+    }
+}
+
+/// Inflects a single lowercase `word` by the rules of `language`, or gives `None` when the
+/// language has no such inflection or the word doesn't take it.
+pub fn inflect(language: &str, word: &str, inflection: Inflection) -> Option<String> {
+    match language {
+        "en" => Some(english(word, inflection)),
+        "es" => spanish(word, inflection),
+        _ => None,
+    }
+}
+
+/// Inflects the last word of `text`, keeping its capital. Text without a word the language can
+/// inflect is kept as it is.
+pub fn inflect_text(language: &str, text: &str, inflection: Inflection) -> String {
+    let end = match text.char_indices().rev().find(|(_, c)| c.is_alphabetic()) {
+        Some((i, c)) => i + c.len_utf8(),
+        None => return text.to_string(),
+    };
+    let start = text[..end]
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !c.is_alphabetic())
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let word = &text[start..end];
+    let lower = word.to_lowercase();
+    match inflect(language, &lower, inflection) {
+        Some(inflected) => {
+            let inflected = if word.starts_with(char::is_uppercase) {
+                let mut chars = inflected.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            } else {
+                inflected
+            };
+            format!("{}{}{}", &text[..start], inflected, &text[end..])
+        }
+        None => text.to_string(),
+    }
+}
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'i' | 'o' | 'u')
+}
+
+/// Looks `word` up in a table of irregular forms.
+fn irregular(table: &[(&str, &str)], word: &str) -> Option<String> {
+    table
+        .iter()
+        .find(|(base, _)| *base == word)
+        .map(|(_, form)| form.to_string())
+}
+
+const ENGLISH_PLURALS: &[(&str, &str)] = &[
+    ("be", "is"),
+    ("child", "children"),
+    ("deer", "deer"),
+    ("do", "does"),
+    ("fish", "fish"),
+    ("foot", "feet"),
+    ("go", "goes"),
+    ("goose", "geese"),
+    ("half", "halves"),
+    ("have", "has"),
+    ("knife", "knives"),
+    ("leaf", "leaves"),
+    ("life", "lives"),
+    ("loaf", "loaves"),
+    ("man", "men"),
+    ("mouse", "mice"),
+    ("person", "people"),
+    ("potato", "potatoes"),
+    ("sheep", "sheep"),
+    ("shelf", "shelves"),
+    ("tomato", "tomatoes"),
+    ("tooth", "teeth"),
+    ("wife", "wives"),
+    ("wolf", "wolves"),
+    ("woman", "women"),
+];
+
+const ENGLISH_PAST: &[(&str, &str)] = &[
+    ("be", "was"),
+    ("become", "became"),
+    ("begin", "began"),
+    ("bite", "bit"),
+    ("blow", "blew"),
+    ("break", "broke"),
+    ("bring", "brought"),
+    ("build", "built"),
+    ("buy", "bought"),
+    ("can", "could"),
+    ("catch", "caught"),
+    ("choose", "chose"),
+    ("come", "came"),
+    ("cost", "cost"),
+    ("cut", "cut"),
+    ("do", "did"),
+    ("draw", "drew"),
+    ("drink", "drank"),
+    ("drive", "drove"),
+    ("eat", "ate"),
+    ("fall", "fell"),
+    ("feed", "fed"),
+    ("feel", "felt"),
+    ("fight", "fought"),
+    ("find", "found"),
+    ("fly", "flew"),
+    ("forget", "forgot"),
+    ("get", "got"),
+    ("give", "gave"),
+    ("go", "went"),
+    ("grow", "grew"),
+    ("have", "had"),
+    ("hear", "heard"),
+    ("hide", "hid"),
+    ("hit", "hit"),
+    ("hold", "held"),
+    ("hurt", "hurt"),
+    ("keep", "kept"),
+    ("know", "knew"),
+    ("lead", "led"),
+    ("leave", "left"),
+    ("let", "let"),
+    ("lose", "lost"),
+    ("make", "made"),
+    ("mean", "meant"),
+    ("meet", "met"),
+    ("pay", "paid"),
+    ("put", "put"),
+    ("quit", "quit"),
+    ("read", "read"),
+    ("ride", "rode"),
+    ("ring", "rang"),
+    ("run", "ran"),
+    ("say", "said"),
+    ("see", "saw"),
+    ("sell", "sold"),
+    ("send", "sent"),
+    ("set", "set"),
+    ("shut", "shut"),
+    ("sing", "sang"),
+    ("sit", "sat"),
+    ("sleep", "slept"),
+    ("speak", "spoke"),
+    ("spend", "spent"),
+    ("stand", "stood"),
+    ("swim", "swam"),
+    ("take", "took"),
+    ("teach", "taught"),
+    ("tell", "told"),
+    ("think", "thought"),
+    ("throw", "threw"),
+    ("understand", "understood"),
+    ("wake", "woke"),
+    ("wear", "wore"),
+    ("will", "would"),
+    ("win", "won"),
+    ("write", "wrote"),
+];
+
+const ENGLISH_PROGRESSIVE: &[(&str, &str)] = &[("be", "being"), ("see", "seeing")];
+
+/// Whether the last consonant of `word` doubles before an ending, as in `stop` to `stopped`:
+/// words of one vowel ending in a consonant, vowel, consonant other than `w`, `x` or `y`.
+fn doubles_final(word: &str) -> bool {
+    let chars = word.chars().collect::<Vec<char>>();
+    let vowels = chars.iter().filter(|c| is_vowel(**c)).count();
+    match chars.as_slice() {
+        [.., a, b, c] => {
+            vowels == 1
+                && !is_vowel(*a)
+                && is_vowel(*b)
+                && c.is_ascii_alphabetic()
+                && !is_vowel(*c)
+                && !matches!(c, 'w' | 'x' | 'y')
+        }
+        _ => false,
+    }
+}
+
+/// Whether the final `z` of `word` doubles before `es`, as in `quiz` to `quizzes`: words of one
+/// vowel, not counting the `u` after a `q`, ending in a consonant, vowel, `z`.
+fn doubles_z(word: &str) -> bool {
+    let chars = word.replace("qu", "q").chars().collect::<Vec<char>>();
+    let vowels = chars.iter().filter(|c| is_vowel(**c)).count();
+    match chars.as_slice() {
+        [.., a, b, 'z'] => vowels == 1 && !is_vowel(*a) && is_vowel(*b),
+        _ => false,
+    }
+}
+
+/// Whether `word` ends in a consonant followed by `y`, as in `cry`.
+fn consonant_y(word: &str) -> bool {
+    let mut chars = word.chars().rev();
+    chars.next() == Some('y') && chars.next().is_some_and(|c| !is_vowel(c))
+}
+
+fn english(word: &str, inflection: Inflection) -> String {
+    match inflection {
+        Inflection::Plural => irregular(ENGLISH_PLURALS, word).unwrap_or_else(|| {
+            if doubles_z(word) {
+                format!("{}zes", word)
+            } else if ["s", "x", "z", "ch", "sh"]
+                .iter()
+                .any(|end| word.ends_with(end))
+            {
+                format!("{}es", word)
+            } else if consonant_y(word) {
+                format!("{}ies", &word[..word.len() - 1])
+            } else {
+                format!("{}s", word)
+            }
+        }),
+        Inflection::Past => irregular(ENGLISH_PAST, word).unwrap_or_else(|| {
+            if word.ends_with('e') {
+                format!("{}d", word)
+            } else if consonant_y(word) {
+                format!("{}ied", &word[..word.len() - 1])
+            } else if doubles_final(word) {
+                format!("{}{}ed", word, &word[word.len() - 1..])
+            } else {
+                format!("{}ed", word)
+            }
+        }),
+        Inflection::Progressive => irregular(ENGLISH_PROGRESSIVE, word).unwrap_or_else(|| {
+            if let Some(stem) = word.strip_suffix("ie") {
+                format!("{}ying", stem)
+            } else if word.ends_with('e')
+                && !["ee", "ye", "oe"].iter().any(|end| word.ends_with(end))
+            {
+                format!("{}ing", &word[..word.len() - 1])
+            } else if doubles_final(word) {
+                format!("{}{}ing", word, &word[word.len() - 1..])
+            } else {
+                format!("{}ing", word)
+            }
+        }),
+        Inflection::Possessive => {
+            if word.ends_with('s') {
+                format!("{}'", word)
+            } else {
+                format!("{}'s", word)
+            }
+        }
+    }
+}
+
+const SPANISH_GERUNDS: &[(&str, &str)] = &[
+    ("decir", "diciendo"),
+    ("dormir", "durmiendo"),
+    ("ir", "yendo"),
+    ("morir", "muriendo"),
+    ("pedir", "pidiendo"),
+    ("poder", "pudiendo"),
+    ("reír", "riendo"),
+    ("seguir", "siguiendo"),
+    ("sentir", "sintiendo"),
+    ("servir", "sirviendo"),
+    ("venir", "viniendo"),
+    ("vestir", "vistiendo"),
+];
+
+/// The Spanish vowel `c` without its written accent, if it has one.
+fn spanish_unaccented(c: char) -> Option<char> {
+    match c {
+        'á' => Some('a'),
+        'é' => Some('e'),
+        'í' => Some('i'),
+        'ó' => Some('o'),
+        'ú' => Some('u'),
+        _ => None,
+    }
+}
+
+/// Whether the last syllable of `word` is stressed, which for words ending in `s` or `x` means it
+/// carries a written accent or is the only syllable.
+fn spanish_stressed_last(word: &str) -> bool {
+    let is_spanish_vowel = |c: char| is_vowel(c) || c == 'ü' || spanish_unaccented(c).is_some();
+    let chars = word.chars().collect::<Vec<char>>();
+    let groups = chars
+        .split(|c| !is_spanish_vowel(*c))
+        .filter(|group| !group.is_empty())
+        .collect::<Vec<&[char]>>();
+    match groups.last() {
+        Some(last) => groups.len() == 1 || last.iter().any(|c| spanish_unaccented(*c).is_some()),
+        None => true,
+    }
+}
+
+/// Drops the written accent marking a stressed last syllable ending in `n` or `s`, as in `ratón`,
+/// which the plural's extra syllable makes unnecessary. An accent keeping two vowels apart, as in
+/// `país`, stays.
+fn spanish_drop_final_accent(word: &str) -> String {
+    let mut chars = word.chars().collect::<Vec<char>>();
+    let n = chars.len();
+    if n >= 2 && matches!(chars[n - 1], 'n' | 's') {
+        if let Some(plain) = spanish_unaccented(chars[n - 2]) {
+            let hiatus = matches!(plain, 'i' | 'u') && n >= 3 && is_vowel(chars[n - 3]);
+            if !hiatus {
+                chars[n - 2] = plain;
+            }
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// Spanish has plurals and gerunds, but its past tenses and possessives depend on who is
+/// speaking, so those are left to tiles of their own.
+fn spanish(word: &str, inflection: Inflection) -> Option<String> {
+    match inflection {
+        Inflection::Plural => {
+            let last = word.chars().last()?;
+            Some(if let Some(stem) = word.strip_suffix('z') {
+                format!("{}ces", stem)
+            } else if is_vowel(last) || matches!(last, 'á' | 'é' | 'ó') {
+                format!("{}s", word)
+            } else if matches!(last, 's' | 'x') && !spanish_stressed_last(word) {
+                // Like `lunes` or `tórax`, which are the same in the plural.
+                word.to_string()
+            } else {
+                format!("{}es", spanish_drop_final_accent(word))
+            })
+        }
+        Inflection::Progressive => irregular(SPANISH_GERUNDS, word).or_else(|| {
+            if let Some(stem) = word.strip_suffix("ar") {
+                Some(format!("{}ando", stem))
+            } else {
+                let stem = word
+                    .strip_suffix("er")
+                    .or_else(|| word.strip_suffix("ir"))
+                    .or_else(|| word.strip_suffix("ír"))?;
+                // An `i` between vowels is written `y`, as in `leyendo`, though the `u` of `gu`
+                // and `qu` is silent.
+                if stem.chars().last().is_some_and(is_vowel)
+                    && !stem.ends_with("gu")
+                    && !stem.ends_with("qu")
+                {
+                    Some(format!("{}yendo", stem))
+                } else {
+                    Some(format!("{}iendo", stem))
+                }
+            }
+        }),
+        Inflection::Past | Inflection::Possessive => None,
+    }
+}
@@ -0,0 +1,363 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Suggests which tile comes next from what the user has said so far. Every message a user
+//! speaks teaches their own trigram model, and a model of everyday sentences bundled with the
+//! server fills in until they have said enough for theirs to be useful. Suggestions are only
+//! ever tiles the user has.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::OnceLock,
+};
+
+use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::Transaction;
+use serde::{Deserialize, Serialize};
+use warp::{
+    reply::{json, Json},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    db, guard,
+    tile::{self, Tile, TILE_COLUMNS},
+    user, Error,
+};
+
+/// The sentences of the fallback model, one per line after the comments.
+const CORPUS: &str = include_str!("../data/corpus_en.txt");
+
+/// Marks the start of a message, so the model learns how messages begin.
+const START: &str = "<s>";
+/// How many preceding words the model conditions on.
+const ORDER: usize = 2;
+/// How much a shorter context counts when backing off from a longer one.
+const BACKOFF: f64 = 0.4;
+/// How much the fallback model counts next to the user's own.
+const FALLBACK_WEIGHT: f64 = 0.25;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("predict"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(predict)
+}
+
+/// Splits text into lowercase words, keeping apostrophes so contractions stay whole. The
+/// schema's `first_word` finds the first of them the same way.
+pub(crate) fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .map(|word| word.trim_matches('\'').to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Every context and following word in `text`, the context being up to [`ORDER`] preceding
+/// words, or none.
+fn ngrams(text: &str) -> Vec<(String, String)> {
+    let mut tokens = vec![START.to_string()];
+    tokens.extend(words(text));
+    let mut ngrams = Vec::new();
+    for i in 1..tokens.len() {
+        ngrams.push((String::new(), tokens[i].clone()));
+        for n in 1..=ORDER.min(i) {
+            ngrams.push((tokens[i - n..i].join(" "), tokens[i].clone()));
+        }
+    }
+    ngrams
+}
+
+/// The contexts to look up after `context`, longest first.
+fn contexts(context: &str) -> Vec<String> {
+    let mut tokens = vec![START.to_string()];
+    tokens.extend(words(context));
+    let mut contexts = (1..=ORDER.min(tokens.len()))
+        .rev()
+        .map(|n| tokens[tokens.len() - n..].join(" "))
+        .collect::<Vec<String>>();
+    contexts.push(String::new());
+    contexts
+}
+
+/// How often each word followed each context, and how often each context was seen.
+#[derive(Debug, Default)]
+struct Counts {
+    pairs: HashMap<(String, String), i64>,
+    totals: HashMap<String, i64>,
+    followers: HashMap<String, Vec<String>>,
+}
+
+impl Counts {
+    fn add(&mut self, context: String, word: String, count: i64) {
+        *self.totals.entry(context.clone()).or_insert(0) += count;
+        match self.pairs.entry((context, word)) {
+            Entry::Occupied(mut entry) => *entry.get_mut() += count,
+            Entry::Vacant(entry) => {
+                let (context, word) = entry.key().clone();
+                self.followers.entry(context).or_default().push(word);
+                entry.insert(count);
+            }
+        }
+    }
+
+    /// Every word seen after any of the `contexts`.
+    fn followers(&self, contexts: &[String]) -> HashSet<&str> {
+        contexts
+            .iter()
+            .filter_map(|context| self.followers.get(context))
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Scores `word` after the `contexts`, longest first, backing off to shorter contexts.
+    fn score(&self, contexts: &[String], word: &str) -> f64 {
+        let mut weight = 1.0;
+        for context in contexts {
+            let total = self.totals.get(context).copied().unwrap_or(0);
+            let count = self
+                .pairs
+                .get(&(context.clone(), word.to_string()))
+                .copied()
+                .unwrap_or(0);
+            if count > 0 {
+                return weight * count as f64 / total as f64;
+            }
+            weight *= BACKOFF;
+        }
+        0.0
+    }
+}
+
+/// The fallback model, built from the bundled sentences the first time it is needed.
+fn fallback() -> &'static Counts {
+    static FALLBACK: OnceLock<Counts> = OnceLock::new();
+    FALLBACK.get_or_init(|| {
+        let mut counts = Counts::default();
+        for line in CORPUS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            for (context, word) in ngrams(line) {
+                counts.add(context, word, 1);
+            }
+        }
+        counts
+    })
+}
+
+async fn add_ngrams(tx: &Transaction<'_>, uid: i32, texts: &[String]) -> Result<(), Error> {
+    let (contexts, words): (Vec<String>, Vec<String>) =
+        texts.iter().flat_map(|text| ngrams(text)).unzip();
+    tx.execute(
+        r#"
+        INSERT INTO ngrams (user_id, context, word, count)
+        SELECT $1, context, word, COUNT(*) FROM UNNEST($2::TEXT[], $3::TEXT[]) AS t(context, word)
+        GROUP BY context, word
+        ON CONFLICT (user_id, context, word) DO UPDATE SET count = ngrams.count + EXCLUDED.count
+        "#,
+        &[&uid, &contexts, &words],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(())
+}
+
+/// Teaches the model of the user `uid` the message `text` they just spoke, already stored with
+/// the rest of their history. A model that doesn't exist yet is built from that whole history,
+/// so messages spoken before models existed, or before the model was erased, are learned once.
+pub(crate) async fn learn(tx: &Transaction<'_>, uid: i32, text: &str) -> Result<(), Error> {
+    let claimed = tx
+        .execute(
+            "INSERT INTO ngram_models (user_id) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    if claimed > 0 {
+        let texts = tx
+            .query("SELECT text FROM utterances WHERE user_id = $1", &[&uid])
+            .await
+            .map_err(Error::DBError)?
+            .iter()
+            .map(|row| row.get("text"))
+            .collect::<Vec<String>>();
+        return add_ngrams(tx, uid, &texts).await;
+    }
+    tx.execute(
+        "UPDATE ngram_models SET updated_at = NOW() WHERE user_id = $1",
+        &[&uid],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    add_ngrams(tx, uid, &[text.to_string()]).await
+}
+
+/// Reads the parts of the model of the user `uid` needed to score `candidates` after `contexts`.
+async fn counts(
+    conn: &db::Conn,
+    uid: i32,
+    contexts: &[String],
+    candidates: &[String],
+) -> Result<Counts, Error> {
+    let mut counts = Counts::default();
+    for row in conn
+        .query(
+            "SELECT context, SUM(count)::BIGINT AS total FROM ngrams
+            WHERE user_id = $1 AND context = ANY($2)
+            GROUP BY context",
+            &[&uid, &contexts],
+        )
+        .await
+        .map_err(Error::DBError)?
+    {
+        counts.totals.insert(row.get("context"), row.get("total"));
+    }
+    for row in conn
+        .query(
+            "SELECT context, word, count::BIGINT AS count FROM ngrams
+            WHERE user_id = $1 AND context = ANY($2) AND word = ANY($3)",
+            &[&uid, &contexts, &candidates],
+        )
+        .await
+        .map_err(Error::DBError)?
+    {
+        counts
+            .pairs
+            .insert((row.get("context"), row.get("word")), row.get("count"));
+    }
+    Ok(counts)
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PredictQuery {
+    /// What the user has said so far.
+    #[serde(default)]
+    pub context: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Prediction {
+    pub tile: Tile,
+    pub score: f64,
+}
+
+/// Ranks the tiles the user can see by how likely each is to come next, by the first word it
+/// says.
+pub async fn predict(
+    username: String,
+    query: PredictQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(Error::MalformedRequest.into());
+    }
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+
+    // Only tiles saying a word the user's model or the fallback has seen after the context can
+    // score, so those are the only ones read.
+    let contexts = contexts(&query.context);
+    let fallback = fallback();
+    let fallback_words = fallback
+        .followers(&contexts)
+        .into_iter()
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    let rows = conn
+        .query(
+            format!(
+                r#"
+                SELECT {}, first_word(COALESCE(tiles.speech, tiles.phrase)) AS word FROM tiles
+                WHERE (user_id IS NULL OR user_id = $1)
+                    AND NOT EXISTS (
+                        SELECT 1 FROM hidden_tiles
+                        WHERE hidden_tiles.user_id = $1 AND hidden_tiles.tile_id = tiles.id
+                    )
+                    AND first_word(COALESCE(speech, phrase)) IN (
+                        SELECT word FROM ngrams WHERE user_id = $1 AND context = ANY($2)
+                        UNION SELECT UNNEST($3::TEXT[])
+                    )
+                ORDER BY phrase
+                "#,
+                TILE_COLUMNS
+            )
+            .as_str(),
+            &[&uid, &contexts, &fallback_words],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    let keys = rows
+        .iter()
+        .map(|row| row.get("word"))
+        .collect::<Vec<String>>();
+    let tiles = rows.iter().map(Tile::from).collect::<Vec<Tile>>();
+    let candidates = keys
+        .iter()
+        .cloned()
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+
+    let own = counts(&conn, uid, &contexts, &candidates).await?;
+    let mut scores = candidates
+        .iter()
+        .map(|word| {
+            let score =
+                own.score(&contexts, word) + FALLBACK_WEIGHT * fallback.score(&contexts, word);
+            (word.as_str(), score)
+        })
+        .collect::<HashMap<&str, f64>>();
+    scores.retain(|_, score| *score > 0.0);
+
+    let mut predictions = tiles
+        .into_iter()
+        .zip(keys.iter())
+        .filter_map(|(tile, key)| {
+            scores.get(key.as_str()).map(|score| Prediction {
+                tile,
+                score: *score,
+            })
+        })
+        .collect::<Vec<Prediction>>();
+    // Tiles are already in phrase order, which breaks ties.
+    predictions.sort_by(|a, b| b.score.total_cmp(&a.score));
+    predictions.truncate(limit);
+    let mut tiles = predictions
+        .iter()
+        .map(|p| p.tile.clone())
+        .collect::<Vec<Tile>>();
+    tile::personalize(&conn, uid, &mut tiles).await?;
+    for (prediction, tile) in predictions.iter_mut().zip(tiles) {
+        prediction.tile = tile;
+    }
+    Ok(json(&predictions))
+}
@@ -0,0 +1,311 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Settings that follow a user from device to device: how they are spoken to and how their
+//! boards are laid out. Preferences are stored as one document per user, and anything missing
+//! from it, whether never set or added in a later version, takes its default.
+
+use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::types::Json as SqlJson;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::{
+    reply::{json, Json},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, speech, user, Error};
+
+/// The version of the preferences document this server writes. Documents from older versions
+/// are read by filling in the settings they lack with defaults.
+pub const PREFERENCES_VERSION: u32 = 1;
+
+/// The largest board, in tiles along each side.
+const MAX_GRID: u32 = 12;
+/// The smallest and largest font scales.
+const FONT_SCALES: (f64, f64) = (0.5, 3.0);
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let read_preferences = warp::get()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(read_preferences);
+
+    let replace_preferences = warp::put()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(replace_preferences);
+
+    let update_preferences = warp::patch()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("preferences"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool))
+        .and_then(update_preferences);
+
+    read_preferences
+        .or(replace_preferences)
+        .or(update_preferences)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Grid {
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid {
+            columns: 4,
+            rows: 3,
+        }
+    }
+}
+
+/// Where a tile's phrase is shown relative to its image.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelPosition {
+    Above,
+    #[default]
+    Below,
+    Hidden,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preferences {
+    /// The version of the document, always the current one in responses.
+    pub version: u32,
+    /// The voice speech is synthesized with, by its engine specific name.
+    pub voice: String,
+    /// Words per minute.
+    pub rate: u32,
+    /// From 0 to 99, where 50 is the voice's normal pitch.
+    pub pitch: u32,
+    pub grid: Grid,
+    /// How much larger than normal text is drawn.
+    pub font_scale: f64,
+    pub label_position: LabelPosition,
+    pub high_contrast: bool,
+    /// Whether the tiles pressed and messages spoken are logged for therapists to review.
+    pub log_usage: bool,
+    /// Whether the messages spoken are kept in the user's history, where they also teach their
+    /// word predictions. What was kept stays until usage is erased.
+    pub keep_history: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        let voice = speech::Voice::default();
+        Preferences {
+            version: PREFERENCES_VERSION,
+            voice: voice.name,
+            rate: voice.rate,
+            pitch: voice.pitch,
+            grid: Grid::default(),
+            font_scale: 1.0,
+            label_position: LabelPosition::default(),
+            high_contrast: false,
+            log_usage: false,
+            keep_history: false,
+        }
+    }
+}
+
+impl Preferences {
+    /// The voice speech is synthesized with when a request doesn't ask for another.
+    pub fn voice(&self) -> speech::Voice {
+        speech::Voice {
+            name: self.voice.clone(),
+            rate: self.rate,
+            pitch: self.pitch,
+        }
+    }
+
+    /// The language the user speaks, from the start of their voice's name, like `en` for
+    /// `en-us`.
+    pub fn language(&self) -> &str {
+        self.voice.split(['-', '_']).next().unwrap_or_default()
+    }
+
+    /// Checks every setting is within range, and brings the document to the current version.
+    fn validate(mut self) -> Result<Self, Error> {
+        self.voice().validate()?;
+        if self.version > PREFERENCES_VERSION
+            || !(1..=MAX_GRID).contains(&self.grid.columns)
+            || !(1..=MAX_GRID).contains(&self.grid.rows)
+            || !(FONT_SCALES.0..=FONT_SCALES.1).contains(&self.font_scale)
+        {
+            return Err(Error::MalformedRequest);
+        }
+        self.version = PREFERENCES_VERSION;
+        Ok(self)
+    }
+}
+
+/// Reads the stored preferences document of `uid`, if there is one.
+async fn document(conn: &db::Conn, uid: i32) -> Result<Option<Value>, Error> {
+    Ok(conn
+        .query_opt(
+            "SELECT document FROM preferences WHERE user_id = $1",
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .map(|row| row.get::<_, SqlJson<Value>>("document").0))
+}
+
+/// Reads a stored preferences document. Requests may only hold known settings, but settings
+/// stored by a version that had them are ignored once they are gone.
+fn from_document(doc: Value) -> Result<Preferences, Error> {
+    let known = match serde_json::to_value(Preferences::default()) {
+        Ok(Value::Object(known)) => known,
+        _ => return Err(Error::MalformedRequest),
+    };
+    let doc: serde_json::Map<String, Value> = match doc {
+        Value::Object(doc) => doc
+            .into_iter()
+            .filter(|(setting, _)| known.contains_key(setting))
+            .collect(),
+        _ => return Err(Error::MalformedRequest),
+    };
+    let preferences: Preferences =
+        serde_json::from_value(Value::Object(doc)).map_err(|_| Error::MalformedRequest)?;
+    Ok(Preferences {
+        version: PREFERENCES_VERSION,
+        ..preferences
+    })
+}
+
+/// Reads the preferences of `uid`, with defaults for everything they haven't set.
+pub async fn load(conn: &db::Conn, uid: i32) -> Result<Preferences, Error> {
+    match document(conn, uid).await? {
+        Some(doc) => from_document(doc),
+        None => Ok(Preferences::default()),
+    }
+}
+
+async fn store(conn: &db::Conn, uid: i32, preferences: &Preferences) -> Result<(), Error> {
+    let doc = serde_json::to_value(preferences).map_err(|_| Error::MalformedRequest)?;
+    conn.execute(
+        r#"
+        INSERT INTO preferences (user_id, document) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET document = EXCLUDED.document
+        "#,
+        &[&uid, &SqlJson(doc)],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(())
+}
+
+pub async fn read_preferences(
+    username: String,
+    _actor: String,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(&load(&conn, uid).await?))
+}
+
+/// Replaces every setting; those left out go back to their defaults.
+pub async fn replace_preferences(
+    username: String,
+    _actor: String,
+    preferences: Preferences,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let preferences = preferences.validate()?;
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    store(&conn, uid, &preferences).await?;
+    Ok(json(&preferences))
+}
+
+/// Changes only the settings given, so devices changing different settings don't undo each
+/// other. Nested settings, like the grid, are replaced whole.
+pub async fn update_preferences(
+    username: String,
+    _actor: String,
+    changes: Value,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let changes = match changes {
+        Value::Object(changes) => changes,
+        _ => return Err(Error::MalformedRequest.into()),
+    };
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    // The document is locked from reading it to writing the merge, so changes made at once
+    // are applied one after the other.
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    tx.execute(
+        r#"
+        INSERT INTO preferences (user_id, document) VALUES ($1, '{}')
+        ON CONFLICT (user_id) DO NOTHING
+        "#,
+        &[&uid],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    let current = tx
+        .query_one(
+            "SELECT document FROM preferences WHERE user_id = $1 FOR UPDATE",
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .get::<_, SqlJson<Value>>("document")
+        .0;
+    let mut doc = match serde_json::to_value(from_document(current)?) {
+        Ok(Value::Object(doc)) => doc,
+        _ => return Err(Error::MalformedRequest.into()),
+    };
+    doc.extend(changes);
+    let preferences = serde_json::from_value::<Preferences>(Value::Object(doc))
+        .map_err(|_| Error::MalformedRequest)?
+        .validate()?;
+    let doc = serde_json::to_value(&preferences).map_err(|_| Error::MalformedRequest)?;
+    tx.execute(
+        "UPDATE preferences SET document = $2 WHERE user_id = $1",
+        &[&uid, &SqlJson(doc)],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    tx.commit().await.map_err(Error::DBError)?;
+    Ok(json(&preferences))
+}
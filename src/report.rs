@@ -0,0 +1,330 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Reports on a user's logged usage for their therapists: how much they say, how varied and how
+//! long their messages are, and how that changes over time. Words are counted from the tiles
+//! pressed, message lengths from the messages spoken, and days are UTC days.
+
+use chrono::{Duration, NaiveDate, Utc};
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::{HeaderValue, Response},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, user, Error};
+
+/// The core vocabulary, one word per line after the comments.
+const CORE_WORDS: &str = include_str!("../data/core_words.txt");
+
+/// How many days a report covers when no range is given.
+const DEFAULT_DAYS: i64 = 30;
+/// The longest range a report may cover, in days.
+const MAX_DAYS: i64 = 366;
+/// How many of the most used tiles are listed.
+const MOST_USED: i64 = 10;
+
+/// Splits text into lowercase words in SQL, keeping apostrophes so contractions stay whole.
+const WORD_PATTERN: &str = "[^[:alnum:]'']+";
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("report"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(read_report)
+}
+
+fn core_words() -> Vec<String> {
+    CORE_WORDS
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    /// One row per day, for clinical records.
+    Csv,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ReportQuery {
+    /// The first day covered, 29 days before `to` by default.
+    pub from: Option<NaiveDate>,
+    /// The last day covered, today by default.
+    pub to: Option<NaiveDate>,
+    pub format: Option<ReportFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileCount {
+    pub phrase: String,
+    pub count: i64,
+}
+
+/// The figures for a single day, or for a whole report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Figures {
+    /// Words said by pressing tiles.
+    pub words: i64,
+    /// Distinct words among them.
+    pub unique_words: i64,
+    /// Words among them from the core vocabulary.
+    pub core_words: i64,
+    /// The share of words from the core vocabulary, the rest being fringe vocabulary.
+    pub core_ratio: Option<f64>,
+    /// Messages spoken.
+    pub utterances: i64,
+    /// The mean length of the messages spoken, in words.
+    pub mean_length_of_utterance: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Day {
+    pub date: NaiveDate,
+    #[serde(flatten)]
+    pub figures: Figures,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    #[serde(flatten)]
+    pub totals: Figures,
+    pub words_per_day: f64,
+    pub most_used: Vec<TileCount>,
+    /// Every day in the range, for following trends.
+    pub days: Vec<Day>,
+}
+
+fn ratio(part: i64, whole: i64) -> Option<f64> {
+    if whole == 0 {
+        None
+    } else {
+        Some(part as f64 / whole as f64)
+    }
+}
+
+/// Works out the figures grouped by `group`, an SQL expression of the day an event happened on.
+/// Returns one row per group with the columns of [`Figures`].
+fn figures_sql(group: &str) -> String {
+    format!(
+        r#"
+        WITH events AS (
+            SELECT {group} AS grp, id, kind, text FROM usage_events
+            WHERE user_id = $1
+                AND occurred_at >= $2::DATE::TIMESTAMP AT TIME ZONE 'UTC'
+                AND occurred_at < ($3::DATE + 1)::TIMESTAMP AT TIME ZONE 'UTC'
+        ),
+        words AS (
+            SELECT grp, id, kind, word
+            FROM events, regexp_split_to_table(lower(text), '{pattern}') AS word
+            WHERE word <> ''
+        ),
+        tile_words AS (
+            SELECT grp,
+                COUNT(*) AS words,
+                COUNT(DISTINCT word) AS unique_words,
+                COUNT(*) FILTER (WHERE word = ANY($4)) AS core_words
+            FROM words WHERE kind = 'tile'
+            GROUP BY grp
+        ),
+        utterances AS (
+            SELECT grp, COUNT(*) AS utterances, AVG(length) AS mlu
+            FROM (
+                SELECT grp, id, COUNT(*) AS length FROM words
+                WHERE kind = 'utterance'
+                GROUP BY grp, id
+            ) AS lengths
+            GROUP BY grp
+        )
+        SELECT grp,
+            COALESCE(tile_words.words, 0) AS words,
+            COALESCE(tile_words.unique_words, 0) AS unique_words,
+            COALESCE(tile_words.core_words, 0) AS core_words,
+            COALESCE(utterances.utterances, 0) AS utterances,
+            utterances.mlu::FLOAT8 AS mlu
+        FROM tile_words FULL JOIN utterances USING (grp)
+        "#,
+        group = group,
+        pattern = WORD_PATTERN,
+    )
+}
+
+fn figures(row: &mobc_postgres::tokio_postgres::Row) -> Figures {
+    let words: i64 = row.get("words");
+    let core_words: i64 = row.get("core_words");
+    Figures {
+        words,
+        unique_words: row.get("unique_words"),
+        core_words,
+        core_ratio: ratio(core_words, words),
+        utterances: row.get("utterances"),
+        mean_length_of_utterance: row.get("mlu"),
+    }
+}
+
+fn empty_figures() -> Figures {
+    Figures {
+        words: 0,
+        unique_words: 0,
+        core_words: 0,
+        core_ratio: None,
+        utterances: 0,
+        mean_length_of_utterance: None,
+    }
+}
+
+/// Reports on the usage logged for the user `uid` from `from` to `to`, both included.
+pub async fn report(
+    conn: &db::Conn,
+    uid: i32,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Report, Error> {
+    let core = core_words();
+    let totals = conn
+        .query_opt(figures_sql("0").as_str(), &[&uid, &from, &to, &core])
+        .await
+        .map_err(Error::DBError)?
+        .map(|row| figures(&row))
+        .unwrap_or_else(empty_figures);
+    let mut by_day = conn
+        .query(
+            figures_sql("(occurred_at AT TIME ZONE 'UTC')::DATE").as_str(),
+            &[&uid, &from, &to, &core],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| (row.get::<_, NaiveDate>("grp"), figures(row)))
+        .collect::<std::collections::BTreeMap<NaiveDate, Figures>>();
+    let days = (0..=(to - from).num_days())
+        .map(|offset| {
+            let date = from + Duration::days(offset);
+            Day {
+                date,
+                figures: by_day.remove(&date).unwrap_or_else(empty_figures),
+            }
+        })
+        .collect::<Vec<Day>>();
+    let most_used = conn
+        .query(
+            r#"
+            SELECT phrase, COUNT(*) AS count FROM usage_events
+            WHERE user_id = $1 AND kind = 'tile'
+                AND occurred_at >= $2::DATE::TIMESTAMP AT TIME ZONE 'UTC'
+                AND occurred_at < ($3::DATE + 1)::TIMESTAMP AT TIME ZONE 'UTC'
+            GROUP BY phrase
+            ORDER BY count DESC, phrase
+            LIMIT $4
+            "#,
+            &[&uid, &from, &to, &MOST_USED],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| TileCount {
+            phrase: row.get("phrase"),
+            count: row.get("count"),
+        })
+        .collect();
+    Ok(Report {
+        from,
+        to,
+        words_per_day: totals.words as f64 / days.len() as f64,
+        totals,
+        most_used,
+        days,
+    })
+}
+
+fn optional(value: Option<f64>) -> String {
+    value.map(|v| format!("{:.3}", v)).unwrap_or_default()
+}
+
+/// Writes the days of `report` as CSV, one row per day.
+pub fn to_csv(report: &Report) -> String {
+    let mut csv = String::from(
+        "date,words,unique_words,core_words,core_ratio,utterances,mean_length_of_utterance\r\n",
+    );
+    for day in report.days.iter() {
+        let f = &day.figures;
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{}\r\n",
+            day.date,
+            f.words,
+            f.unique_words,
+            f.core_words,
+            optional(f.core_ratio),
+            f.utterances,
+            optional(f.mean_length_of_utterance),
+        ));
+    }
+    csv
+}
+
+pub async fn read_report(
+    username: String,
+    _actor: String,
+    query: ReportQuery,
+    pool: db::Pool,
+) -> Result<warp::reply::Response, Rejection> {
+    let to = query.to.unwrap_or_else(|| Utc::now().naive_utc().date());
+    let from = query
+        .from
+        .unwrap_or_else(|| to - Duration::days(DEFAULT_DAYS - 1));
+    if from > to || (to - from).num_days() >= MAX_DAYS {
+        return Err(Error::MalformedRequest.into());
+    }
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let report = report(&conn, uid, from, to).await?;
+    Ok(match query.format.unwrap_or_default() {
+        ReportFormat::Json => warp::reply::json(&report).into_response(),
+        ReportFormat::Csv => {
+            let mut res = Response::new(to_csv(&report).into());
+            let headers = res.headers_mut();
+            headers.insert(
+                "Content-Type",
+                HeaderValue::from_static("text/csv; charset=utf-8"),
+            );
+            let disposition = format!(
+                "attachment; filename=\"report-{}-{}-{}.csv\"",
+                username, from, to
+            );
+            if let Ok(value) = HeaderValue::from_str(&disposition) {
+                headers.insert("Content-Disposition", value);
+            }
+            res
+        }
+    })
+}
@@ -0,0 +1,326 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Share links grant anyone holding them read-only access to one of a user's boards, without an
+//! account. A share expires, can be revoked by its owner at any time, and may require a PIN.
+//! Wrong PINs lock a share for a while, so they can't be guessed one after another.
+
+use chrono::{DateTime, Duration, Utc};
+use crypto::util::fixed_time_eq;
+use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::row::Row;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, with_status, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    auth, db, guard,
+    tile::{self, TileQuery},
+    user, Error,
+};
+
+const TOKEN_SIZE: usize = 32;
+const DEFAULT_EXPIRATION: i64 = 604800;
+const MAX_EXPIRATION: i64 = 7776000;
+/// How many wrong PINs lock a share.
+const MAX_PIN_ATTEMPTS: i32 = 5;
+/// How long a share stays locked, in seconds. Each wrong PIN after that locks it again.
+const PIN_LOCKOUT: i64 = 900;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let create_share = warp::post()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("shares"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(create_share);
+
+    let list_shares = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("shares"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_shares);
+
+    let revoke_share = warp::delete()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("shares"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(revoke_share);
+
+    let shared_tiles = warp::get()
+        .and(warp::path("share"))
+        .and(warp::path::param())
+        .and(warp::path("tiles"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(warp::query())
+        .and(guard::with_db(db_pool))
+        .and_then(shared_tiles);
+
+    create_share
+        .or(list_shares)
+        .or(revoke_share)
+        .or(shared_tiles)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewShare {
+    /// The board to share, or every tile when absent.
+    pub category: Option<String>,
+    /// Seconds until the share expires.
+    pub expires_in: Option<i64>,
+    pub pin: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub token: String,
+    pub category: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub has_pin: bool,
+    pub url: String,
+}
+
+impl<'a> From<&'a Row> for Share {
+    fn from(item: &'a Row) -> Self {
+        let token: String = item.get("token");
+        Share {
+            url: format!("/api/share/{}/tiles", token),
+            token,
+            category: item.get("category"),
+            expires_at: item.get("expires_at"),
+            has_pin: item.get::<_, Option<String>>("pin_hash").is_some(),
+        }
+    }
+}
+
+/// The access a valid share token grants.
+pub struct ShareGrant {
+    pub token: String,
+    /// Unlocks the media of a share with a PIN, see [`ShareQuery::key`].
+    pub key: Option<String>,
+    pub user_id: i32,
+    pub category: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShareQuery {
+    pub share: Option<String>,
+    pub pin: Option<String>,
+    /// Unlocks a share with a PIN in the links to its images and recordings, so the PIN itself
+    /// never ends up in logs or referrers.
+    pub key: Option<String>,
+}
+
+/// The key to the media of a share with a PIN. Knowing it reveals nothing about the PIN.
+fn media_key(pin_hash: &str, salt: &str) -> String {
+    auth::secure_hash(format!("media\n{}\n{}", salt, pin_hash))
+}
+
+/// Checks that `token` names a live share and that `pin`, or the media `key` derived from it,
+/// unlocks it. Every PIN tried counts towards locking the share until the right one is given.
+pub async fn verify(
+    conn: &db::Conn,
+    token: String,
+    pin: Option<String>,
+    key: Option<String>,
+) -> Result<ShareGrant, Error> {
+    let row = conn
+        .query_opt(
+            r#"
+            SELECT user_id, category, pin_hash, salt
+            FROM shares
+            WHERE token = $1 AND expires_at > NOW()
+            "#,
+            &[&token],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    let pin_hash: Option<String> = row.get("pin_hash");
+    let key = match pin_hash {
+        Some(pin_hash) => {
+            let salt: String = row.get("salt");
+            let expected_key = media_key(&pin_hash, &salt);
+            let key_matches = key
+                .map(|key| fixed_time_eq(key.as_bytes(), expected_key.as_bytes()))
+                .unwrap_or(false);
+            if !key_matches {
+                let mut given = pin.ok_or(Error::Unauthorized)?;
+                // The attempt is counted before the PIN is checked, so guesses made at once are
+                // all counted too.
+                conn.query_opt(
+                    r#"
+                    UPDATE shares
+                    SET failed_attempts = failed_attempts + 1,
+                        locked_until = CASE
+                            WHEN failed_attempts + 1 >= $2 THEN NOW() + make_interval(secs => $3)
+                            ELSE locked_until
+                        END
+                    WHERE token = $1 AND (locked_until IS NULL OR locked_until <= NOW())
+                    RETURNING failed_attempts
+                    "#,
+                    &[&token, &MAX_PIN_ATTEMPTS, &(PIN_LOCKOUT as f64)],
+                )
+                .await
+                .map_err(Error::DBError)?
+                .ok_or(Error::Unauthorized)?;
+                given.push_str(&salt);
+                if !fixed_time_eq(auth::secure_hash(given).as_bytes(), pin_hash.as_bytes()) {
+                    return Err(Error::Unauthorized);
+                }
+                conn.execute(
+                    "UPDATE shares SET failed_attempts = 0, locked_until = NULL WHERE token = $1",
+                    &[&token],
+                )
+                .await
+                .map_err(Error::DBError)?;
+            }
+            Some(expected_key)
+        }
+        None => None,
+    };
+    Ok(ShareGrant {
+        token,
+        key,
+        user_id: row.get("user_id"),
+        category: row.get("category"),
+    })
+}
+
+async fn create_share(
+    username: String,
+    share: NewShare,
+    pool: db::Pool,
+) -> Result<WithStatus<Json>, Rejection> {
+    let expires_in = share.expires_in.unwrap_or(DEFAULT_EXPIRATION);
+    if !(1..=MAX_EXPIRATION).contains(&expires_in) {
+        return Err(Rejection::from(Error::MalformedRequest));
+    }
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let valid_pin =
+        |pin: &String| (4..=8).contains(&pin.len()) && pin.chars().all(|c| c.is_ascii_digit());
+    if !share.pin.iter().all(valid_pin) {
+        return Err(Rejection::from(Error::MalformedRequest));
+    }
+    let (pin_hash, salt) = match share.pin {
+        Some(mut pin) => {
+            let salt = auth::random_string(auth::SALT_SIZE);
+            pin.push_str(salt.as_str());
+            (Some(auth::secure_hash(pin)), Some(salt))
+        }
+        None => (None, None),
+    };
+    let row = conn
+        .query_one(
+            r#"
+            INSERT INTO shares (user_id, token, category, pin_hash, salt, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING token, category, pin_hash, expires_at
+            "#,
+            &[
+                &uid,
+                &auth::random_string(TOKEN_SIZE),
+                &share.category,
+                &pin_hash,
+                &salt,
+                &(Utc::now() + Duration::seconds(expires_in)),
+            ],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    Ok(with_status(json(&Share::from(&row)), StatusCode::CREATED))
+}
+
+async fn list_shares(username: String, pool: db::Pool) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    Ok(json(
+        &conn
+            .query(
+                r#"
+                SELECT token, category, pin_hash, expires_at
+                FROM shares
+                WHERE user_id = $1 AND expires_at > NOW()
+                ORDER BY expires_at ASC
+                "#,
+                &[&uid],
+            )
+            .await
+            .map_err(Error::DBError)?
+            .iter()
+            .map(Share::from)
+            .collect::<Vec<Share>>(),
+    ))
+}
+
+async fn revoke_share(
+    username: String,
+    token: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    conn.execute(
+        "DELETE FROM shares WHERE user_id = $1 AND token = $2",
+        &[&uid, &token],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
+
+async fn shared_tiles(
+    token: String,
+    mut query: TileQuery,
+    share_query: ShareQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let grant = verify(&conn, token, share_query.pin, share_query.key).await?;
+    if grant.category.is_some() {
+        query.category = grant.category.clone();
+    }
+    let mut page = tile::query_tiles(&conn, Some(grant.user_id), &query).await?;
+    // Viewers have no token of their own, so images and recordings carry the share with them.
+    let access = match &grant.key {
+        Some(key) => format!("share={}&key={}", grant.token, key),
+        None => format!("share={}", grant.token),
+    };
+    for tile in page.tiles.iter_mut() {
+        tile.image = format!("{}?{}", tile.image, access);
+        for path in tile.thumbnails.values_mut() {
+            *path = format!("{}&{}", path, access);
+        }
+        if let Some(audio) = tile.audio.as_mut() {
+            *audio = format!("{}?{}", audio, access);
+        }
+    }
+    Ok(json(&page))
+}
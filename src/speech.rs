@@ -0,0 +1,488 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Speech synthesized on the server, so every device speaks with the same voice, even those
+//! without voices of their own. An [`Engine`] turns text into a recording, and recordings are
+//! cached on disk by their text, voice and rate, so each utterance is only synthesized once. The
+//! cache is kept to a size, forgetting the recordings used least recently first.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command};
+use warp::{http::HeaderValue, reply::Response, Filter, Rejection, Reply};
+
+use crate::{
+    db, guard, lexicon,
+    media::{self, Conditions},
+    preferences,
+    storage::{self, SharedStorage, StorageConfig},
+    user, util, Error,
+};
+
+/// The longest text spoken at once, in characters.
+pub const MAX_TEXT_CHARS: usize = 1000;
+/// The voice used when none is asked for.
+pub const DEFAULT_VOICE: &str = "en";
+/// The speaking rate used when none is asked for, in words per minute.
+pub const DEFAULT_RATE: u32 = 175;
+/// The slowest and fastest speaking rates accepted, in words per minute.
+pub const RATES: (u32, u32) = (80, 450);
+/// The pitch used when none is asked for.
+pub const DEFAULT_PITCH: u32 = 50;
+/// The highest pitch accepted; the lowest is 0.
+pub const MAX_PITCH: u32 = 99;
+/// The most the cache of recordings holds unless configured otherwise, in bytes.
+pub const DEFAULT_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// How to speak some text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Voice {
+    /// The engine specific name of the voice, usually a language code.
+    pub name: String,
+    /// Words per minute.
+    pub rate: u32,
+    /// From 0 to 99, where 50 is the voice's normal pitch.
+    pub pitch: u32,
+}
+
+impl Default for Voice {
+    fn default() -> Self {
+        Voice {
+            name: DEFAULT_VOICE.to_string(),
+            rate: DEFAULT_RATE,
+            pitch: DEFAULT_PITCH,
+        }
+    }
+}
+
+impl Voice {
+    pub fn validate(&self) -> Result<(), Error> {
+        // Voice names reach the engine's command line, so they are kept to a safe alphabet.
+        let name_ok = !self.name.is_empty()
+            && self.name.len() <= 40
+            && !self.name.starts_with('-')
+            && self
+                .name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'+');
+        if !name_ok || self.rate < RATES.0 || self.rate > RATES.1 || self.pitch > MAX_PITCH {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait Engine: Send + Sync {
+    /// Names the engine in cache keys, so switching engines doesn't serve stale recordings.
+    fn name(&self) -> &str;
+    /// The type of the recordings the engine makes.
+    fn content_type(&self) -> &str;
+    /// Speaks `text` with `voice`, returning the recording.
+    async fn synthesize(&self, text: &str, voice: &Voice) -> Result<Vec<u8>, Error>;
+}
+
+pub type SharedEngine = Arc<dyn Engine>;
+
+/// How speech is synthesized. Without an engine, speech is left to the browser.
+#[derive(Default, Clone)]
+pub struct SpeechConfig {
+    pub engine: Option<SharedEngine>,
+    /// Where recordings are cached, a directory below the system's temporary one by default.
+    pub cache: Option<PathBuf>,
+    /// The most the cache holds, in bytes, [`DEFAULT_CACHE_BYTES`] by default.
+    pub cache_bytes: Option<u64>,
+}
+
+/// eSpeak NG, run once per utterance from the locally installed `espeak-ng` program.
+pub struct Espeak {
+    program: PathBuf,
+}
+
+impl Espeak {
+    /// How long an utterance may take to synthesize before it is given up on.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Espeak {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for Espeak {
+    fn default() -> Self {
+        Espeak::new("espeak-ng")
+    }
+}
+
+#[async_trait]
+impl Engine for Espeak {
+    fn name(&self) -> &str {
+        "espeak-ng"
+    }
+
+    fn content_type(&self) -> &str {
+        "audio/wav"
+    }
+
+    async fn synthesize(&self, text: &str, voice: &Voice) -> Result<Vec<u8>, Error> {
+        // The text goes through stdin, so it is never mistaken for an option.
+        let mut child = Command::new(&self.program)
+            .arg("--stdout")
+            .arg("--stdin")
+            .arg("-v")
+            .arg(&voice.name)
+            .arg("-s")
+            .arg(voice.rate.to_string())
+            .arg("-p")
+            .arg(voice.pitch.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::SpeechUnavailable(format!("could not run eSpeak NG: {}", e)))?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| Error::SpeechUnavailable("could not write to eSpeak NG".to_string()))?;
+        stdin.write_all(text.as_bytes()).await?;
+        drop(stdin);
+        let output = tokio::time::timeout(Self::TIMEOUT, child.wait_with_output())
+            .await
+            .map_err(|_| Error::SpeechUnavailable("eSpeak NG timed out".to_string()))??;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(Error::SpeechUnavailable(format!(
+                "eSpeak NG failed with {}",
+                output.status
+            )));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// The size of each cached recording and when it was last used, to find the ones to forget.
+#[derive(Default)]
+struct CacheIndex {
+    /// The size and last use of each recording, by key.
+    entries: HashMap<String, (u64, u64)>,
+    /// The key of each recording, by last use.
+    by_use: BTreeMap<u64, String>,
+    /// Counts uses, so later uses are always larger.
+    clock: u64,
+    bytes: u64,
+}
+
+impl CacheIndex {
+    /// Reads the recordings already cached in `dir`, treating the latest modified as the latest
+    /// used.
+    fn scan(dir: &Path) -> Result<Self, Error> {
+        let mut found = Vec::new();
+        for shard in fs::read_dir(dir)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(shard.path())? {
+                let file = file?;
+                let name = file.file_name().to_string_lossy().into_owned();
+                // Half written files start with a dot.
+                if name.starts_with('.') {
+                    continue;
+                }
+                let metadata = file.metadata()?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, name, metadata.len()));
+            }
+        }
+        found.sort();
+        let mut index = CacheIndex::default();
+        for (_, key, bytes) in found {
+            index.insert(key, bytes);
+        }
+        Ok(index)
+    }
+
+    /// Records a use of the recording `key`.
+    fn touch(&mut self, key: &str, bytes: u64) {
+        self.insert(key.to_string(), bytes);
+    }
+
+    fn insert(&mut self, key: String, bytes: u64) {
+        self.clock += 1;
+        if let Some((old_bytes, old_use)) = self.entries.remove(&key) {
+            self.by_use.remove(&old_use);
+            self.bytes -= old_bytes;
+        }
+        self.by_use.insert(self.clock, key.clone());
+        self.entries.insert(key, (bytes, self.clock));
+        self.bytes += bytes;
+    }
+
+    /// Forgets the least recently used recordings until at most `max_bytes` remain, returning
+    /// their keys.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.bytes > max_bytes {
+            let (_, key) = match self.by_use.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some((bytes, _)) = self.entries.remove(&key) {
+                self.bytes -= bytes;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// An engine and the cache in front of it.
+#[derive(Clone)]
+pub struct Speaker {
+    engine: Option<SharedEngine>,
+    cache: SharedStorage,
+    index: Arc<Mutex<CacheIndex>>,
+    max_bytes: u64,
+}
+
+impl Speaker {
+    pub async fn open(config: &SpeechConfig, db_pool: db::Pool) -> Result<Self, Error> {
+        let dir = config
+            .cache
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("open-comm-speech"));
+        let cache = storage::open(&StorageConfig::Filesystem(dir.clone()), db_pool).await?;
+        let index = tokio::task::spawn_blocking(move || CacheIndex::scan(&dir))
+            .await
+            .map_err(|e| Error::IOError(e.into()))??;
+        let speaker = Speaker {
+            engine: config.engine.clone(),
+            cache,
+            index: Arc::new(Mutex::new(index)),
+            max_bytes: config.cache_bytes.unwrap_or(DEFAULT_CACHE_BYTES),
+        };
+        // The limit may have shrunk since the recordings were cached.
+        speaker.evict().await?;
+        Ok(speaker)
+    }
+
+    /// Deletes the least recently used recordings while the cache is over its limit.
+    async fn evict(&self) -> Result<(), Error> {
+        let evicted = self
+            .index
+            .lock()
+            .expect("speech cache index poisoned")
+            .evict(self.max_bytes);
+        for key in evicted {
+            self.cache.delete(&key).await?;
+        }
+        Ok(())
+    }
+
+    fn engine(&self) -> Result<&dyn Engine, Error> {
+        self.engine.as_deref().ok_or_else(|| {
+            Error::SpeechUnavailable("speech synthesis is not configured".to_string())
+        })
+    }
+
+    /// The key the recording of `text` spoken with `voice` is cached under.
+    pub fn key(&self, text: &str, voice: &Voice) -> Result<String, Error> {
+        let engine = self.engine()?;
+        Ok(util::hash(
+            format!(
+                "{}\n{}\n{}\n{}\n{}",
+                engine.name(),
+                voice.name,
+                voice.rate,
+                voice.pitch,
+                text
+            )
+            .as_bytes(),
+        ))
+    }
+
+    /// Speaks `text` with `voice`, from the cache when it was spoken before. Returns the
+    /// recording and its type.
+    pub async fn speak(&self, text: &str, voice: &Voice) -> Result<(Vec<u8>, String), Error> {
+        let engine = self.engine()?;
+        let key = self.key(text, voice)?;
+        let content_type = engine.content_type().to_string();
+        match self.cache.get(&key).await {
+            Ok(data) => {
+                self.index
+                    .lock()
+                    .expect("speech cache index poisoned")
+                    .touch(&key, data.len() as u64);
+                return Ok((data, content_type));
+            }
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        let data = engine.synthesize(text, voice).await?;
+        self.cache.put(&key, &data).await?;
+        self.index
+            .lock()
+            .expect("speech cache index poisoned")
+            .touch(&key, data.len() as u64);
+        self.evict().await?;
+        Ok((data, content_type))
+    }
+}
+
+pub fn api(
+    db_pool: db::Pool,
+    speaker: Speaker,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let speak_text = warp::get()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("speech"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(media::conditions())
+        .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_speaker(speaker.clone()))
+        .and_then(speak_text);
+
+    let speak_tile = warp::get()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("tiles"))
+        .and(warp::path::param())
+        .and(warp::path("speech"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(media::conditions())
+        .and(guard::with_db(db_pool))
+        .and(guard::with_speaker(speaker))
+        .and_then(speak_tile);
+
+    speak_text.or(speak_tile)
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SpeechQuery {
+    /// What to say. Tiles say their own speech text instead.
+    pub text: Option<String>,
+    pub voice: Option<String>,
+    pub rate: Option<u32>,
+    pub pitch: Option<u32>,
+}
+
+impl SpeechQuery {
+    /// The voice asked for, falling back to the user's preferred one.
+    fn voice(&self, defaults: Voice) -> Result<Voice, Error> {
+        let voice = Voice {
+            name: self.voice.clone().unwrap_or(defaults.name),
+            rate: self.rate.unwrap_or(defaults.rate),
+            pitch: self.pitch.unwrap_or(defaults.pitch),
+        };
+        voice.validate()?;
+        Ok(voice)
+    }
+}
+
+/// Answers with the recording of `text`, or with 304 when the client already has it. Recordings
+/// depend on the user's pronunciations and preferences as well as the request, so clients always
+/// check theirs is still current.
+async fn respond(
+    speaker: &Speaker,
+    text: &str,
+    voice: &Voice,
+    conditions: &Conditions,
+) -> Result<Response, Error> {
+    let etag = media::etag(&speaker.key(text, voice)?);
+    let mut res = match media::not_modified(conditions, &etag) {
+        Some(res) => res,
+        None => {
+            let (data, content_type) = speaker.speak(text, voice).await?;
+            media::respond(data, &content_type, &etag, conditions)
+        }
+    };
+    res.headers_mut().insert(
+        "Cache-Control",
+        HeaderValue::from_static("private, no-cache"),
+    );
+    Ok(res)
+}
+
+fn check_text(text: &str) -> Result<&str, Error> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > MAX_TEXT_CHARS {
+        return Err(Error::MalformedRequest);
+    }
+    Ok(text)
+}
+
+pub async fn speak_text(
+    username: String,
+    query: SpeechQuery,
+    conditions: Conditions,
+    pool: db::Pool,
+    speaker: Speaker,
+) -> Result<Response, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let text = lexicon::lexicon(&conn, uid)
+        .await?
+        .apply(query.text.as_deref().unwrap_or(""));
+    let text = check_text(&text)?;
+    let voice = query.voice(preferences::load(&conn, uid).await?.voice())?;
+    Ok(respond(&speaker, text, &voice, &conditions).await?)
+}
+
+pub async fn speak_tile(
+    username: String,
+    phrase: String,
+    query: SpeechQuery,
+    conditions: Conditions,
+    pool: db::Pool,
+    speaker: Speaker,
+) -> Result<Response, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    // The user's own tile wins over a library tile of the same phrase.
+    let row = conn
+        .query_opt(
+            r#"
+            SELECT COALESCE(speech, phrase) AS text FROM tiles
+            WHERE (user_id = $1 OR user_id IS NULL) AND phrase = $2
+            ORDER BY user_id NULLS LAST
+            LIMIT 1
+            "#,
+            &[&uid, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    let text = lexicon::lexicon(&conn, uid).await?.apply(row.get("text"));
+    let text = check_text(&text)?;
+    let voice = query.voice(preferences::load(&conn, uid).await?.voice())?;
+    Ok(respond(&speaker, text, &voice, &conditions).await?)
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Where the contents of stored images live. Image metadata and reference counts always stay in
+//! the database; a [`Storage`] only keeps the bytes, keyed by the hash of their content.
+
+use std::{io::ErrorKind, path::PathBuf, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::fs;
+
+use crate::{auth, db, Error};
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `data` under `hash`. Storing the same content twice is harmless.
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<(), Error>;
+    /// Reads the content stored under `hash`.
+    async fn get(&self, hash: &str) -> Result<Vec<u8>, Error>;
+    /// Removes the content stored under `hash`, if there is any.
+    async fn delete(&self, hash: &str) -> Result<(), Error>;
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// Which backend keeps the contents of images.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StorageConfig {
+    /// The `blobs` table of the application database.
+    #[default]
+    Postgres,
+    /// Files below the given directory.
+    Filesystem(PathBuf),
+}
+
+impl FromStr for StorageConfig {
+    type Err = String;
+
+    /// Parses either `postgres` or `fs:<directory>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("fs:") {
+            Some(dir) if !dir.is_empty() => Ok(StorageConfig::Filesystem(PathBuf::from(dir))),
+            _ if s == "postgres" => Ok(StorageConfig::Postgres),
+            _ => Err(format!("unknown image storage \"{}\"", s)),
+        }
+    }
+}
+
+/// Opens the backend described by `config`.
+pub async fn open(config: &StorageConfig, db_pool: db::Pool) -> Result<SharedStorage, Error> {
+    Ok(match config {
+        StorageConfig::Postgres => Arc::new(PostgresStorage { db_pool }),
+        StorageConfig::Filesystem(root) => {
+            fs::create_dir_all(root).await?;
+            Arc::new(FilesystemStorage { root: root.clone() })
+        }
+    })
+}
+
+pub struct PostgresStorage {
+    db_pool: db::Pool,
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<(), Error> {
+        let conn = db::get_db_conn(&self.db_pool).await?;
+        conn.execute(
+            "INSERT INTO blobs (hash, data) VALUES ($1, $2) ON CONFLICT (hash) DO NOTHING",
+            &[&hash, &data],
+        )
+        .await
+        .map_err(Error::DBError)?;
+        Ok(())
+    }
+
+    async fn get(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let conn = db::get_db_conn(&self.db_pool).await?;
+        let row = conn
+            .query_opt("SELECT data FROM blobs WHERE hash = $1", &[&hash])
+            .await
+            .map_err(Error::DBError)?
+            .ok_or(Error::NotFound)?;
+        Ok(row.get("data"))
+    }
+
+    async fn delete(&self, hash: &str) -> Result<(), Error> {
+        let conn = db::get_db_conn(&self.db_pool).await?;
+        conn.execute("DELETE FROM blobs WHERE hash = $1", &[&hash])
+            .await
+            .map_err(Error::DBError)?;
+        Ok(())
+    }
+}
+
+/// One file per content, spread over subdirectories named after the first two characters of
+/// the hash.
+pub struct FilesystemStorage {
+    root: PathBuf,
+}
+
+impl FilesystemStorage {
+    fn path(&self, hash: &str) -> Result<PathBuf, Error> {
+        // Hashes arrive in request paths, so refuse anything that could leave the root.
+        if hash.len() < 3 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::NotFound);
+        }
+        Ok(self.root.join(&hash[..2]).join(hash))
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<(), Error> {
+        let path = self.path(hash)?;
+        if fs::metadata(&path).await.is_ok() {
+            return Ok(());
+        }
+        let dir = self.root.join(&hash[..2]);
+        fs::create_dir_all(&dir).await?;
+        // Write beside the final name and rename into place, so readers never see half a file.
+        let partial = dir.join(format!(".{}.{}", hash, auth::random_string(8)));
+        fs::write(&partial, data).await?;
+        if let Err(e) = fs::rename(&partial, &path).await {
+            let _ = fs::remove_file(&partial).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn get(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        match fs::read(self.path(hash)?).await {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::NotFound),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, hash: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(hash)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether `a` and `b` keep their contents in the same place: both in the database, or both in
+/// the same directory however its path is written.
+pub async fn same_backend(a: &StorageConfig, b: &StorageConfig) -> Result<bool, Error> {
+    match (a, b) {
+        (StorageConfig::Postgres, StorageConfig::Postgres) => Ok(true),
+        (StorageConfig::Filesystem(a), StorageConfig::Filesystem(b)) => {
+            fs::create_dir_all(a).await?;
+            fs::create_dir_all(b).await?;
+            Ok(fs::canonicalize(a).await? == fs::canonicalize(b).await?)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Moves the contents of every stored image, image variant and recording from `from` to `to`,
+/// returning how many were moved.
+/// Run it while the server is stopped, then start the server on the new backend.
+pub async fn migrate(
+    db_pool: &db::Pool,
+    from: &dyn Storage,
+    to: &dyn Storage,
+) -> Result<usize, Error> {
+    let conn = db::get_db_conn(db_pool).await?;
+    let hashes = conn
+        .query(
+            "SELECT hash FROM images UNION ALL SELECT key FROM image_variants UNION ALL SELECT hash FROM recordings",
+            &[],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .iter()
+        .map(|row| row.get("hash"))
+        .collect::<Vec<String>>();
+    let mut moved = Vec::new();
+    for hash in hashes {
+        match from.get(&hash).await {
+            Ok(data) => to.put(&hash, &data).await?,
+            // Already moved by an earlier, interrupted run.
+            Err(Error::NotFound) => continue,
+            Err(e) => return Err(e),
+        }
+        moved.push(hash);
+    }
+    // Only clear the source once everything is copied, so a failed run loses nothing.
+    for hash in &moved {
+        from.delete(hash).await?;
+    }
+    Ok(moved.len())
+}
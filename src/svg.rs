@@ -0,0 +1,265 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! SVG symbols are welcome, but SVG is a document format that can run scripts and load other
+//! documents. Uploads are rewritten to drop everything that could, and can be rasterized for
+//! clients that don't render SVG themselves.
+
+use std::collections::HashMap;
+
+use ::image::{DynamicImage, ImageFormat};
+use resvg::{tiny_skia, usvg};
+use xmlparser::{ElementEnd, EntityDefinition, Token, Tokenizer};
+
+use crate::Error;
+
+pub const CONTENT_TYPE: &str = "image/svg+xml";
+
+/// Elements dropped together with everything inside them.
+const FORBIDDEN_ELEMENTS: [&str; 10] = [
+    "script",
+    "foreignobject",
+    "iframe",
+    "embed",
+    "object",
+    "handler",
+    "listener",
+    "audio",
+    "video",
+    "canvas",
+];
+
+/// Images that may be embedded as data URLs.
+const SAFE_DATA_URLS: [&str; 4] = [
+    "data:image/png",
+    "data:image/jpeg",
+    "data:image/gif",
+    "data:image/webp",
+];
+
+/// An element whose start tag has been read but not written yet.
+struct Pending {
+    name: String,
+    attributes: Vec<String>,
+    dropped: bool,
+}
+
+fn malformed() -> Error {
+    Error::InvalidUpload("SVG image is not well-formed XML".to_string())
+}
+
+fn qualified_name(prefix: &str, local: &str) -> String {
+    if prefix.is_empty() {
+        local.to_string()
+    } else {
+        format!("{}:{}", prefix, local)
+    }
+}
+
+/// Rewrites an SVG document without scripts, event handlers, foreign objects, external
+/// references, comments, processing instructions or document type declarations. Simple internal
+/// entities are expanded, since some editors use them for namespaces.
+pub fn sanitize(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let text = std::str::from_utf8(data).map_err(|_| malformed())?;
+    let mut out = String::with_capacity(text.len());
+    let mut entities: HashMap<&str, &str> = HashMap::new();
+    let mut open: Vec<String> = Vec::new();
+    // How many elements were open when the outermost dropped element started.
+    let mut dropped_at: Option<usize> = None;
+    let mut pending: Option<Pending> = None;
+
+    for token in Tokenizer::from(text) {
+        match token.map_err(|_| malformed())? {
+            Token::Declaration { span, .. } => out.push_str(span.as_str()),
+            // Values with markup or references of their own are never expanded, which keeps out
+            // both injected elements and exponential expansion.
+            Token::EntityDeclaration {
+                name,
+                definition: EntityDefinition::EntityValue(value),
+                ..
+            } if !value.as_str().contains(&['&', '<', '%'][..]) => {
+                entities.insert(name.as_str(), value.as_str());
+            }
+            Token::ElementStart { prefix, local, .. } => {
+                let lowercase = local.as_str().to_ascii_lowercase();
+                pending = Some(Pending {
+                    name: qualified_name(prefix.as_str(), local.as_str()),
+                    attributes: Vec::new(),
+                    dropped: FORBIDDEN_ELEMENTS.contains(&lowercase.as_str()),
+                });
+            }
+            Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } => {
+                let element = pending.as_mut().ok_or_else(malformed)?;
+                let name = local.as_str().to_ascii_lowercase();
+                let value = expand(value.as_str(), &entities);
+                if name.starts_with("on")
+                    || (name == "href" && !safe_reference(&value))
+                    || ((name == "style" || value.contains("url(")) && !safe_css(&value))
+                {
+                    continue;
+                }
+                // Animations could turn a harmless link into a script.
+                if name == "attributename" && value.to_ascii_lowercase().ends_with("href") {
+                    element.dropped = true;
+                }
+                element.attributes.push(format!(
+                    "{}=\"{}\"",
+                    qualified_name(prefix.as_str(), local.as_str()),
+                    value.replace('"', "&quot;")
+                ));
+            }
+            Token::ElementEnd { end, .. } => match end {
+                ElementEnd::Open => {
+                    let element = pending.take().ok_or_else(malformed)?;
+                    if dropped_at.is_none() {
+                        if element.dropped {
+                            dropped_at = Some(open.len());
+                        } else {
+                            write_start_tag(&mut out, &element, false);
+                        }
+                    }
+                    open.push(element.name);
+                }
+                ElementEnd::Empty => {
+                    let element = pending.take().ok_or_else(malformed)?;
+                    if dropped_at.is_none() && !element.dropped {
+                        write_start_tag(&mut out, &element, true);
+                    }
+                }
+                ElementEnd::Close(prefix, local) => {
+                    let name = open.pop().ok_or_else(malformed)?;
+                    if name != qualified_name(prefix.as_str(), local.as_str()) {
+                        return Err(malformed());
+                    }
+                    if dropped_at == Some(open.len()) {
+                        dropped_at = None;
+                    } else if dropped_at.is_none() {
+                        out.push_str(format!("</{}>", name).as_str());
+                    }
+                }
+            },
+            Token::Text { text } if dropped_at.is_none() => {
+                let text = expand(text.as_str(), &entities);
+                if !in_style(&open) || safe_css(&text) {
+                    out.push_str(&text);
+                }
+            }
+            Token::Cdata { text, span }
+                if dropped_at.is_none() && (!in_style(&open) || safe_css(text.as_str())) =>
+            {
+                out.push_str(span.as_str());
+            }
+            _ => (),
+        }
+    }
+    if !open.is_empty() || pending.is_some() {
+        return Err(malformed());
+    }
+    Ok(out.into_bytes())
+}
+
+fn write_start_tag(out: &mut String, element: &Pending, empty: bool) {
+    out.push('<');
+    out.push_str(&element.name);
+    for attribute in element.attributes.iter() {
+        out.push(' ');
+        out.push_str(attribute);
+    }
+    out.push_str(if empty { "/>" } else { ">" });
+}
+
+fn in_style(open: &[String]) -> bool {
+    open.last()
+        .map(|name| name == "style" || name.ends_with(":style"))
+        .unwrap_or(false)
+}
+
+/// Replaces references to the declared `entities` in raw XML text.
+fn expand(raw: &str, entities: &HashMap<&str, &str>) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        match entities.get(&rest[1..end]) {
+            Some(value) => out.push_str(value),
+            None => out.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether a link stays inside the document or embeds a raster image.
+fn safe_reference(target: &str) -> bool {
+    let target = target
+        .trim_matches(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        .to_ascii_lowercase();
+    target.starts_with('#') || SAFE_DATA_URLS.iter().any(|url| target.starts_with(url))
+}
+
+/// Whether a style sheet or style attribute only refers to things inside the document.
+fn safe_css(css: &str) -> bool {
+    let css = css.to_ascii_lowercase();
+    if [
+        "@import",
+        "expression(",
+        "javascript:",
+        "behavior:",
+        "-moz-binding",
+    ]
+    .iter()
+    .any(|bad| css.contains(bad))
+    {
+        return false;
+    }
+    css.split("url(")
+        .skip(1)
+        .all(|rest| safe_reference(rest.split(')').next().unwrap_or("")))
+}
+
+/// Draws an SVG image so its longest side is `size` pixels.
+pub fn rasterize(data: &[u8], size: u32) -> Option<DynamicImage> {
+    let mut options = usvg::Options::default();
+    // Sanitized images only embed data URLs, but never read files even so.
+    options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    let tree = usvg::Tree::from_data(data, &options).ok()?;
+    let (width, height) = (tree.size().width(), tree.size().height());
+    let scale = size as f32 / width.max(height);
+    let mut pixmap = tiny_skia::Pixmap::new(
+        ((width * scale).round() as u32).max(1),
+        ((height * scale).round() as u32).max(1),
+    )?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    let png = pixmap.encode_png().ok()?;
+    ::image::load_from_memory_with_format(&png, ImageFormat::Png).ok()
+}
@@ -0,0 +1,206 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Phrase templates: tiles that say a sentence with gaps, like "I want {food} please", where each
+//! gap is filled by choosing a tile from a category. A template names its slots in what it says
+//! and restricts each to a category the user has tiles in.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::{types::Json as SqlJson, Transaction};
+use serde::{Deserialize, Serialize};
+use warp::{
+    reply::{json, Json},
+    Filter, Rejection, Reply,
+};
+
+use crate::{db, guard, lexicon, tile::Tile, user, Error};
+
+/// The longest slot name, in characters.
+const MAX_SLOT_CHARS: usize = 32;
+/// The most slots a template may have.
+const MAX_SLOTS: usize = 8;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(guard::user_resource(jwt_key))
+        .and(warp::path("tiles"))
+        .and(warp::path::param())
+        .and(warp::path("render"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool))
+        .and_then(render)
+}
+
+/// Slot names are written between braces, and hold only letters, digits and underscores.
+fn is_slot_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_SLOT_CHARS
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Where each `{slot}` in `text` starts and ends, with its name. Braces around anything else are
+/// part of the text.
+fn placeholders(text: &str) -> Vec<(usize, usize, &str)> {
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(open) = text[from..].find('{').map(|i| from + i) {
+        match text[open..].find('}').map(|i| open + i) {
+            Some(close) if is_slot_name(&text[open + 1..close]) => {
+                found.push((open, close + 1, &text[open + 1..close]));
+                from = close + 1;
+            }
+            _ => from = open + 1,
+        }
+    }
+    found
+}
+
+/// Checks the slots of `tile`, if it is a template, against what it says, and that each slot's
+/// category is one `owner` has tiles in, or the library when there is no owner.
+pub(crate) async fn check(
+    tx: &Transaction<'_>,
+    owner: Option<i32>,
+    tile: &Tile,
+) -> Result<(), Error> {
+    if tile.slots.is_empty() {
+        return Ok(());
+    }
+    let text = tile.speech.as_deref().unwrap_or(&tile.phrase);
+    let named = placeholders(text)
+        .into_iter()
+        .map(|(_, _, name)| name)
+        .collect::<BTreeSet<&str>>();
+    if tile.slots.len() > MAX_SLOTS
+        || !named
+            .iter()
+            .copied()
+            .eq(tile.slots.keys().map(String::as_str))
+        || tile
+            .slots
+            .values()
+            .any(|category| category.trim().is_empty())
+    {
+        return Err(Error::MalformedRequest);
+    }
+    for category in tile.slots.values() {
+        tx.query_opt(
+            r#"
+            SELECT 1 FROM tiles
+            WHERE (user_id = $1 OR user_id IS NULL)
+                AND NOT EXISTS (
+                    SELECT 1 FROM hidden_tiles
+                    WHERE hidden_tiles.user_id = $1 AND hidden_tiles.tile_id = tiles.id
+                )
+                AND slots = '{}'
+                AND $2 = ANY(categories)
+            LIMIT 1
+            "#,
+            &[&owner, &category],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::MalformedRequest)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Selections {
+    /// The phrase of the tile chosen for each slot.
+    pub slots: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Rendered {
+    /// The template with every slot filled.
+    pub text: String,
+    /// The text as it is spoken, with the user's pronunciations applied.
+    pub spoken: String,
+}
+
+/// Fills each slot of the template `phrase` with what the tile chosen for it says. Every slot
+/// must be filled, and only from its category.
+pub async fn render(
+    username: String,
+    phrase: String,
+    selections: Selections,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let row = conn
+        .query_opt(
+            r#"
+            SELECT COALESCE(speech, phrase) AS text, slots FROM tiles
+            WHERE (user_id = $1 OR user_id IS NULL) AND phrase = $2
+            ORDER BY user_id NULLS LAST
+            LIMIT 1
+            "#,
+            &[&uid, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    let template: String = row.get("text");
+    let slots = row.get::<_, SqlJson<BTreeMap<String, String>>>("slots").0;
+    if slots.is_empty() || !selections.slots.keys().all(|name| slots.contains_key(name)) {
+        return Err(Error::MalformedRequest.into());
+    }
+
+    let mut fills = BTreeMap::new();
+    for (name, category) in &slots {
+        let chosen = selections.slots.get(name).ok_or(Error::MalformedRequest)?;
+        let fill: String = conn
+            .query_opt(
+                r#"
+                SELECT COALESCE(speech, phrase) AS text FROM tiles
+                WHERE (user_id = $1 OR user_id IS NULL)
+                    AND phrase = $2
+                    AND $3 = ANY(categories)
+                    AND slots = '{}'
+                ORDER BY user_id NULLS LAST
+                LIMIT 1
+                "#,
+                &[&uid, chosen, category],
+            )
+            .await
+            .map_err(Error::DBError)?
+            .ok_or(Error::MalformedRequest)?
+            .get("text");
+        fills.insert(name.as_str(), fill);
+    }
+
+    let mut text = String::with_capacity(template.len());
+    let mut copied = 0;
+    for (start, end, name) in placeholders(&template) {
+        if let Some(fill) = fills.get(name) {
+            text.push_str(&template[copied..start]);
+            text.push_str(fill);
+            copied = end;
+        }
+    }
+    text.push_str(&template[copied..]);
+    let spoken = lexicon::lexicon(&conn, uid).await?.apply(&text);
+    Ok(json(&Rendered { text, spoken }))
+}
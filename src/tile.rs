@@ -16,30 +16,44 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
 use futures::stream::TryStreamExt;
 use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::{row::Row, types::Json as SqlJson};
 use serde::{Deserialize, Serialize};
 use warp::{
-    http::StatusCode,
-    multipart::FormData,
-    reply::{json, with_header, with_status, Json, WithHeader, WithStatus},
+    http::{HeaderValue, StatusCode},
+    multipart::{FormData, FormOptions},
+    reply::{json, with_status, Json, Response, WithStatus},
     Filter, Rejection, Reply,
 };
 
-use crate::{auth::BearerToken, db, guard, util, Error};
+use crate::{
+    audio::{self, Audio},
+    auth::BearerToken,
+    board, db, guard,
+    image::{self, Image, ImageQuery},
+    lexicon,
+    media::{self, Conditions},
+    morphology::{self, Inflection},
+    share::ShareGrant,
+    storage::{SharedStorage, Storage},
+    template, user, util, Error,
+};
 
 pub fn api(
     db_pool: db::Pool,
+    storage: SharedStorage,
     jwt_key: DecodingKey<'static>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let create_user_tile = warp::post()
         .and(guard::user_resource(jwt_key.clone()))
         .and(warp::path("tiles"))
         .and(warp::path::end())
-        .and(warp::multipart::form())
+        .and(tile_form())
         .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage.clone()))
         .and_then(create_user_tile);
 
     let list_tiles = warp::get()
@@ -54,16 +68,32 @@ pub fn api(
         .and(warp::path("image"))
         .and(guard::optional_authentic_token(jwt_key.clone()))
         .and(warp::path::param())
+        .and(warp::query())
+        .and(media::conditions())
+        .and(guard::optional_share(db_pool.clone()))
         .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage.clone()))
         .and_then(read_image);
 
+    let audio = warp::get()
+        .and(warp::path("audio"))
+        .and(guard::optional_authentic_token(jwt_key.clone()))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(media::conditions())
+        .and(guard::optional_share(db_pool.clone()))
+        .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage.clone()))
+        .and_then(read_audio);
+
     let update_user_tile = warp::patch()
         .and(guard::user_resource(jwt_key.clone()))
         .and(warp::path("tiles"))
         .and(warp::path::param())
         .and(warp::path::end())
-        .and(warp::multipart::form())
+        .and(tile_form())
         .and(guard::with_db(db_pool.clone()))
+        .and(guard::with_storage(storage.clone()))
         .and_then(update_user_tile);
 
     let delete_user_tile = warp::delete()
@@ -72,11 +102,13 @@ pub fn api(
         .and(warp::path::param())
         .and(warp::path::end())
         .and(guard::with_db(db_pool))
+        .and(guard::with_storage(storage))
         .and_then(delete_user_tile);
 
     create_user_tile
         .or(list_tiles)
         .or(image)
+        .or(audio)
         .or(update_user_tile)
         .or(delete_user_tile)
 }
@@ -86,21 +118,115 @@ fn image_path<T: Display>(filename: T) -> String {
     format!("/api/image/{}", filename)
 }
 
+#[inline(always)]
+fn audio_path<T: Display>(filename: T) -> String {
+    format!("/api/audio/{}", filename)
+}
+
+/// Tile forms may be a little larger than their image and recording.
+const MAX_FORM_BYTES: u64 = (image::MAX_IMAGE_BYTES + audio::MAX_AUDIO_BYTES) as u64 + 64 * 1024;
+
+pub(crate) fn tile_form() -> FormOptions {
+    warp::multipart::form().max_length(MAX_FORM_BYTES)
+}
+
+/// Where to fetch each of the scaled down versions of the image `hash`, by size.
+fn thumbnail_paths(hash: &str) -> BTreeMap<u32, String> {
+    image::THUMBNAIL_SIZES
+        .iter()
+        .map(|size| (*size, format!("{}?size={}", image_path(hash), size)))
+        .collect()
+}
+
+/// The columns needed to build a [`Tile`] from a row.
+pub(crate) const TILE_COLUMNS: &str = r#"
+    tiles.phrase, tiles.image_hash, tiles.categories, tiles.speech, tiles.position,
+    tiles.audio_hash, tiles.inflections, tiles.slots, tiles.user_id IS NULL AS library
+"#;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Tile {
     pub phrase: String,
     pub image: String,
     pub categories: Vec<String>,
+    pub speech: Option<String>,
+    pub position: i32,
+    #[serde(default)]
+    pub library: bool,
+    /// What to say for the tile, with the user's pronunciations applied.
+    #[serde(default)]
+    pub spoken: String,
+    #[serde(default)]
+    pub thumbnails: BTreeMap<u32, String>,
+    /// A recording to play instead of speaking the tile.
+    #[serde(default)]
+    pub audio: Option<String>,
+    /// Forms of the tile's word that modifier tiles give instead of the language's rules.
+    #[serde(default)]
+    pub inflections: BTreeMap<Inflection, String>,
+    /// The category each `{slot}` in what a template tile says is filled from. Tiles without
+    /// slots aren't templates.
+    #[serde(default)]
+    pub slots: BTreeMap<String, String>,
+    /// Whether the user keeps the tile on their favorites board.
+    #[serde(default)]
+    pub favorite: bool,
+}
+
+impl<'a> From<&'a Row> for Tile {
+    fn from(item: &'a Row) -> Self {
+        let hash: &str = item.get("image_hash");
+        let phrase: String = item.get("phrase");
+        let speech: Option<String> = item.get("speech");
+        Tile {
+            spoken: speech.clone().unwrap_or_else(|| phrase.clone()),
+            phrase,
+            image: image_path(hash),
+            categories: item.get("categories"),
+            speech,
+            position: item.get("position"),
+            library: item.get("library"),
+            thumbnails: thumbnail_paths(hash),
+            audio: item.get::<_, Option<&str>>("audio_hash").map(audio_path),
+            inflections: item
+                .get::<_, SqlJson<BTreeMap<Inflection, String>>>("inflections")
+                .0,
+            slots: item.get::<_, SqlJson<BTreeMap<String, String>>>("slots").0,
+            favorite: false,
+        }
+    }
 }
 
 #[derive(Default)]
-struct TileForm {
+pub(crate) struct TileForm {
     pub phrase: Option<String>,
-    pub image: Option<(Vec<u8>, String, String)>,
+    pub image: Option<Image>,
     pub categories: Option<Vec<String>>,
+    pub speech: Option<String>,
+    pub position: Option<i32>,
+    /// A new recording, or `Some(None)` to remove the recording when the part is empty.
+    pub audio: Option<Option<Audio>>,
+    pub inflections: Option<BTreeMap<Inflection, String>>,
+    pub slots: Option<BTreeMap<String, String>>,
+}
+
+/// Reads the forms overriding a tile's inflections, like `{"past": "went"}`.
+fn decode_inflections(raw: &str) -> Result<BTreeMap<Inflection, String>, Error> {
+    let mut inflections = serde_json::from_str::<BTreeMap<Inflection, String>>(raw)
+        .map_err(|_| Error::MalformedRequest)?;
+    for form in inflections.values_mut() {
+        *form = form.trim().to_string();
+        if form.is_empty() || form.chars().count() > morphology::MAX_FORM_CHARS {
+            return Err(Error::MalformedRequest);
+        }
+    }
+    Ok(inflections)
 }
 
-async fn decode_tile_form(mut form_data: FormData) -> Result<TileForm, Error> {
+pub(crate) async fn decode_tile_form(mut form_data: FormData) -> Result<TileForm, Error> {
     let mut form: TileForm = Default::default();
     while let Ok(Some(part)) = form_data.try_next().await {
         match (part.name(), part.content_type()) {
@@ -119,13 +245,52 @@ async fn decode_tile_form(mut form_data: FormData) -> Result<TileForm, Error> {
                     );
                 }
             }
-            ("image", Some(content_type)) => {
-                let content_type = content_type.to_string();
-                if content_type.starts_with("image/") {
-                    if let Ok(bytes) = util::stream_bytes(part.stream()).await {
-                        let hash = util::hash(bytes.as_slice());
-                        form.image = Some((bytes, content_type, hash));
-                    }
+            ("speech", _) => {
+                if let Ok(bytes) = util::stream_bytes(part.stream()).await {
+                    form.speech =
+                        Some(String::from_utf8(bytes).map_err(|_| Error::MalformedRequest)?);
+                }
+            }
+            ("position", _) => {
+                if let Ok(bytes) = util::stream_bytes(part.stream()).await {
+                    let raw_str = String::from_utf8(bytes).map_err(|_| Error::MalformedRequest)?;
+                    form.position = Some(
+                        raw_str
+                            .trim()
+                            .parse()
+                            .map_err(|_| Error::MalformedRequest)?,
+                    );
+                }
+            }
+            ("inflections", _) => {
+                if let Ok(bytes) = util::stream_bytes(part.stream()).await {
+                    let raw_str = String::from_utf8(bytes).map_err(|_| Error::MalformedRequest)?;
+                    form.inflections = Some(decode_inflections(&raw_str)?);
+                }
+            }
+            ("slots", _) => {
+                if let Ok(bytes) = util::stream_bytes(part.stream()).await {
+                    let raw_str = String::from_utf8(bytes).map_err(|_| Error::MalformedRequest)?;
+                    form.slots = Some(
+                        serde_json::from_str::<BTreeMap<String, String>>(raw_str.as_ref())
+                            .map_err(|_| Error::MalformedRequest)?,
+                    );
+                }
+            }
+            ("image", content_type) => {
+                let content_type = content_type.map(str::to_string);
+                if let Ok(bytes) = util::stream_bytes(part.stream()).await {
+                    form.image = Some(Image::from_upload(bytes, content_type.as_deref()).await?);
+                }
+            }
+            ("audio", content_type) => {
+                let content_type = content_type.map(str::to_string);
+                if let Ok(bytes) = util::stream_bytes(part.stream()).await {
+                    form.audio = Some(if bytes.is_empty() {
+                        None
+                    } else {
+                        Some(Audio::from_upload(bytes, content_type.as_deref()).await?)
+                    });
                 }
             }
             _ => (),
@@ -134,48 +299,334 @@ async fn decode_tile_form(mut form_data: FormData) -> Result<TileForm, Error> {
     Ok(form)
 }
 
-pub async fn create_user_tile(
-    username: String,
-    form: FormData,
-    pool: db::Pool,
-) -> Result<WithStatus<Json>, Rejection> {
-    let tile = decode_tile_form(form).await?;
+/// Inserts a tile owned by `owner`, or into the shared library when there is no owner.
+pub(crate) async fn insert_tile(
+    conn: &mut db::Conn,
+    storage: &dyn Storage,
+    owner: Option<i32>,
+    tile: TileForm,
+) -> Result<Tile, Error> {
     match (tile.phrase, tile.image, tile.categories) {
-        (Some(phrase), Some((image, content_type, hash)), Some(categories)) => {
-            let conn = db::get_db_conn(&pool).await?;
-            let uid: i32 = {
-                let row = conn
-                    .query_one("SELECT id FROM users WHERE username = $1", &[&username])
-                    .await
-                    .map_err(|_| Error::NotFound)?;
-                row.get::<_, Option<i32>>("id").ok_or(Error::NotFound)?
-            };
-            conn.query(
+        (Some(phrase), Some(image), Some(categories)) => {
+            let tx = conn.transaction().await.map_err(Error::DBError)?;
+            image::store(&tx, storage, &image).await?;
+            let audio = tile.audio.flatten();
+            if let Some(audio) = &audio {
+                audio::store(&tx, storage, audio).await?;
+            }
+            // New tiles go to the end of the manual order unless placed explicitly.
+            let row = tx
+                .query_one(
+                    format!(
+                        r#"
+                        INSERT INTO tiles (
+                            user_id, phrase, image_hash, categories, speech, position, audio_hash,
+                            inflections, slots
+                        )
+                        VALUES ($1, $2, $3, $4, $5, COALESCE($6, (
+                            SELECT COALESCE(MAX(position) + 1, 0) FROM tiles
+                            WHERE user_id = $1 OR (user_id IS NULL AND $1::INTEGER IS NULL)
+                        )), $7, COALESCE($8, '{{}}'::JSONB), COALESCE($9, '{{}}'::JSONB))
+                        RETURNING {}
+                        "#,
+                        TILE_COLUMNS
+                    )
+                    .as_str(),
+                    &[
+                        &owner,
+                        &phrase,
+                        &image.hash,
+                        &categories,
+                        &tile.speech,
+                        &tile.position,
+                        &audio.as_ref().map(|audio| audio.hash.as_str()),
+                        &tile.inflections.as_ref().map(SqlJson),
+                        &tile.slots.as_ref().map(SqlJson),
+                    ],
+                )
+                .await
+                .map_err(Error::DBError)?;
+            let tile = Tile::from(&row);
+            template::check(&tx, owner, &tile).await?;
+            tx.commit().await.map_err(Error::DBError)?;
+            Ok(tile)
+        }
+        _ => Err(Error::MalformedRequest),
+    }
+}
+
+/// Applies the fields present in `tile` to the tile `phrase` belonging to `owner`, or to the
+/// shared library when there is no owner.
+pub(crate) async fn update_tile(
+    conn: &mut db::Conn,
+    storage: &dyn Storage,
+    owner: Option<i32>,
+    phrase: &str,
+    tile: TileForm,
+) -> Result<Tile, Error> {
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    if let Some(image) = &tile.image {
+        image::store(&tx, storage, image).await?;
+    }
+    if let Some(Some(audio)) = &tile.audio {
+        audio::store(&tx, storage, audio).await?;
+    }
+    let (mut released_images, mut released_audio) = (vec![], vec![]);
+    if tile.image.is_some() || tile.audio.is_some() {
+        let rows = tx
+            .query(
                 r#"
-                INSERT INTO tiles (user_id, phrase, image, image_type, image_hash, categories)
-                VALUES ($1, $2, $3, $4, $5, $6)
+                SELECT image_hash, audio_hash FROM tiles
+                WHERE (user_id = $1 OR (user_id IS NULL AND $1::INTEGER IS NULL))
+                    AND phrase = $2
+                FOR UPDATE
                 "#,
-                &[&uid, &phrase, &image, &content_type, &hash, &categories],
+                &[&owner, &phrase],
             )
             .await
             .map_err(Error::DBError)?;
+        for row in rows.iter() {
+            if tile.image.is_some() {
+                released_images.push(row.get("image_hash"));
+            }
+            if tile.audio.is_some() {
+                released_audio.extend(row.get::<_, Option<String>>("audio_hash"));
+            }
+        }
+    }
+    let row = tx
+        .query_opt(
+            format!(
+                r#"
+                UPDATE tiles
+                SET phrase = COALESCE($1, phrase),
+                    image_hash = COALESCE($2, image_hash),
+                    categories = COALESCE($3, categories),
+                    speech = COALESCE($4, speech),
+                    position = COALESCE($5, position),
+                    audio_hash = CASE WHEN $8 THEN $9 ELSE audio_hash END,
+                    inflections = COALESCE($10, inflections),
+                    slots = COALESCE($11, slots)
+                WHERE (user_id = $6 OR (user_id IS NULL AND $6::INTEGER IS NULL))
+                    AND phrase = $7
+                RETURNING {}
+                "#,
+                TILE_COLUMNS
+            )
+            .as_str(),
+            &[
+                &tile.phrase,
+                &tile.image.as_ref().map(|image| image.hash.as_str()),
+                &tile.categories,
+                &tile.speech,
+                &tile.position,
+                &owner,
+                &phrase,
+                &tile.audio.is_some(),
+                &tile
+                    .audio
+                    .as_ref()
+                    .and_then(|audio| audio.as_ref().map(|a| a.hash.as_str())),
+                &tile.inflections.as_ref().map(SqlJson),
+                &tile.slots.as_ref().map(SqlJson),
+            ],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    let updated = Tile::from(&row);
+    template::check(&tx, owner, &updated).await?;
+    tx.commit().await.map_err(Error::DBError)?;
+    if !released_images.is_empty() {
+        image::collect(conn, storage, &released_images).await?;
+    }
+    if !released_audio.is_empty() {
+        audio::collect(conn, storage, &released_audio).await?;
+    }
+    Ok(updated)
+}
+
+/// Deletes the tile `phrase` belonging to `owner`, or from the shared library when there is no
+/// owner.
+pub(crate) async fn delete_tile(
+    conn: &mut db::Conn,
+    storage: &dyn Storage,
+    owner: Option<i32>,
+    phrase: &str,
+) -> Result<(), Error> {
+    let rows = conn
+        .query(
+            r#"
+            DELETE FROM tiles
+            WHERE (user_id = $1 OR (user_id IS NULL AND $1::INTEGER IS NULL)) AND phrase = $2
+            RETURNING image_hash, audio_hash
+            "#,
+            &[&owner, &phrase],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    let released_images = rows
+        .iter()
+        .map(|row| row.get("image_hash"))
+        .collect::<Vec<String>>();
+    let released_audio = rows
+        .iter()
+        .filter_map(|row| row.get("audio_hash"))
+        .collect::<Vec<String>>();
+    if !released_images.is_empty() {
+        image::collect(conn, storage, &released_images).await?;
+    }
+    if !released_audio.is_empty() {
+        audio::collect(conn, storage, &released_audio).await?;
+    }
+    Ok(())
+}
+
+/// Applies the pronunciations of the user `uid` to what each of `tiles` says, and flags the
+/// tiles they marked as favorites.
+pub(crate) async fn personalize(
+    conn: &db::Conn,
+    uid: i32,
+    tiles: &mut [Tile],
+) -> Result<(), Error> {
+    let lexicon = lexicon::lexicon(conn, uid).await?;
+    let favorites = board::favorites(conn, uid).await?;
+    for tile in tiles.iter_mut() {
+        tile.spoken = lexicon.apply(&tile.spoken);
+        tile.favorite = favorites.contains(&(tile.phrase.clone(), tile.library));
+    }
+    Ok(())
+}
+
+pub async fn create_user_tile(
+    username: String,
+    form: FormData,
+    pool: db::Pool,
+    storage: SharedStorage,
+) -> Result<WithStatus<Json>, Rejection> {
+    let tile = decode_tile_form(form).await?;
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let mut tile = insert_tile(&mut conn, storage.as_ref(), Some(uid), tile).await?;
+    personalize(&conn, uid, std::slice::from_mut(&mut tile)).await?;
+    Ok(with_status(json(&tile), StatusCode::CREATED))
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileSort {
+    Relevance,
+    Alphabetical,
+    MostUsed,
+    RecentlyUsed,
+    RecentlyCreated,
+    Manual,
+}
+
+impl TileSort {
+    fn name(self) -> &'static str {
+        match self {
+            TileSort::Relevance => "relevance",
+            TileSort::Alphabetical => "alphabetical",
+            TileSort::MostUsed => "most_used",
+            TileSort::RecentlyUsed => "recently_used",
+            TileSort::RecentlyCreated => "recently_created",
+            TileSort::Manual => "manual",
+        }
+    }
+
+    /// The SQL expression tiles are ordered by, and the type it is cast back to when read out
+    /// of a cursor. Ties are always broken by id in the same direction.
+    fn key(self) -> (&'static str, &'static str) {
+        match self {
+            TileSort::Relevance => (RELEVANCE, "DOUBLE PRECISION"),
+            TileSort::Alphabetical => ("phrase", "TEXT"),
+            TileSort::MostUsed => (USE_COUNT, "INTEGER"),
+            TileSort::RecentlyUsed => (LAST_USED, "TIMESTAMPTZ"),
+            TileSort::RecentlyCreated => ("created_at", "TIMESTAMPTZ"),
+            TileSort::Manual => ("position", "INTEGER"),
+        }
+    }
+
+    fn ascending(self) -> bool {
+        match self {
+            TileSort::Alphabetical | TileSort::Manual => true,
+            TileSort::Relevance
+            | TileSort::MostUsed
+            | TileSort::RecentlyUsed
+            | TileSort::RecentlyCreated => false,
+        }
+    }
+}
+
+/// How many times the user in `$1` used a tile, library tiles included.
+const USE_COUNT: &str = r#"COALESCE((
+    SELECT use_count FROM tile_uses
+    WHERE tile_uses.user_id = $1 AND tile_uses.tile_id = tiles.id
+), 0)"#;
+
+/// When the user in `$1` last used a tile, library tiles included.
+const LAST_USED: &str = r#"COALESCE((
+    SELECT last_used_at FROM tile_uses
+    WHERE tile_uses.user_id = $1 AND tile_uses.tile_id = tiles.id
+), '-infinity')"#;
+
+/// Scores a tile against the search term in `$2` (escaped for `LIKE` in `$4`). Exact and prefix
+/// matches on the phrase outrank matches elsewhere, and trigram similarity orders the rest.
+const RELEVANCE: &str = r#"COALESCE(
+    CASE
+        WHEN search_normalize(phrase) = search_normalize($2) THEN 3
+        WHEN search_normalize(phrase) LIKE search_normalize($4) || '%' THEN 2
+        WHEN tile_search_text(phrase, speech, categories)
+            LIKE '%' || search_normalize($4) || '%' THEN 1
+        ELSE 0
+    END + word_similarity(search_normalize($2), tile_search_text(phrase, speech, categories)),
+    0
+)"#;
+
+/// An opaque position within a listing: the sort it belongs to plus the sort key and id of the
+/// last tile on the previous page.
+struct TileCursor {
+    sort: TileSort,
+    id: i32,
+    key: String,
+}
 
-            let tile = Tile {
-                phrase: phrase.to_string(),
-                image: image_path(hash),
-                categories,
-            };
+impl TileCursor {
+    fn encode(&self) -> String {
+        util::hex_encode(format!("{}:{}:{}", self.sort.name(), self.id, self.key).as_bytes())
+    }
 
-            Ok(with_status(json(&tile), StatusCode::CREATED))
+    fn decode(raw: &str, sort: TileSort) -> Result<Self, Error> {
+        let decoded = util::hex_decode(raw)
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(Error::MalformedRequest)?;
+        let mut fields = decoded.splitn(3, ':');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(id), Some(key)) if name == sort.name() => Ok(TileCursor {
+                sort,
+                id: id.parse().map_err(|_| Error::MalformedRequest)?,
+                key: key.to_string(),
+            }),
+            _ => Err(Error::MalformedRequest),
         }
-        _ => Err(Rejection::from(Error::MalformedRequest)),
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct TileQuery {
-    phrase: Option<String>,
-    category: Option<String>,
+    pub phrase: Option<String>,
+    pub category: Option<String>,
+    pub sort: Option<TileSort>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TilePage {
+    pub tiles: Vec<Tile>,
+    pub total: i64,
+    pub next_cursor: Option<String>,
 }
 
 pub async fn list_tiles(
@@ -193,37 +644,134 @@ pub async fn list_tiles(
     } else {
         None
     };
-    Ok(json(
-        &conn
-            .query(
+    Ok(json(&query_tiles(&conn, uid, &query).await?))
+}
+
+/// Lists a page of the tiles `uid` can see, or only the library without a user.
+pub(crate) async fn query_tiles(
+    conn: &db::Conn,
+    uid: Option<i32>,
+    query: &TileQuery,
+) -> Result<TilePage, Error> {
+    let sort = query.sort.unwrap_or(if query.phrase.is_some() {
+        TileSort::Relevance
+    } else {
+        TileSort::Alphabetical
+    });
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Error::MalformedRequest);
+    }
+    let cursor = match &query.cursor {
+        Some(raw) => Some(TileCursor::decode(raw, sort)?),
+        None => None,
+    };
+
+    // Searches are case and accent insensitive, match substrings of the phrase, speech text and
+    // categories, and tolerate typos through trigram word similarity.
+    let pattern = query.phrase.as_deref().map(util::escape_like);
+    let filter = r#"
+        (user_id IS NULL OR user_id = $1)
+            AND NOT EXISTS (
+                SELECT 1 FROM hidden_tiles
+                WHERE hidden_tiles.user_id = $1 AND hidden_tiles.tile_id = tiles.id
+            )
+            AND ($2::TEXT IS NULL
+                OR tile_search_text(phrase, speech, categories)
+                    LIKE '%' || search_normalize($4::TEXT) || '%'
+                OR search_normalize($2) <% tile_search_text(phrase, speech, categories))
+            AND ($3::TEXT IS NULL OR $3 = ANY(categories))
+    "#;
+    let total: i64 = conn
+        .query_one(
+            format!("SELECT COUNT(*) AS total FROM tiles WHERE {}", filter).as_str(),
+            &[&uid, &query.phrase, &query.category, &pattern],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .get("total");
+
+    let (key, key_type) = sort.key();
+    let (direction, comparison) = if sort.ascending() {
+        ("ASC", ">")
+    } else {
+        ("DESC", "<")
+    };
+    let (after_key, after_id) = match &cursor {
+        Some(c) => (Some(c.key.as_str()), Some(c.id)),
+        None => (None, None),
+    };
+    // One extra row is fetched to learn whether another page follows.
+    let rows = conn
+        .query(
+            format!(
                 r#"
-                SELECT phrase, image_hash, categories
+                SELECT id, {columns}, {key}::TEXT AS sort_key
                 FROM tiles
-                WHERE (user_id IS NULL OR user_id = $1)
-                    AND ($2::TEXT IS NULL OR phrase LIKE $2)
-                    AND ($3::TEXT IS NULL OR $3 = ANY(categories))
-                ORDER BY phrase ASC
+                WHERE {filter}
+                    AND ($5::TEXT IS NULL OR ({key}, id) {cmp} ($5::TEXT::{key_type}, $6::INTEGER))
+                ORDER BY {key} {dir}, id {dir}
+                LIMIT $7
                 "#,
-                &[&uid, &query.phrase, &query.category],
+                columns = TILE_COLUMNS,
+                key = key,
+                key_type = key_type,
+                filter = filter,
+                cmp = comparison,
+                dir = direction,
             )
-            .await
-            .map_err(Error::DBError)?
-            .iter()
-            .map(|row| Tile {
-                phrase: row.get("phrase"),
-                image: image_path(row.get::<_, String>("image_hash")),
-                categories: row.get("categories"),
-            })
-            .collect::<Vec<Tile>>(),
-    ))
+            .as_str(),
+            &[
+                &uid,
+                &query.phrase,
+                &query.category,
+                &pattern,
+                &after_key,
+                &after_id,
+                &(limit + 1),
+            ],
+        )
+        .await
+        .map_err(Error::DBError)?;
+
+    let next_cursor = if rows.len() as i64 > limit {
+        let last = &rows[limit as usize - 1];
+        Some(
+            TileCursor {
+                sort,
+                id: last.get("id"),
+                key: last.get("sort_key"),
+            }
+            .encode(),
+        )
+    } else {
+        None
+    };
+
+    let mut tiles = rows
+        .iter()
+        .take(limit as usize)
+        .map(Tile::from)
+        .collect::<Vec<Tile>>();
+    if let Some(uid) = uid {
+        personalize(conn, uid, &mut tiles).await?;
+    }
+    Ok(TilePage {
+        tiles,
+        total,
+        next_cursor,
+    })
 }
 
-pub async fn read_image(
+/// Checks the tiles the requester can see, by their token or a share, include one using `hash`
+/// as its `column`. Media is visible to anyone who can see one of the tiles using it.
+async fn check_media_access(
+    conn: &db::Conn,
     maybe_tok: Option<BearerToken>,
-    hash: String,
-    pool: db::Pool,
-) -> Result<WithHeader<Vec<u8>>, Rejection> {
-    let conn = db::get_db_conn(&pool).await?;
+    maybe_share: Option<ShareGrant>,
+    column: &str,
+    hash: &str,
+) -> Result<(), Error> {
     let uid: Option<i32> = if let Some(tok) = maybe_tok {
         let row = conn
             .query_one("SELECT id FROM users WHERE username = $1", &[&tok.username])
@@ -233,22 +781,89 @@ pub async fn read_image(
     } else {
         None
     };
-    let row = conn
-        .query_one(
+    let (share_uid, share_category) = match maybe_share {
+        Some(grant) => (Some(grant.user_id), grant.category),
+        None => (None, None),
+    };
+    conn.query_opt(
+        format!(
             r#"
-            SELECT image, image_type FROM tiles
-            WHERE (user_id IS NULL OR user_id = $1)
-                AND image_hash = $2
+            SELECT 1 FROM tiles
+            WHERE (user_id IS NULL
+                    OR user_id = $1
+                    OR (user_id = $3 AND ($4::TEXT IS NULL OR $4 = ANY(categories))))
+                AND {} = $2
+            LIMIT 1
             "#,
-            &[&uid, &hash],
+            column
         )
-        .await
-        .map_err(Error::DBError)?;
-    Ok(with_header(
-        row.get("image"),
-        "Content-Type",
-        row.get::<_, String>("image_type"),
-    ))
+        .as_str(),
+        &[&uid, &hash, &share_uid, &share_category],
+    )
+    .await
+    .map_err(Error::DBError)?
+    .ok_or(Error::NotFound)?;
+    Ok(())
+}
+
+pub async fn read_image(
+    maybe_tok: Option<BearerToken>,
+    hash: String,
+    query: ImageQuery,
+    conditions: Conditions,
+    maybe_share: Option<ShareGrant>,
+    pool: db::Pool,
+    storage: SharedStorage,
+) -> Result<Response, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    check_media_access(&conn, maybe_tok, maybe_share, "image_hash", &hash).await?;
+    let etag = media::etag(&query.version(&hash));
+    let mut res = match media::not_modified(&conditions, &etag) {
+        Some(res) => res,
+        None => {
+            let (data, content_type) =
+                image::load_variant(&conn, storage.as_ref(), &hash, &query).await?;
+            media::respond(data, &content_type, &etag, &conditions)
+        }
+    };
+    // Even sanitized SVG is a document, so it gets no scripts, plugins or outside resources.
+    let headers = res.headers_mut();
+    headers.insert(
+        "X-Content-Type-Options",
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        "Content-Security-Policy",
+        HeaderValue::from_static(
+            "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox",
+        ),
+    );
+    Ok(res)
+}
+
+pub async fn read_audio(
+    maybe_tok: Option<BearerToken>,
+    hash: String,
+    conditions: Conditions,
+    maybe_share: Option<ShareGrant>,
+    pool: db::Pool,
+    storage: SharedStorage,
+) -> Result<Response, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    check_media_access(&conn, maybe_tok, maybe_share, "audio_hash", &hash).await?;
+    let etag = media::etag(&hash);
+    let mut res = match media::not_modified(&conditions, &etag) {
+        Some(res) => res,
+        None => {
+            let (data, content_type) = audio::load(&conn, storage.as_ref(), &hash).await?;
+            media::respond(data, &content_type, &etag, &conditions)
+        }
+    };
+    res.headers_mut().insert(
+        "X-Content-Type-Options",
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok(res)
 }
 
 pub async fn update_user_tile(
@@ -256,51 +871,13 @@ pub async fn update_user_tile(
     phrase: String,
     form: FormData,
     pool: db::Pool,
+    storage: SharedStorage,
 ) -> Result<Json, Rejection> {
     let tile = decode_tile_form(form).await?;
-    let conn = db::get_db_conn(&pool).await?;
-    let uid: i32 = {
-        let row = conn
-            .query_one("SELECT id FROM users WHERE username = $1", &[&username])
-            .await
-            .map_err(|_| Error::NotFound)?;
-        row.get("id")
-    };
-    let (image, content_type, hash) = match tile.image {
-        Some((bytes, content_type, hash)) => (Some(bytes), Some(content_type), Some(hash)),
-        None => (None, None, None),
-    };
-    let row = conn
-        .query_one(
-            r#"
-            UPDATE tiles
-            SET phrase = COALESCE($1, phrase),
-                image = COALESCE($2, image),
-                image_type = COALESCE($3, image_type),
-                image_hash = COALESCE($4, image_hash),
-                categories = COALESCE($5, categories)
-            WHERE user_id = $6 AND phrase = $7
-            RETURNING phrase, image_hash, categories
-            "#,
-            &[
-                &tile.phrase,
-                &image,
-                &content_type,
-                &hash,
-                &tile.categories,
-                &uid,
-                &phrase,
-            ],
-        )
-        .await
-        .map_err(Error::DBError)?;
-
-    let tile = Tile {
-        phrase: row.get("phrase"),
-        image: image_path(row.get::<_, String>("image_hash")),
-        categories: row.get("categories"),
-    };
-
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let mut tile = update_tile(&mut conn, storage.as_ref(), Some(uid), &phrase, tile).await?;
+    personalize(&conn, uid, std::slice::from_mut(&mut tile)).await?;
     Ok(json(&tile))
 }
 
@@ -308,24 +885,10 @@ pub async fn delete_user_tile(
     username: String,
     phrase: String,
     pool: db::Pool,
+    storage: SharedStorage,
 ) -> Result<StatusCode, Rejection> {
-    let conn = db::get_db_conn(&pool).await?;
-    let uid: i32 = {
-        let row = conn
-            .query_one("SELECT id FROM users WHERE username = $1", &[&username])
-            .await
-            .map_err(|_| Error::NotFound)?;
-        row.get("id")
-    };
-    conn.query(
-        r#"
-        DELETE FROM tiles
-        WHERE user_id = $1 AND phrase = $2
-        "#,
-        &[&uid, &phrase],
-    )
-    .await
-    .map_err(Error::DBError)?;
-
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    delete_tile(&mut conn, storage.as_ref(), Some(uid), &phrase).await?;
     Ok(StatusCode::OK)
 }
@@ -1,3 +1,27 @@
 DROP TABLE IF EXISTS user_auths;
+DROP TABLE IF EXISTS pronunciations;
+DROP TABLE IF EXISTS preferences;
+DROP TABLE IF EXISTS messages;
+DROP TABLE IF EXISTS utterances;
+DROP TABLE IF EXISTS usage_events;
+DROP TABLE IF EXISTS ngram_models;
+DROP TABLE IF EXISTS ngrams;
+DROP TABLE IF EXISTS personal_words;
+DROP TABLE IF EXISTS abbreviations;
+DROP TABLE IF EXISTS hidden_tiles;
+DROP TABLE IF EXISTS favorite_tiles;
+DROP TABLE IF EXISTS tile_activity;
+DROP TABLE IF EXISTS tile_uses;
+DROP TABLE IF EXISTS caregivers;
+DROP TABLE IF EXISTS shares;
 DROP TABLE IF EXISTS tiles;
+DROP TABLE IF EXISTS image_variants;
+DROP TABLE IF EXISTS images;
+DROP TABLE IF EXISTS recordings;
+DROP TABLE IF EXISTS blobs;
 DROP TABLE IF EXISTS users;
+DROP FUNCTION IF EXISTS tile_search_text;
+DROP FUNCTION IF EXISTS search_normalize;
+DROP FUNCTION IF EXISTS first_word;
+DROP FUNCTION IF EXISTS count_image_refs;
+DROP FUNCTION IF EXISTS count_recording_refs;
@@ -0,0 +1,376 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! What a user says, tile by tile and message by message, for therapists tracking their goals.
+//! Nothing is logged here unless the user opts in through their `log_usage` preference. Apart
+//! from this log, the messages a user speaks are only kept in their history, teaching their word
+//! predictions, if they opt in through `keep_history`. Activating a tile always counts towards
+//! its use, which orders tiles and fills the recent and most used boards with a week of daily
+//! counts, but no text and no record of where. Erasing usage forgets all of it.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::DecodingKey;
+use mobc_postgres::tokio_postgres::types::Json as SqlJson;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::StatusCode,
+    reply::{json, Json},
+    Filter, Rejection, Reply,
+};
+
+use crate::{board, db, guard, message, morphology::Inflection, preferences, user, util, Error};
+
+/// The longest board name, in characters.
+const MAX_BOARD_CHARS: usize = 100;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+pub fn api(
+    db_pool: db::Pool,
+    jwt_key: DecodingKey<'static>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let activate_tile = warp::post()
+        .and(guard::user_resource(jwt_key.clone()))
+        .and(warp::path("usage"))
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(activate_tile);
+
+    let list_usage = warp::get()
+        .and(guard::managed_user_resource(
+            jwt_key.clone(),
+            db_pool.clone(),
+        ))
+        .and(warp::path("usage"))
+        .and(warp::path::end())
+        .and(warp::query())
+        .and(guard::with_db(db_pool.clone()))
+        .and_then(list_usage);
+
+    let erase_usage = warp::delete()
+        .and(guard::managed_user_resource(jwt_key, db_pool.clone()))
+        .and(warp::path("usage"))
+        .and(warp::path::end())
+        .and(guard::with_db(db_pool))
+        .and_then(erase_usage);
+
+    activate_tile.or(list_usage).or(erase_usage)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageKind {
+    /// A tile was pressed.
+    Tile,
+    /// A composed message was spoken.
+    Utterance,
+}
+
+impl UsageKind {
+    fn name(self) -> &'static str {
+        match self {
+            UsageKind::Tile => "tile",
+            UsageKind::Utterance => "utterance",
+        }
+    }
+}
+
+/// A tile being pressed, as reported by a device.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Activation {
+    pub phrase: String,
+    /// The board, or category, the tile was pressed on.
+    #[serde(default)]
+    pub board: Option<String>,
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UsageEvent {
+    pub kind: UsageKind,
+    /// The tile pressed. Kept when the tile is deleted, though its id is not.
+    pub tile_id: Option<i32>,
+    pub phrase: Option<String>,
+    pub board: Option<String>,
+    pub device: Option<String>,
+    /// What was said.
+    pub text: String,
+    pub occurred_at: DateTime<Utc>,
+}
+
+/// What a tile says, as found when it was pressed.
+pub(crate) struct ActivatedTile {
+    pub phrase: String,
+    pub text: String,
+    pub inflections: BTreeMap<Inflection, String>,
+}
+
+/// Whether the user `uid` agreed to have their usage logged.
+async fn logging(conn: &db::Conn, uid: i32) -> Result<bool, Error> {
+    Ok(preferences::load(conn, uid).await?.log_usage)
+}
+
+/// Presses the tile `phrase` for the user `uid`: their own tile of that phrase, or else the
+/// library's. The press counts towards the user's use of the tile, and is logged if they opted
+/// in.
+pub(crate) async fn activate(
+    conn: &db::Conn,
+    uid: i32,
+    activation: &Activation,
+) -> Result<ActivatedTile, Error> {
+    if let Some(board) = &activation.board {
+        if board.chars().count() > MAX_BOARD_CHARS {
+            return Err(Error::MalformedRequest);
+        }
+    }
+    if let Some(device) = &activation.device {
+        message::check_device(device)?;
+    }
+    let row = conn
+        .query_opt(
+            r#"
+            SELECT id, phrase, COALESCE(speech, phrase) AS text, inflections FROM tiles
+            WHERE (user_id = $1 OR user_id IS NULL) AND phrase = $2
+            ORDER BY user_id NULLS LAST
+            LIMIT 1
+            "#,
+            &[&uid, &activation.phrase],
+        )
+        .await
+        .map_err(Error::DBError)?
+        .ok_or(Error::NotFound)?;
+    let tile_id: i32 = row.get("id");
+    let tile = ActivatedTile {
+        phrase: row.get("phrase"),
+        text: row.get("text"),
+        inflections: row
+            .get::<_, SqlJson<BTreeMap<Inflection, String>>>("inflections")
+            .0,
+    };
+    conn.execute(
+        r#"
+        INSERT INTO tile_uses (user_id, tile_id) VALUES ($1, $2)
+        ON CONFLICT (user_id, tile_id)
+            DO UPDATE SET use_count = tile_uses.use_count + 1, last_used_at = NOW()
+        "#,
+        &[&uid, &tile_id],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    // Days before the most used board's window are dropped once the tile is used again, as the
+    // recent board only needs the latest.
+    conn.execute(
+        r#"
+        INSERT INTO tile_activity (user_id, tile_id) VALUES ($1, $2)
+        ON CONFLICT (user_id, tile_id, used_on)
+            DO UPDATE SET uses = tile_activity.uses + 1, last_used_at = NOW()
+        "#,
+        &[&uid, &tile_id],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    conn.execute(
+        format!(
+            r#"
+            DELETE FROM tile_activity
+            WHERE user_id = $1 AND tile_id = $2 AND used_on <= CURRENT_DATE - {}
+            "#,
+            board::FREQUENT_DAYS
+        )
+        .as_str(),
+        &[&uid, &tile_id],
+    )
+    .await
+    .map_err(Error::DBError)?;
+    if logging(conn, uid).await? {
+        conn.execute(
+            r#"
+            INSERT INTO usage_events (user_id, kind, tile_id, phrase, board, device, text)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            &[
+                &uid,
+                &UsageKind::Tile.name(),
+                &tile_id,
+                &tile.phrase,
+                &activation.board,
+                &activation.device,
+                &tile.text,
+            ],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    }
+    Ok(tile)
+}
+
+/// Logs the message `text` spoken on `device`, if the user `uid` opted in.
+pub(crate) async fn log_utterance(
+    conn: &db::Conn,
+    uid: i32,
+    device: &str,
+    text: &str,
+) -> Result<(), Error> {
+    if logging(conn, uid).await? {
+        conn.execute(
+            "INSERT INTO usage_events (user_id, kind, device, text) VALUES ($1, $2, $3, $4)",
+            &[&uid, &UsageKind::Utterance.name(), &device, &text],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    }
+    Ok(())
+}
+
+pub async fn activate_tile(
+    username: String,
+    activation: Activation,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    activate(&conn, uid, &activation).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UsageQuery {
+    pub kind: Option<UsageKind>,
+    /// Only events at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only events before this time.
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UsagePage {
+    pub events: Vec<UsageEvent>,
+    pub next_cursor: Option<String>,
+}
+
+/// Lists a page of the user's logged usage, newest first.
+pub async fn list_usage(
+    username: String,
+    _actor: String,
+    query: UsageQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(Error::MalformedRequest.into());
+    }
+    // Cursors hold the id of the last event on the previous page.
+    let before: Option<i64> = match &query.cursor {
+        Some(raw) => Some(
+            util::hex_decode(raw)
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .and_then(|id| id.parse().ok())
+                .ok_or(Error::MalformedRequest)?,
+        ),
+        None => None,
+    };
+    let conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    // One extra row is fetched to learn whether another page follows.
+    let rows = conn
+        .query(
+            r#"
+            SELECT id, kind, tile_id, phrase, board, device, text, occurred_at
+            FROM usage_events
+            WHERE user_id = $1
+                AND ($2::TEXT IS NULL OR kind = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR occurred_at >= $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR occurred_at < $4)
+                AND ($5::BIGINT IS NULL OR id < $5)
+            ORDER BY id DESC
+            LIMIT $6
+            "#,
+            &[
+                &uid,
+                &query.kind.map(UsageKind::name),
+                &query.from,
+                &query.to,
+                &before,
+                &(limit + 1),
+            ],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    let next_cursor = if rows.len() as i64 > limit {
+        let last: i64 = rows[limit as usize - 1].get("id");
+        Some(util::hex_encode(last.to_string().as_bytes()))
+    } else {
+        None
+    };
+    let events = rows
+        .iter()
+        .take(limit as usize)
+        .map(|row| UsageEvent {
+            kind: match row.get::<_, &str>("kind") {
+                "tile" => UsageKind::Tile,
+                _ => UsageKind::Utterance,
+            },
+            tile_id: row.get("tile_id"),
+            phrase: row.get("phrase"),
+            board: row.get("board"),
+            device: row.get("device"),
+            text: row.get("text"),
+            occurred_at: row.get("occurred_at"),
+        })
+        .collect::<Vec<UsageEvent>>();
+    Ok(json(&UsagePage {
+        events,
+        next_cursor,
+    }))
+}
+
+/// Forgets everything logged about the user, along with the messages they spoke, what their
+/// predictions learned from them and the uses their recent and most used boards are built from.
+pub async fn erase_usage(
+    username: String,
+    _actor: String,
+    pool: db::Pool,
+) -> Result<StatusCode, Rejection> {
+    let mut conn = db::get_db_conn(&pool).await?;
+    let uid = user::user_id(&conn, &username).await?;
+    let tx = conn.transaction().await.map_err(Error::DBError)?;
+    for table in &[
+        "usage_events",
+        "tile_activity",
+        "tile_uses",
+        "utterances",
+        "ngrams",
+        "ngram_models",
+    ] {
+        tx.execute(
+            format!("DELETE FROM {} WHERE user_id = $1", table).as_str(),
+            &[&uid],
+        )
+        .await
+        .map_err(Error::DBError)?;
+    }
+    tx.commit().await.map_err(Error::DBError)?;
+    Ok(StatusCode::OK)
+}
@@ -24,7 +24,11 @@ use warp::{
     Filter, Rejection, Reply,
 };
 
-use crate::{db, guard, Error};
+use crate::{
+    db, guard,
+    preferences::{self, Preferences},
+    Error,
+};
 
 pub fn api(
     db_pool: db::Pool,
@@ -33,34 +37,72 @@ pub fn api(
     warp::get()
         .and(guard::user_resource(jwt_key))
         .and(warp::path::end())
+        .and(warp::query())
         .and(guard::with_db(db_pool))
         .and_then(read_user)
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
+    /// Only included when asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferences: Option<Preferences>,
 }
 
 impl<'a> From<&'a Row> for User {
     fn from(item: &'a Row) -> Self {
         User {
             username: item.get("username"),
+            preferences: None,
         }
     }
 }
 
-async fn read_user(username: String, pool: db::Pool) -> Result<Json, Rejection> {
-    let query = db::get_db_conn(&pool)
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UserQuery {
+    /// A comma separated list of optional parts to include, currently only `preferences`.
+    pub include: Option<String>,
+}
+
+impl UserQuery {
+    fn includes(&self, part: &str) -> bool {
+        self.include
+            .as_deref()
+            .is_some_and(|include| include.split(',').any(|p| p.trim() == part))
+    }
+}
+
+/// Looks up the id of `username`, failing with [`Error::NotFound`] for unknown users.
+pub async fn user_id(conn: &db::Conn, username: &str) -> Result<i32, Error> {
+    Ok(conn
+        .query_opt("SELECT id FROM users WHERE username = $1", &[&username])
         .await?
+        .ok_or(Error::NotFound)?
+        .get("id"))
+}
+
+async fn read_user(
+    username: String,
+    user_query: UserQuery,
+    pool: db::Pool,
+) -> Result<Json, Rejection> {
+    let conn = db::get_db_conn(&pool).await?;
+    let query = conn
         .query_one(
-            "SELECT username FROM users WHERE username = $1",
+            "SELECT id, username FROM users WHERE username = $1",
             &[&username],
         )
         .await;
 
     match query {
-        Ok(row) => Ok(json(&User::from(&row))),
+        Ok(row) => {
+            let mut user = User::from(&row);
+            if user_query.includes("preferences") {
+                user.preferences = Some(preferences::load(&conn, row.get("id")).await?);
+            }
+            Ok(json(&user))
+        }
         Err(e) => Err(Rejection::from(Error::DBError(e))),
     }
 }
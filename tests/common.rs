@@ -57,3 +57,62 @@ pub fn secret() -> String {
     }
     SECRET.clone()
 }
+
+/// Builds a multipart tile form holding `image`, returning its content type and body.
+#[allow(dead_code)]
+pub fn tile_form(
+    phrase: &str,
+    categories: &[&str],
+    image: &[u8],
+    image_type: &str,
+) -> (String, Vec<u8>) {
+    let boundary = "------------------------tileform";
+    let mut body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"phrase\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"categories\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"image\"\r\n\
+         Content-Type: {}\r\n\r\n",
+        phrase,
+        serde_json::to_string(categories).unwrap(),
+        image_type,
+        b = boundary,
+    )
+    .into_bytes();
+    body.extend_from_slice(image);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// Encodes a blank `width` by `height` image in `format`.
+#[allow(dead_code)]
+pub fn blank_image(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+    let mut out = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(width, height)
+        .write_to(&mut out, format)
+        .unwrap();
+    out.into_inner()
+}
+
+/// Builds a multipart form for a template tile in `category` saying `speech`, with the category
+/// of each of its `slots` as JSON, returning its content type and body.
+#[allow(dead_code)]
+pub fn template_form(phrase: &str, category: &str, speech: &str, slots: &str) -> (String, Vec<u8>) {
+    let boundary = "------------------------templateform";
+    let mut body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"phrase\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"categories\"\r\n\r\n[\"{}\"]\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"speech\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"slots\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"image\"\r\n\
+         Content-Type: image/png\r\n\r\n",
+        phrase,
+        category,
+        speech,
+        slots,
+        b = boundary,
+    )
+    .into_bytes();
+    body.extend_from_slice(&blank_image(8, 8, image::ImageFormat::Png));
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
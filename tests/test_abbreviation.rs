@@ -0,0 +1,202 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    abbreviation::{Abbreviation, Expansion},
+    app, auth,
+    message::{AppendTile, Message},
+    Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn abbreviation_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "abbreviation_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+    let set = |abbreviation: &str, expansion: &str| {
+        request("POST", "/api/user/abbreviation_flow/abbreviations")
+            .json(&Abbreviation {
+                abbreviation: abbreviation.to_string(),
+                expansion: expansion.to_string(),
+            })
+            .reply(&api)
+    };
+    // Takes the text already encoded for the query.
+    let expand = |text: &str| {
+        let res = request(
+            "GET",
+            &format!(
+                "/api/user/abbreviation_flow/abbreviations/expand?text={}",
+                text
+            ),
+        )
+        .reply(&api);
+        async move {
+            let res = res.await;
+            assert_eq!(res.status(), 200, "expansion ok");
+            serde_json::from_slice::<Expansion>(res.body())
+                .unwrap()
+                .expanded
+        }
+    };
+
+    {
+        // Test adding abbreviations.
+        assert_eq!(set("brb", "be right back").await.status(), 201);
+        assert_eq!(set("mm", "my mum").await.status(), 201);
+        assert_eq!(
+            set("MM", "my mom").await.status(),
+            200,
+            "abbreviation replaced"
+        );
+        assert_eq!(set("ty", "Thank you to Sam").await.status(), 201);
+        assert_eq!(set("b r b", "be right back").await.status(), 400);
+        assert_eq!(set("idk", " ").await.status(), 400, "expansion required");
+
+        let res = request("GET", "/api/user/abbreviation_flow/abbreviations")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "abbreviations listed");
+        assert_eq!(
+            serde_json::from_slice::<Vec<Abbreviation>>(res.body()).unwrap(),
+            vec![
+                Abbreviation {
+                    abbreviation: "brb".to_string(),
+                    expansion: "be right back".to_string(),
+                },
+                Abbreviation {
+                    abbreviation: "MM".to_string(),
+                    expansion: "my mom".to_string(),
+                },
+                Abbreviation {
+                    abbreviation: "ty".to_string(),
+                    expansion: "Thank you to Sam".to_string(),
+                },
+            ],
+            "one expansion per abbreviation"
+        );
+    }
+
+    {
+        // Test expanding.
+        assert_eq!(
+            expand("ok%20brb,%20mm%20is%20here").await,
+            "ok be right back, my mom is here",
+            "whole words expanded"
+        );
+        assert_eq!(
+            expand("Brb.%20BRB!").await,
+            "Be right back. BE RIGHT BACK!",
+            "case followed"
+        );
+        assert_eq!(
+            expand("ty").await,
+            "Thank you to Sam",
+            "saved capitals kept"
+        );
+        assert_eq!(
+            expand("mm-hmm%20summer%20brbs%20%22mm%22").await,
+            "mm-hmm summer brbs \"my mom\"",
+            "parts of words kept"
+        );
+    }
+
+    {
+        // Test expanding composed messages.
+        for phrase in ["brb", "mm"].iter() {
+            let (content_type, body) = common::tile_form(
+                phrase,
+                &["chat"],
+                &common::blank_image(8, 8, ImageFormat::Png),
+                "image/png",
+            );
+            let res = request("POST", "/api/user/abbreviation_flow/tiles")
+                .header("Content-Type", content_type)
+                .body(body)
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 201, "new tile created new resource");
+        }
+        for phrase in ["mm", "brb"].iter() {
+            let res = request("POST", "/api/user/abbreviation_flow/message/tablet/tiles")
+                .json(&AppendTile {
+                    phrase: phrase.to_string(),
+                    board: None,
+                })
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200, "{} appended", phrase);
+        }
+        let res = request("POST", "/api/user/abbreviation_flow/message/tablet/speak")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "message spoken");
+        let message = serde_json::from_slice::<Message>(res.body()).unwrap();
+        assert_eq!(message.text, "My mom be right back");
+    }
+
+    {
+        // Test removing abbreviations.
+        let remove = || {
+            request(
+                "DELETE",
+                "/api/user/abbreviation_flow/abbreviations?abbreviation=Brb",
+            )
+            .reply(&api)
+        };
+        assert_eq!(remove().await.status(), 200, "abbreviation removed");
+        assert_eq!(remove().await.status(), 404, "abbreviation gone");
+        assert_eq!(expand("brb").await, "brb", "no longer expanded");
+    }
+}
@@ -0,0 +1,225 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{app, auth, db, tile, Config, JWTConfig};
+
+mod common;
+
+/// Encodes `seconds` of silence as an 8 kHz, 8 bit mono WAV recording.
+fn silence(seconds: u32) -> Vec<u8> {
+    let samples = 8000 * seconds;
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + samples).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&8000u32.to_le_bytes());
+    wav.extend_from_slice(&8000u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&8u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&samples.to_le_bytes());
+    wav.resize(wav.len() + samples as usize, 0x80);
+    wav
+}
+
+/// Builds a multipart tile form holding only `audio`, returning its content type and body.
+fn audio_form(audio: &[u8], audio_type: &str) -> (String, Vec<u8>) {
+    let boundary = "------------------------audioform";
+    let mut body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"audio\"; filename=\"audio\"\r\n\
+         Content-Type: {}\r\n\r\n",
+        audio_type,
+        b = boundary,
+    )
+    .into_bytes();
+    body.extend_from_slice(audio);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+async fn recording_refs(conn: &db::Conn, hash: &str) -> Option<i32> {
+    conn.query_opt("SELECT ref_count FROM recordings WHERE hash = $1", &[&hash])
+        .await
+        .unwrap()
+        .map(|row| row.get(0))
+}
+
+#[tokio::test]
+async fn audio_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "audio_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let update = |form: (String, Vec<u8>)| {
+        let api = api.clone();
+        let token = token.clone();
+        async move {
+            warp::test::request()
+                .method("PATCH")
+                .path("/api/user/audio_flow/tiles/hello")
+                .header("Content-Type", form.0)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(form.1)
+                .reply(&api)
+                .await
+        }
+    };
+
+    {
+        // Create a tile and give it a recording.
+        let (content_type, body) = common::tile_form(
+            "hello",
+            &["greetings"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/audio_flow/tiles")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+        let tile = serde_json::from_slice::<tile::Tile>(res.body()).unwrap();
+        assert_eq!(tile.audio, None, "tile starts without a recording");
+    }
+
+    let recording = silence(1);
+    let tile = {
+        let res = update(audio_form(&recording, "audio/x-wav")).await;
+        assert_eq!(res.status(), 200, "recording accepted");
+        serde_json::from_slice::<tile::Tile>(res.body()).unwrap()
+    };
+    let path = tile.audio.expect("tile has a recording");
+    let hash = path.rsplit('/').next().unwrap().to_string();
+    let conn = db::get_db_conn(&pool).await.unwrap();
+    assert_eq!(
+        recording_refs(&conn, &hash).await,
+        Some(1),
+        "recording counted"
+    );
+
+    {
+        // Test serving the recording.
+        let res = warp::test::request()
+            .method("GET")
+            .path(path.as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "recording ok");
+        assert_eq!(res.headers()["Content-Type"], "audio/wav", "type sniffed");
+        assert_eq!(
+            res.body().as_ref(),
+            recording.as_slice(),
+            "recording intact"
+        );
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(path.as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Range", "bytes=0-3")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 206, "recording can be sought");
+        assert_eq!(res.body().as_ref(), b"RIFF", "range served");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(path.as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404, "recording is private");
+    }
+
+    {
+        // Test validation.
+        let res = update(audio_form(&silence(61), "audio/wav")).await;
+        assert_eq!(res.status(), 413, "long recording rejected");
+        assert!(
+            String::from_utf8_lossy(res.body()).contains("at most 60 seconds"),
+            "limit explained"
+        );
+
+        let res = update(audio_form(&recording, "audio/mpeg")).await;
+        assert_eq!(res.status(), 400, "mismatched type rejected");
+
+        let res = update(audio_form(b"not a recording", "audio/wav")).await;
+        assert_eq!(res.status(), 400, "garbage rejected");
+    }
+
+    {
+        // Test removing the recording, which leaves recordings it did not release alone.
+        conn.execute(
+            "INSERT INTO recordings (hash, content_type) VALUES ('unreleased', 'audio/wav')",
+            &[],
+        )
+        .await
+        .unwrap();
+        let res = update(audio_form(b"", "audio/wav")).await;
+        assert_eq!(res.status(), 200, "recording removed");
+        let tile = serde_json::from_slice::<tile::Tile>(res.body()).unwrap();
+        assert_eq!(tile.audio, None, "tile has no recording");
+        assert_eq!(
+            recording_refs(&conn, &hash).await,
+            None,
+            "recording collected"
+        );
+        assert_eq!(
+            recording_refs(&conn, "unreleased").await,
+            Some(0),
+            "only released recordings collected"
+        );
+        conn.execute("DELETE FROM recordings WHERE hash = 'unreleased'", &[])
+            .await
+            .unwrap();
+    }
+}
@@ -16,7 +16,7 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use open_comm::{app, auth, user, JWTConfig};
+use open_comm::{app, auth, user, Config, JWTConfig};
 
 mod common;
 
@@ -24,7 +24,10 @@ mod common;
 async fn auth_flow() {
     let api = app(
         common::db_pool().await,
-        Some(JWTConfig::Secret(common::secret())),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
     )
     .await
     .expect("app initialized");
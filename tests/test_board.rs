@@ -0,0 +1,247 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    board::Board,
+    tile::{Tile, TilePage},
+    usage::Activation,
+    Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn board_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "board_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+    for (phrase, category) in [("apple", "food"), ("juice", "drinks"), ("ball", "toys")].iter() {
+        let (content_type, body) = common::tile_form(
+            phrase,
+            &[category],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = request("POST", "/api/user/board_flow/tiles")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+    // Boards are read from the URL they are listed with, whichever kind they are.
+    let board_url = |name: &'static str, generated: bool| {
+        let res = request("GET", "/api/user/board_flow/boards").reply(&api);
+        async move {
+            serde_json::from_slice::<Vec<Board>>(res.await.body())
+                .unwrap()
+                .into_iter()
+                .find(|board| board.name == name && board.generated == generated)
+                .expect("board listed")
+                .url
+        }
+    };
+    let board = |name: &'static str, generated: bool| {
+        let (api, request, board_url) = (&api, &request, &board_url);
+        async move {
+            let res = request("GET", &board_url(name, generated).await)
+                .reply(api)
+                .await;
+            assert_eq!(res.status(), 200, "board read");
+            serde_json::from_slice::<TilePage>(res.body())
+                .unwrap()
+                .tiles
+                .into_iter()
+                .map(|tile| tile.phrase)
+                .collect::<Vec<String>>()
+        }
+    };
+    let activate = |phrase: &str| {
+        request("POST", "/api/user/board_flow/usage")
+            .json(&Activation {
+                phrase: phrase.to_string(),
+                board: None,
+                device: None,
+            })
+            .reply(&api)
+    };
+
+    {
+        // Test listing boards.
+        let res = request("GET", "/api/user/board_flow/boards")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "boards listed");
+        let boards = serde_json::from_slice::<Vec<Board>>(res.body()).unwrap();
+        let names = boards
+            .iter()
+            .map(|board| (board.name.as_str(), board.generated, board.total))
+            .collect::<Vec<(&str, bool, i64)>>();
+        assert_eq!(
+            names,
+            vec![
+                ("favorites", true, 0),
+                ("recent", true, 0),
+                ("frequent", true, 0),
+                ("drinks", false, 1),
+                ("food", false, 1),
+                ("toys", false, 1),
+            ]
+        );
+        assert_eq!(board("food", false).await, vec!["apple"]);
+    }
+
+    {
+        // Test favorites.
+        for phrase in &["juice", "apple", "juice"] {
+            let res = request(
+                "PUT",
+                &format!("/api/user/board_flow/tiles/{}/favorite", phrase),
+            )
+            .reply(&api)
+            .await;
+            assert_eq!(res.status(), 200, "tile favorited");
+        }
+        let res = request("PUT", "/api/user/board_flow/tiles/nothing/favorite")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404, "unknown tile");
+        assert_eq!(board("favorites", true).await, vec!["juice", "apple"]);
+
+        let res = request("GET", "/api/user/board_flow/tiles?category=drinks")
+            .reply(&api)
+            .await;
+        let page = serde_json::from_slice::<TilePage>(res.body()).unwrap();
+        assert!(page.tiles.iter().all(|tile: &Tile| tile.favorite));
+
+        let res = request("DELETE", "/api/user/board_flow/tiles/juice/favorite")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile unfavorited");
+        assert_eq!(board("favorites", true).await, vec!["apple"]);
+    }
+
+    {
+        // Test boards built from usage.
+        for phrase in &["apple", "juice", "apple", "ball"] {
+            assert_eq!(activate(phrase).await.status(), 200, "tile activated");
+        }
+        assert_eq!(board("recent", true).await, vec!["ball", "apple", "juice"]);
+        assert_eq!(
+            board("frequent", true).await,
+            vec!["apple", "ball", "juice"]
+        );
+
+        let url = board_url("recent", true).await;
+        let res = request("GET", &format!("{}?limit=1", url))
+            .reply(&api)
+            .await;
+        let page = serde_json::from_slice::<TilePage>(res.body()).unwrap();
+        assert_eq!(page.tiles.len(), 1);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_cursor, None);
+        let res = request("GET", &format!("{}?sort=alphabetical", url))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "generated boards keep their order");
+
+        let res = request("DELETE", "/api/user/board_flow/tiles/ball")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile deleted");
+        assert_eq!(board("recent", true).await, vec!["apple", "juice"]);
+
+        let res = request("DELETE", "/api/user/board_flow/usage")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "usage erased");
+        assert!(board("recent", true).await.is_empty());
+        assert!(board("frequent", true).await.is_empty());
+    }
+
+    {
+        // Test categories named like generated boards.
+        let (content_type, body) = common::tile_form(
+            "cake",
+            &["recent"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = request("POST", "/api/user/board_flow/tiles")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+        let res = request("GET", "/api/user/board_flow/boards")
+            .reply(&api)
+            .await;
+        let boards = serde_json::from_slice::<Vec<Board>>(res.body()).unwrap();
+        assert!(
+            boards.contains(&Board {
+                name: "recent".to_string(),
+                url: "/api/user/board_flow/boards/recent".to_string(),
+                generated: false,
+                total: 1,
+            }),
+            "category listed beside the generated board"
+        );
+        assert_eq!(board("recent", false).await, vec!["cake"]);
+        assert!(board("recent", true).await.is_empty());
+
+        let res = request("GET", "/api/user/board_flow/boards/generated/nothing")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404, "unknown generated board");
+    }
+}
@@ -0,0 +1,220 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    copy::{ConflictStrategy, CopyReport, CopyRequest},
+    Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn copy_flow() {
+    let api = app(
+        common::db_pool().await,
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a therapist, a child and a stranger.
+    let mut tokens = Vec::new();
+    for username in &["copy_therapist", "copy_child", "copy_stranger"] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: username.to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        tokens.push(
+            serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+                .unwrap()
+                .token,
+        );
+    }
+    let (therapist, child, stranger) = (tokens[0].clone(), tokens[1].clone(), tokens[2].clone());
+
+    // The therapist builds a food board.
+    for (boundary, body) in &[
+        (
+            "------------------------0af30d233b54bac0",
+            &include_bytes!("tile_create.bin")[..],
+        ),
+        (
+            "------------------------0b56506eb827d2ac",
+            &include_bytes!("tile_create2.bin")[..],
+        ),
+    ] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/copy_therapist/tiles")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .header("Authorization", format!("Bearer {}", therapist))
+            .body(*body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+
+    let copy = |token: String, from: &'static str, on_conflict: Option<ConflictStrategy>| {
+        let api = api.clone();
+        async move {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/api/user/copy_child/copy")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&CopyRequest {
+                    from: from.to_string(),
+                    tile: None,
+                    category: Some("food".to_string()),
+                    on_conflict,
+                })
+                .reply(&api)
+                .await;
+            let report = serde_json::from_slice::<CopyReport>(res.body()).ok();
+            (res.status(), report)
+        }
+    };
+
+    {
+        // Test copying requires permission.
+        let (status, _) = copy(therapist.clone(), "copy_therapist", None).await;
+        assert_eq!(status, 401, "only caregivers may copy into an account");
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/user/copy_child/caregivers/copy_therapist")
+            .header("Authorization", format!("Bearer {}", child))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "caregiver added");
+
+        let (status, _) = copy(stranger.clone(), "copy_therapist", None).await;
+        assert_eq!(status, 401, "strangers may not copy");
+    }
+
+    {
+        // Test copying a board.
+        let (status, report) = copy(therapist.clone(), "copy_therapist", None).await;
+        assert_eq!(status, 200, "board copied");
+        let report = report.unwrap();
+        assert_eq!(
+            report
+                .copied
+                .iter()
+                .map(|t| t.phrase.as_str())
+                .collect::<Vec<_>>(),
+            vec!["pizza", "spinach"],
+            "board tiles copied in order"
+        );
+    }
+
+    {
+        // Test name collisions.
+        let (status, report) = copy(therapist.clone(), "copy_therapist", None).await;
+        assert_eq!(status, 409, "collisions fail by default");
+        let report = report.unwrap();
+        assert_eq!(report.collisions, vec!["pizza", "spinach"]);
+        assert!(report.copied.is_empty(), "failed copies copy nothing");
+
+        let (status, report) = copy(
+            therapist.clone(),
+            "copy_therapist",
+            Some(ConflictStrategy::Skip),
+        )
+        .await;
+        assert_eq!(status, 200, "collisions may be skipped");
+        assert_eq!(report.unwrap().skipped, vec!["pizza", "spinach"]);
+
+        let (status, report) = copy(
+            therapist.clone(),
+            "copy_therapist",
+            Some(ConflictStrategy::Rename),
+        )
+        .await;
+        assert_eq!(status, 200, "collisions may be renamed");
+        let report = report.unwrap();
+        assert_eq!(report.renamed[0].to, "pizza (2)");
+        assert_eq!(report.copied[1].phrase, "spinach (2)");
+    }
+
+    {
+        // Test copied templates need tiles to fill their slots.
+        let drink = common::tile_form(
+            "juice",
+            &["copy_drinks"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let template = common::template_form(
+            "thirsty",
+            "copy_drinks",
+            "I want {drink}",
+            r#"{"drink": "copy_drinks"}"#,
+        );
+        for (content_type, body) in [drink, template] {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/api/user/copy_child/tiles")
+                .header("Content-Type", content_type)
+                .header("Authorization", format!("Bearer {}", child))
+                .body(body)
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 201, "new tile created new resource");
+        }
+
+        let copy_drinks = |tile: Option<&str>| {
+            warp::test::request()
+                .method("POST")
+                .path("/api/user/copy_therapist/copy")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", therapist))
+                .json(&CopyRequest {
+                    from: "copy_child".to_string(),
+                    tile: tile.map(str::to_string),
+                    category: Some("copy_drinks".to_string()),
+                    on_conflict: None,
+                })
+                .reply(&api)
+        };
+        let res = copy_drinks(Some("thirsty")).await;
+        assert_eq!(
+            res.status(),
+            400,
+            "template without its slot's tiles refused"
+        );
+        let res = copy_drinks(None).await;
+        assert_eq!(res.status(), 200, "template copied with its slot's tiles");
+    }
+}
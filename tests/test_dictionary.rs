@@ -0,0 +1,191 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    dictionary::{Completion, Word},
+    message::AppendTile,
+    Config, JWTConfig,
+};
+mod common;
+
+#[tokio::test]
+async fn dictionary_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "dictionary_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+
+    let res = request("PATCH", "/api/user/dictionary_flow/preferences")
+        .json(&serde_json::json!({"keep_history": true}))
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), 200, "history kept");
+    let complete = |query: &str| {
+        request(
+            "GET",
+            &format!("/api/user/dictionary_flow/dictionary?{}", query),
+        )
+        .reply(&api)
+    };
+    let words = |body: &[u8]| {
+        serde_json::from_slice::<Vec<Completion>>(body)
+            .unwrap()
+            .into_iter()
+            .map(|completion| completion.word)
+            .collect::<Vec<String>>()
+    };
+
+    {
+        // Test completing from the bundled word lists.
+        let res = complete("prefix=hel").await;
+        assert_eq!(res.status(), 200, "completions ok");
+        let completions = words(res.body());
+        assert!(
+            completions.contains(&"hello".to_string()),
+            "hello completed"
+        );
+        assert!(completions.contains(&"help".to_string()), "help completed");
+        assert!(completions.iter().all(|word| word.starts_with("hel")));
+
+        let res = complete("prefix=th&limit=3").await;
+        assert_eq!(words(res.body()), ["the", "that", "this"], "common first");
+
+        let res = complete("prefix=gra&lang=es").await;
+        assert_eq!(words(res.body())[0], "gracias", "other languages bundled");
+
+        let res = complete("prefix=hel&lang=xx").await;
+        assert_eq!(res.status(), 400, "unknown languages rejected");
+        let res = complete("prefix=").await;
+        assert_eq!(res.status(), 400, "empty prefix rejected");
+    }
+
+    {
+        // Test personal words.
+        let add = |word: &str| {
+            request("POST", "/api/user/dictionary_flow/dictionary/words")
+                .json(&Word {
+                    word: word.to_string(),
+                })
+                .reply(&api)
+        };
+        let res = add("Bella").await;
+        assert_eq!(res.status(), 201, "personal word added");
+        let res = add("bella").await;
+        assert_eq!(res.status(), 200, "personal word respelled");
+        let res = add("Bella").await;
+        assert_eq!(res.status(), 200, "personal word respelled");
+        let res = add("two words").await;
+        assert_eq!(res.status(), 400, "single words only");
+
+        let res = request("GET", "/api/user/dictionary_flow/dictionary/words")
+            .reply(&api)
+            .await;
+        let list = serde_json::from_slice::<Vec<Word>>(res.body()).unwrap();
+        assert_eq!(
+            list,
+            [Word {
+                word: "Bella".to_string()
+            }]
+        );
+
+        let res = complete("prefix=Be").await;
+        let completions = serde_json::from_slice::<Vec<Completion>>(res.body()).unwrap();
+        assert_eq!(completions[0].word, "Bella", "personal words first");
+        assert!(completions[0].personal);
+    }
+
+    {
+        // Test ranking the words the user says ahead of the rest.
+        let (content_type, body) = common::tile_form(
+            "better",
+            &["core"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = request("POST", "/api/user/dictionary_flow/tiles")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+        for _ in 0..2 {
+            request("POST", "/api/user/dictionary_flow/message/tablet/tiles")
+                .json(&AppendTile {
+                    phrase: "better".to_string(),
+                    board: None,
+                })
+                .reply(&api)
+                .await;
+            let res = request("POST", "/api/user/dictionary_flow/message/tablet/speak")
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 201, "message spoken");
+        }
+        let res = complete("prefix=be").await;
+        assert_eq!(words(res.body())[..2], ["better", "Bella"], "usage first");
+
+        let res = request(
+            "DELETE",
+            "/api/user/dictionary_flow/dictionary/words?word=bella",
+        )
+        .reply(&api)
+        .await;
+        assert_eq!(res.status(), 200, "personal word deleted");
+        let res = request(
+            "DELETE",
+            "/api/user/dictionary_flow/dictionary/words?word=bella",
+        )
+        .reply(&api)
+        .await;
+        assert_eq!(res.status(), 404, "personal word gone");
+    }
+}
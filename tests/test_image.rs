@@ -0,0 +1,337 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::{GenericImageView, ImageFormat};
+use open_comm::{app, auth, db, tile, Config, JWTConfig};
+
+mod common;
+
+async fn variant_count(conn: &db::Conn, hash: &str) -> i64 {
+    conn.query_one(
+        "SELECT COUNT(*) FROM image_variants WHERE source_hash = $1",
+        &[&hash],
+    )
+    .await
+    .unwrap()
+    .get(0)
+}
+
+#[tokio::test]
+async fn image_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "image_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let photo = {
+        let (content_type, body) = common::tile_form(
+            "photo",
+            &["pictures"],
+            &common::blank_image(600, 300, ImageFormat::Jpeg),
+            "image/jpeg",
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/image_flow/tiles")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+        serde_json::from_slice::<tile::Tile>(res.body()).unwrap()
+    };
+    assert_eq!(
+        photo.thumbnails.keys().copied().collect::<Vec<u32>>(),
+        vec![128, 256, 512],
+        "tile advertises its thumbnails"
+    );
+
+    let fetch = |path: String| {
+        let api = api.clone();
+        let token = token.clone();
+        async move {
+            warp::test::request()
+                .method("GET")
+                .path(path.as_str())
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&api)
+                .await
+        }
+    };
+
+    {
+        // Test thumbnails.
+        let res = fetch(photo.thumbnails[&128].clone()).await;
+        assert_eq!(res.status(), 200, "thumbnail ok");
+        assert_eq!(
+            res.headers()["Content-Type"],
+            "image/png",
+            "thumbnail is PNG"
+        );
+        let thumbnail = image::load_from_memory(res.body()).unwrap();
+        assert_eq!(
+            thumbnail.dimensions(),
+            (128, 64),
+            "thumbnail fits the size and keeps its aspect ratio"
+        );
+
+        let res = fetch(format!("{}&format=webp", photo.thumbnails[&256])).await;
+        assert_eq!(res.status(), 200, "converted thumbnail ok");
+        assert_eq!(
+            res.headers()["Content-Type"],
+            "image/webp",
+            "thumbnail is WebP"
+        );
+        let thumbnail = image::load_from_memory(res.body()).unwrap();
+        assert_eq!(thumbnail.dimensions(), (256, 128), "thumbnail is scaled");
+
+        let res = fetch(photo.thumbnails[&512].clone()).await;
+        let thumbnail = image::load_from_memory(res.body()).unwrap();
+        assert_eq!(
+            thumbnail.dimensions(),
+            (512, 256),
+            "large thumbnail is scaled"
+        );
+
+        let res = fetch(format!("{}?size=100", photo.image)).await;
+        assert_eq!(res.status(), 400, "unknown size is rejected");
+
+        let res = fetch(photo.image.clone()).await;
+        assert_eq!(
+            res.headers()["Content-Type"],
+            "image/jpeg",
+            "original is kept"
+        );
+    }
+
+    {
+        // Test caching.
+        let get = |headers: Vec<(&'static str, String)>| {
+            let api = api.clone();
+            let token = token.clone();
+            let path = photo.image.clone();
+            async move {
+                let mut req = warp::test::request()
+                    .method("GET")
+                    .path(path.as_str())
+                    .header("Authorization", format!("Bearer {}", token));
+                for (name, value) in headers {
+                    req = req.header(name, value);
+                }
+                req.reply(&api).await
+            }
+        };
+        let hash = photo.image.trim_start_matches("/api/image/");
+        let res = get(vec![]).await;
+        let etag = format!("\"{}\"", hash);
+        let len = res.body().len();
+        assert_eq!(res.headers()["ETag"], etag.as_str(), "image tagged by hash");
+        assert!(
+            res.headers()["Cache-Control"]
+                .to_str()
+                .unwrap()
+                .contains("immutable"),
+            "image cached for good"
+        );
+
+        let res = get(vec![("If-None-Match", etag.clone())]).await;
+        assert_eq!(res.status(), 304, "known image not sent again");
+        assert!(res.body().is_empty(), "not modified has no body");
+
+        let res = get(vec![("If-None-Match", "\"other\"".to_string())]).await;
+        assert_eq!(res.status(), 200, "changed image sent again");
+
+        let res = get(vec![("Range", "bytes=0-9".to_string())]).await;
+        assert_eq!(res.status(), 206, "range served");
+        assert_eq!(res.body().len(), 10, "range has the asked for length");
+        assert_eq!(
+            res.headers()["Content-Range"],
+            format!("bytes 0-9/{}", len).as_str(),
+            "range is described"
+        );
+
+        let res = get(vec![("Range", "bytes=-5".to_string())]).await;
+        assert_eq!(res.status(), 206, "suffix range served");
+        assert_eq!(res.body().len(), 5, "suffix range has the asked for length");
+
+        let res = get(vec![("Range", format!("bytes={}-", len))]).await;
+        assert_eq!(res.status(), 416, "range past the end is refused");
+
+        let res = get(vec![
+            ("Range", "bytes=0-9".to_string()),
+            ("If-Range", "\"other\"".to_string()),
+        ])
+        .await;
+        assert_eq!(res.status(), 200, "stale range gets the whole image");
+        assert_eq!(res.body().len(), len, "whole image sent");
+
+        let res = fetch(photo.thumbnails[&128].clone()).await;
+        assert_ne!(
+            res.headers()["ETag"],
+            etag.as_str(),
+            "thumbnail tagged apart from its original"
+        );
+    }
+
+    {
+        // Test variants are cached and collected with their image.
+        let conn = db::get_db_conn(&pool).await.unwrap();
+        let hash = photo.image.trim_start_matches("/api/image/").to_owned();
+        fetch(photo.thumbnails[&128].clone()).await;
+        assert_eq!(
+            variant_count(&conn, &hash).await,
+            3,
+            "variants are rendered once"
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/user/image_flow/tiles/photo")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile delete ok");
+        assert_eq!(
+            variant_count(&conn, &hash).await,
+            0,
+            "variants are collected"
+        );
+    }
+
+    {
+        // Test uploads are checked by their content.
+        let upload = |image: Vec<u8>, image_type: &'static str| {
+            let api = api.clone();
+            let token = token.clone();
+            async move {
+                let (content_type, body) =
+                    common::tile_form("upload", &["pictures"], &image, image_type);
+                warp::test::request()
+                    .method("POST")
+                    .path("/api/user/image_flow/tiles")
+                    .header("Content-Type", content_type)
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(body)
+                    .reply(&api)
+                    .await
+            }
+        };
+        let res = upload(
+            b"<html><script>alert(1)</script></html>".to_vec(),
+            "image/svg+xml",
+        )
+        .await;
+        assert_eq!(res.status(), 400, "markup that isn't SVG is rejected");
+        assert_eq!(
+            res.body(),
+            "image is not a PNG, JPEG, GIF, WebP or SVG image",
+            "rejection explains itself"
+        );
+
+        let res = upload(common::blank_image(10, 10, ImageFormat::Png), "image/jpeg").await;
+        assert_eq!(res.status(), 400, "mismatched type is rejected");
+        assert_eq!(
+            res.body(),
+            "image was declared as image/jpeg but its content is image/png",
+            "rejection explains itself"
+        );
+
+        let res = upload(common::blank_image(9000, 1, ImageFormat::Png), "image/png").await;
+        assert_eq!(res.status(), 413, "huge image is rejected");
+
+        let res = upload(vec![0; 11 * 1024 * 1024], "image/png").await;
+        assert_eq!(res.status(), 413, "huge upload is rejected");
+
+        let res = upload(common::blank_image(10, 10, ImageFormat::Gif), "image/gif").await;
+        assert_eq!(res.status(), 201, "valid image is accepted");
+    }
+
+    {
+        // Test vector images are served as they are.
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/image_flow/tiles")
+            .header(
+                "Content-Type",
+                "multipart/form-data; boundary=------------------------0af30d233b54bac0",
+            )
+            .header("Authorization", format!("Bearer {}", token))
+            .body(&include_bytes!("tile_create.bin")[..])
+            .reply(&api)
+            .await;
+        let tile = serde_json::from_slice::<tile::Tile>(res.body()).unwrap();
+        let res = fetch(tile.thumbnails[&128].clone()).await;
+        assert_eq!(res.status(), 200, "vector thumbnail ok");
+        assert_eq!(
+            res.headers()["Content-Type"],
+            "image/svg+xml",
+            "vector image is served as SVG"
+        );
+        assert!(
+            res.headers()["Content-Security-Policy"]
+                .to_str()
+                .unwrap()
+                .starts_with("default-src 'none'"),
+            "vector image is served with a restrictive policy"
+        );
+        let body = String::from_utf8_lossy(res.body());
+        assert!(
+            body.contains("<path") && !body.contains("foreignObject"),
+            "vector image is sanitized"
+        );
+
+        let res = fetch(format!("{}?format=png", tile.image)).await;
+        assert_eq!(
+            res.headers()["Content-Type"],
+            "image/png",
+            "vector image is drawn"
+        );
+        let drawn = image::load_from_memory(res.body()).unwrap();
+        assert_eq!(
+            drawn.width().max(drawn.height()),
+            512,
+            "vector image is drawn at a default size"
+        );
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    lexicon::{Preview, Pronunciation},
+    tile, Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn lexicon_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "lexicon_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let set = |word: &str, replacement: &str| {
+        let api = api.clone();
+        let token = token.clone();
+        let pronunciation = Pronunciation {
+            word: word.to_string(),
+            replacement: replacement.to_string(),
+        };
+        async move {
+            warp::test::request()
+                .method("POST")
+                .path("/api/user/lexicon_flow/pronunciations")
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&pronunciation)
+                .reply(&api)
+                .await
+                .status()
+        }
+    };
+    // Takes the text already encoded for the query.
+    let preview = |text: &str| {
+        let api = api.clone();
+        let token = token.clone();
+        let path = format!(
+            "/api/user/lexicon_flow/pronunciations/preview?text={}",
+            text
+        );
+        async move {
+            let res = warp::test::request()
+                .method("GET")
+                .path(path.as_str())
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200, "preview ok");
+            serde_json::from_slice::<Preview>(res.body())
+                .unwrap()
+                .spoken
+        }
+    };
+
+    {
+        // Test adding pronunciations.
+        assert_eq!(set("Siobhan", "Shivawn").await, 201, "pronunciation added");
+        assert_eq!(
+            set("siobhan", "Shi-vawn").await,
+            200,
+            "pronunciation replaced"
+        );
+        assert_eq!(set("New York", "Noo York").await, 201, "phrase added");
+        assert_eq!(set("!!", "bang").await, 400, "word required");
+        assert_eq!(set("Rex", " ").await, 400, "replacement required");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/lexicon_flow/pronunciations")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "pronunciations listed");
+        assert_eq!(
+            serde_json::from_slice::<Vec<Pronunciation>>(res.body()).unwrap(),
+            vec![
+                Pronunciation {
+                    word: "New York".to_string(),
+                    replacement: "Noo York".to_string(),
+                },
+                Pronunciation {
+                    word: "siobhan".to_string(),
+                    replacement: "Shi-vawn".to_string(),
+                },
+            ],
+            "one pronunciation per word"
+        );
+    }
+
+    {
+        // Test applying pronunciations.
+        assert_eq!(
+            preview("SIOBHAN%27s%20trip%20to%20new%20%20york.").await,
+            "Shi-vawn's trip to Noo York.",
+            "whole words replaced regardless of case"
+        );
+        assert_eq!(
+            preview("Siobhanna%20went%20to%20York").await,
+            "Siobhanna went to York",
+            "parts of words and phrases kept"
+        );
+
+        let (content_type, body) = common::tile_form(
+            "Siobhan",
+            &["people"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/lexicon_flow/tiles")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+        let tile = serde_json::from_slice::<tile::Tile>(res.body()).unwrap();
+        assert_eq!(tile.spoken, "Shi-vawn", "created tile pronounced");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/lexicon_flow/tiles")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        let page = serde_json::from_slice::<tile::TilePage>(res.body()).unwrap();
+        assert_eq!(page.tiles[0].phrase, "Siobhan", "phrase kept");
+        assert_eq!(page.tiles[0].spoken, "Shi-vawn", "listed tile pronounced");
+    }
+
+    {
+        // Test removing pronunciations.
+        let remove = || {
+            warp::test::request()
+                .method("DELETE")
+                .path("/api/user/lexicon_flow/pronunciations?word=SIOBHAN")
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&api)
+        };
+        assert_eq!(remove().await.status(), 200, "pronunciation removed");
+        assert_eq!(remove().await.status(), 404, "pronunciation gone");
+        assert_eq!(preview("Siobhan").await, "Siobhan", "no longer applied");
+    }
+}
@@ -0,0 +1,324 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{app, auth, db, tile, usage::Activation, Config, JWTConfig};
+
+mod common;
+
+#[tokio::test]
+async fn library_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register an administrator and a regular user.
+    let mut tokens = Vec::new();
+    for username in &["library_admin", "library_user"] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: username.to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        tokens.push(
+            serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+                .unwrap()
+                .token,
+        );
+    }
+    let (admin_token, user_token) = (tokens[0].clone(), tokens[1].clone());
+
+    {
+        // Test publishing requires an administrator.
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/library/tiles")
+            .header(
+                "Content-Type",
+                "multipart/form-data; boundary=------------------------0af30d233b54bac0",
+            )
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(include_bytes!("tile_create.bin"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401, "only administrators may publish");
+    }
+
+    db::get_db_conn(&pool)
+        .await
+        .unwrap()
+        .execute(
+            "UPDATE users SET is_admin = TRUE WHERE username = 'library_admin'",
+            &[],
+        )
+        .await
+        .unwrap();
+
+    let image = {
+        // Test publishing a library tile.
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/library/tiles")
+            .header(
+                "Content-Type",
+                "multipart/form-data; boundary=------------------------0af30d233b54bac0",
+            )
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .body(include_bytes!("tile_create.bin"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "library tile created");
+        let body = String::from_utf8_lossy(res.body());
+        let tile = serde_json::from_str::<tile::Tile>(body.as_ref()).unwrap();
+        assert!(tile.library, "published tile belongs to the library");
+        tile.image
+    };
+
+    {
+        // Test anonymous access to the library.
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/tiles")
+            .header("Accept", "application/json")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "anonymous library query ok");
+        let body = String::from_utf8_lossy(res.body());
+        let page = serde_json::from_str::<tile::TilePage>(body.as_ref()).unwrap();
+        assert_eq!(page.tiles[0].phrase, "pizza", "library tile is listed");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(image.as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "anonymous library image ok");
+    }
+
+    let list_user_tiles = || {
+        let api = api.clone();
+        let token = user_token.clone();
+        async move {
+            let res = warp::test::request()
+                .method("GET")
+                .path("/api/user/library_user/tiles")
+                .header("Accept", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200, "tile query ok");
+            let body = String::from_utf8_lossy(res.body());
+            serde_json::from_str::<tile::TilePage>(body.as_ref())
+                .unwrap()
+                .tiles
+        }
+    };
+
+    {
+        // Test hiding a library tile.
+        assert_eq!(list_user_tiles().await.len(), 1, "users see library tiles");
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/user/library_user/library/pizza/hidden")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "library tile hidden");
+        assert!(
+            list_user_tiles().await.is_empty(),
+            "hidden tiles are not listed"
+        );
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/library_user/library/hidden")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "hidden tile query ok");
+        let body = String::from_utf8_lossy(res.body());
+        let hidden = serde_json::from_str::<Vec<tile::Tile>>(body.as_ref()).unwrap();
+        assert_eq!(hidden[0].phrase, "pizza", "hidden tiles are listed");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/user/library_user/library/pizza/hidden")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "library tile unhidden");
+        assert_eq!(
+            list_user_tiles().await.len(),
+            1,
+            "unhidden tiles are listed"
+        );
+    }
+
+    {
+        // Test library tiles count towards each user's use.
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/library_user/usage")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .json(&Activation {
+                phrase: "pizza".to_string(),
+                board: None,
+                device: None,
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "library tile activated");
+        let row = db::get_db_conn(&pool)
+            .await
+            .unwrap()
+            .query_one(
+                r#"
+                SELECT use_count FROM tile_uses JOIN users ON users.id = tile_uses.user_id
+                WHERE username = 'library_user'
+                "#,
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i32>(0), 1, "library tile use counted");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/library_user/tiles?sort=most_used")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "most used tile query ok");
+    }
+
+    {
+        // Test copying a library tile.
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/library_user/library/pizza/copy")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "library tile copied");
+        let body = String::from_utf8_lossy(res.body());
+        let tile = serde_json::from_str::<tile::Tile>(body.as_ref()).unwrap();
+        assert!(!tile.library, "copied tile belongs to the user");
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/library_user/library/pizza/copy")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 409, "repeat copy responds with conflict");
+        let body = String::from_utf8_lossy(res.body());
+        let tile = serde_json::from_str::<tile::Tile>(body.as_ref()).unwrap();
+        assert!(!tile.library, "conflict shows the user's own tile");
+    }
+
+    {
+        // Test removing a library tile.
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/library/tiles/pizza")
+            .header("Authorization", format!("Bearer {}", admin_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "library tile deleted");
+        let tiles = list_user_tiles().await;
+        assert_eq!(tiles.len(), 1, "copies outlive the library tile");
+        assert!(!tiles[0].library, "only the copy remains");
+    }
+
+    {
+        // Test copied templates need tiles to fill their slots.
+        let drink = common::tile_form(
+            "juice",
+            &["library_drinks"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let template = common::template_form(
+            "thirsty",
+            "library_drinks",
+            "I want {drink}",
+            r#"{"drink": "library_drinks"}"#,
+        );
+        for (content_type, body) in [drink, template] {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/api/library/tiles")
+                .header("Content-Type", content_type)
+                .header("Authorization", format!("Bearer {}", admin_token))
+                .body(body)
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 201, "library tile created");
+        }
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/user/library_user/library/juice/hidden")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "library tile hidden");
+        let copy_template = || {
+            warp::test::request()
+                .method("POST")
+                .path("/api/user/library_user/library/thirsty/copy")
+                .header("Authorization", format!("Bearer {}", user_token))
+                .reply(&api)
+        };
+        let res = copy_template().await;
+        assert_eq!(
+            res.status(),
+            400,
+            "template without its slot's tiles refused"
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/user/library_user/library/juice/hidden")
+            .header("Authorization", format!("Bearer {}", user_token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "library tile unhidden");
+        let res = copy_template().await;
+        assert_eq!(
+            res.status(),
+            201,
+            "template copied once its slot can be filled"
+        );
+    }
+}
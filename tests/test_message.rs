@@ -0,0 +1,205 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth, db,
+    message::{AppendTile, Message, Utterance},
+    Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn message_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "message_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let phrases = ["hello", ",", "my friend", "!", "how are you", "?"];
+    for phrase in phrases.iter() {
+        let (content_type, body) = common::tile_form(
+            phrase,
+            &["chat"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/message_flow/tiles")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+    let append = |phrase: &str| {
+        request("POST", "/api/user/message_flow/message/tablet-1/tiles")
+            .json(&AppendTile {
+                phrase: phrase.to_string(),
+                board: None,
+            })
+            .reply(&api)
+    };
+
+    {
+        // Test composing a message.
+        for phrase in phrases.iter() {
+            let res = append(phrase).await;
+            assert_eq!(res.status(), 200, "{} appended", phrase);
+        }
+        let res = append("goodbye").await;
+        assert_eq!(res.status(), 404, "unknown tile not appended");
+
+        let res = request("GET", "/api/user/message_flow/message/tablet-1")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "message ok");
+        let message = serde_json::from_slice::<Message>(res.body()).unwrap();
+        assert_eq!(message.parts.len(), 6, "message kept on the server");
+        assert_eq!(
+            message.text, "Hello, my friend! How are you?",
+            "spaced, punctuated and capitalized"
+        );
+
+        let res = request(
+            "DELETE",
+            "/api/user/message_flow/message/tablet-1/tiles/last",
+        )
+        .reply(&api)
+        .await;
+        assert_eq!(res.status(), 200, "last tile deleted");
+        let message = serde_json::from_slice::<Message>(res.body()).unwrap();
+        assert_eq!(message.text, "Hello, my friend! How are you");
+
+        let res = request("GET", "/api/user/message_flow/message/phone")
+            .reply(&api)
+            .await;
+        let message = serde_json::from_slice::<Message>(res.body()).unwrap();
+        assert!(message.parts.is_empty(), "each device has its own message");
+
+        let res = request("GET", "/api/user/message_flow/message/not.a.device")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "device names checked");
+    }
+
+    {
+        // Test speaking and clearing.
+        let res = request("POST", "/api/user/message_flow/message/tablet-1/speak")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "message spoken");
+        let message = serde_json::from_slice::<Message>(res.body()).unwrap();
+        assert_eq!(message.text, "Hello, my friend! How are you");
+
+        let res = request("GET", "/api/user/message_flow/message/tablet-1")
+            .reply(&api)
+            .await;
+        let message = serde_json::from_slice::<Message>(res.body()).unwrap();
+        assert!(message.parts.is_empty(), "spoken message cleared");
+
+        let res = request("POST", "/api/user/message_flow/message/tablet-1/speak")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "empty message not spoken");
+
+        let conn = db::get_db_conn(&pool).await.unwrap();
+        for table in &["utterances", "ngrams"] {
+            let row = conn
+                .query_one(
+                    format!(
+                        "SELECT COUNT(*) FROM {} WHERE user_id = (SELECT id FROM users WHERE username = $1)",
+                        table
+                    )
+                    .as_str(),
+                    &[&"message_flow"],
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                row.get::<_, i64>(0),
+                0,
+                "nothing kept in {} without opting in",
+                table
+            );
+        }
+
+        let res = request("PATCH", "/api/user/message_flow/preferences")
+            .json(&serde_json::json!({"keep_history": true}))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "history kept from now on");
+        for phrase in phrases.iter().take(4) {
+            append(phrase).await;
+        }
+        let res = request("POST", "/api/user/message_flow/message/tablet-1/speak")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "message spoken");
+
+        append("hello").await;
+        let res = request("DELETE", "/api/user/message_flow/message/tablet-1")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "message cleared");
+
+        let res = request("GET", "/api/user/message_flow/utterances")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "history ok");
+        let history = serde_json::from_slice::<Vec<Utterance>>(res.body()).unwrap();
+        assert_eq!(history.len(), 1, "only spoken messages logged");
+        assert_eq!(history[0].text, "Hello, my friend!");
+        assert_eq!(history[0].device, "tablet-1");
+    }
+}
@@ -0,0 +1,135 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::{GenericImageView, ImageFormat};
+use open_comm::metadata;
+
+fn blank_image(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+    let mut out = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(width, height)
+        .write_to(&mut out, format)
+        .unwrap();
+    out.into_inner()
+}
+
+/// An EXIF block with an orientation tag, a location-like string and the given orientation.
+fn exif(orientation: u8) -> Vec<u8> {
+    let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01".to_vec();
+    tiff.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+    tiff.extend_from_slice(&[0x00, orientation, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    tiff.extend_from_slice(b"GPS 51.5007N 0.1246W");
+    tiff
+}
+
+/// Puts an EXIF segment right after the start of a JPEG.
+fn jpeg_with_exif(jpeg: &[u8], orientation: u8) -> Vec<u8> {
+    let mut payload = b"Exif\x00\x00".to_vec();
+    payload.extend_from_slice(&exif(orientation));
+    let mut out = jpeg[..2].to_vec();
+    out.extend_from_slice(&[0xff, 0xe1]);
+    out.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&jpeg[2..]);
+    out
+}
+
+/// Puts an EXIF segment after the picture of a JPEG, and another after its end.
+fn jpeg_with_trailing_exif(jpeg: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0xff, 0xe1];
+    let mut payload = b"Exif\x00\x00".to_vec();
+    payload.extend_from_slice(&exif(1));
+    segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    segment.extend_from_slice(&payload);
+    let (picture, end) = jpeg.split_at(jpeg.len() - 2);
+    let mut out = picture.to_vec();
+    out.extend_from_slice(&segment);
+    out.extend_from_slice(end);
+    out.extend_from_slice(&segment);
+    out
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Puts a text chunk right after the header of a PNG.
+fn png_with_text(png: &[u8]) -> Vec<u8> {
+    let text = b"tEXtLocation\x00GPS 51.5007N 0.1246W";
+    let mut out = png[..33].to_vec();
+    out.extend_from_slice(&((text.len() - 4) as u32).to_be_bytes());
+    out.extend_from_slice(text);
+    out.extend_from_slice(&crc32(text).to_be_bytes());
+    out.extend_from_slice(&png[33..]);
+    out
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn metadata_strip() {
+    let jpeg = blank_image(20, 10, ImageFormat::Jpeg);
+
+    let tagged = jpeg_with_exif(&jpeg, 1);
+    assert!(contains(&tagged, b"GPS"), "test photo carries a location");
+    let stripped = metadata::strip(tagged, "image/jpeg").unwrap();
+    assert_eq!(stripped, jpeg, "EXIF is cut out of upright photos");
+
+    let tagged = jpeg_with_trailing_exif(&jpeg);
+    let stripped = metadata::strip(tagged, "image/jpeg").unwrap();
+    assert_eq!(stripped, jpeg, "EXIF is cut out after the picture too");
+
+    let turned = metadata::strip(jpeg_with_exif(&jpeg, 6), "image/jpeg").unwrap();
+    assert!(
+        !contains(&turned, b"GPS"),
+        "EXIF is dropped from turned photos"
+    );
+    assert_eq!(
+        image::load_from_memory(&turned).unwrap().dimensions(),
+        (10, 20),
+        "turned photos are rotated"
+    );
+
+    let png = blank_image(20, 10, ImageFormat::Png);
+    let tagged = png_with_text(&png);
+    assert!(
+        image::load_from_memory(&tagged).is_ok(),
+        "test image is valid"
+    );
+    let stripped = metadata::strip(tagged, "image/png").unwrap();
+    assert_eq!(stripped, png, "text is cut out of PNG images");
+
+    let gif = blank_image(20, 10, ImageFormat::Gif);
+    assert_eq!(
+        metadata::strip(gif.clone(), "image/gif").unwrap(),
+        gif,
+        "images without metadata are untouched"
+    );
+}
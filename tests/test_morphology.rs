@@ -0,0 +1,228 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    message::{AppendTile, Message},
+    morphology::{inflect, inflect_text, Inflection},
+    tile, Config, JWTConfig,
+};
+
+mod common;
+
+/// Builds a multipart tile form holding only `inflections`, returning its content type and body.
+fn inflections_form(inflections: &str) -> (String, Vec<u8>) {
+    let boundary = "------------------------inflectionsform";
+    let body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"inflections\"\r\n\r\n{}\r\n--{b}--\r\n",
+        inflections,
+        b = boundary,
+    )
+    .into_bytes();
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+#[test]
+fn morphology_inflect() {
+    let en = |word: &str, inflection| inflect("en", word, inflection).unwrap();
+    assert_eq!(en("dog", Inflection::Plural), "dogs");
+    assert_eq!(en("bus", Inflection::Plural), "buses");
+    assert_eq!(en("quiz", Inflection::Plural), "quizzes");
+    assert_eq!(en("buzz", Inflection::Plural), "buzzes");
+    assert_eq!(en("waltz", Inflection::Plural), "waltzes");
+    assert_eq!(en("baby", Inflection::Plural), "babies");
+    assert_eq!(en("toy", Inflection::Plural), "toys");
+    assert_eq!(en("child", Inflection::Plural), "children");
+    assert_eq!(en("want", Inflection::Past), "wanted");
+    assert_eq!(en("like", Inflection::Past), "liked");
+    assert_eq!(en("cry", Inflection::Past), "cried");
+    assert_eq!(en("stop", Inflection::Past), "stopped");
+    assert_eq!(en("open", Inflection::Past), "opened");
+    assert_eq!(en("go", Inflection::Past), "went");
+    assert_eq!(en("eat", Inflection::Progressive), "eating");
+    assert_eq!(en("make", Inflection::Progressive), "making");
+    assert_eq!(en("run", Inflection::Progressive), "running");
+    assert_eq!(en("lie", Inflection::Progressive), "lying");
+    assert_eq!(en("see", Inflection::Progressive), "seeing");
+    assert_eq!(en("mom", Inflection::Possessive), "mom's");
+    assert_eq!(en("james", Inflection::Possessive), "james'");
+
+    let es = |word: &str, inflection| inflect("es", word, inflection);
+    assert_eq!(es("casa", Inflection::Plural).unwrap(), "casas");
+    assert_eq!(es("árbol", Inflection::Plural).unwrap(), "árboles");
+    assert_eq!(es("lápiz", Inflection::Plural).unwrap(), "lápices");
+    assert_eq!(es("canción", Inflection::Plural).unwrap(), "canciones");
+    assert_eq!(es("ratón", Inflection::Plural).unwrap(), "ratones");
+    assert_eq!(es("autobús", Inflection::Plural).unwrap(), "autobuses");
+    assert_eq!(es("inglés", Inflection::Plural).unwrap(), "ingleses");
+    assert_eq!(es("capitán", Inflection::Plural).unwrap(), "capitanes");
+    assert_eq!(es("andén", Inflection::Plural).unwrap(), "andenes");
+    assert_eq!(es("país", Inflection::Plural).unwrap(), "países");
+    assert_eq!(es("mes", Inflection::Plural).unwrap(), "meses");
+    assert_eq!(es("lunes", Inflection::Plural).unwrap(), "lunes");
+    assert_eq!(es("tórax", Inflection::Plural).unwrap(), "tórax");
+    assert_eq!(es("hablar", Inflection::Progressive).unwrap(), "hablando");
+    assert_eq!(es("comer", Inflection::Progressive).unwrap(), "comiendo");
+    assert_eq!(es("leer", Inflection::Progressive).unwrap(), "leyendo");
+    assert_eq!(es("dormir", Inflection::Progressive).unwrap(), "durmiendo");
+    assert_eq!(
+        es("comer", Inflection::Past),
+        None,
+        "past depends on person"
+    );
+
+    assert_eq!(
+        inflect_text("en", "my Friend", Inflection::Plural),
+        "my Friends",
+        "last word inflected, keeping its capital"
+    );
+    assert_eq!(inflect_text("en", "?", Inflection::Plural), "?");
+    assert_eq!(inflect_text("xx", "dog", Inflection::Plural), "dog");
+}
+
+#[tokio::test]
+async fn morphology_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "morphology_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+    let phrases = [
+        "I", "go", "with", "Sam", "child", "cactus", ".", "+s", "+ed", "+ing", "+'s",
+    ];
+    for phrase in phrases.iter() {
+        let (content_type, body) = common::tile_form(
+            phrase,
+            &["core"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = request("POST", "/api/user/morphology_flow/tiles")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+    let compose = |device: &'static str, phrases: &'static [&'static str]| {
+        let request = &request;
+        let api = &api;
+        async move {
+            let mut message = None;
+            for phrase in phrases {
+                let res = request(
+                    "POST",
+                    &format!("/api/user/morphology_flow/message/{}/tiles", device),
+                )
+                .json(&AppendTile {
+                    phrase: phrase.to_string(),
+                    board: None,
+                })
+                .reply(api)
+                .await;
+                assert_eq!(res.status(), 200, "{} appended", phrase);
+                message = Some(serde_json::from_slice::<Message>(res.body()).unwrap());
+            }
+            message.unwrap().text
+        }
+    };
+
+    {
+        // Test applying modifiers to the tile before them.
+        assert_eq!(
+            compose(
+                "tablet",
+                &["+s", "I", "go", "+ed", "with", "Sam", "+'s", "child", "+s", "."]
+            )
+            .await,
+            "I went with Sam's children.",
+            "irregular forms used, leading modifier ignored"
+        );
+        assert_eq!(
+            compose("phone", &["cactus", "+s"]).await,
+            "Cactuses",
+            "regular rules by default"
+        );
+    }
+
+    {
+        // Test overriding a tile's forms.
+        let (content_type, body) = inflections_form(r#"{"plural": "cacti"}"#);
+        let res = request("PATCH", "/api/user/morphology_flow/tiles/cactus")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile updated");
+        let tile = serde_json::from_slice::<tile::Tile>(res.body()).unwrap();
+        assert_eq!(tile.inflections[&Inflection::Plural], "cacti");
+
+        let (content_type, body) = inflections_form(r#"{"plural": " "}"#);
+        let res = request("PATCH", "/api/user/morphology_flow/tiles/cactus")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "forms required");
+        let (content_type, body) = inflections_form(r#"{"dual": "cacti"}"#);
+        let res = request("PATCH", "/api/user/morphology_flow/tiles/cactus")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "unknown inflections rejected");
+
+        assert_eq!(
+            compose("laptop", &["cactus", "+s", "+'s"]).await,
+            "Cacti's",
+            "tile's form used, then the rules"
+        );
+    }
+}
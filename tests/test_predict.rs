@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{app, auth, db, message::AppendTile, predict::Prediction, Config, JWTConfig};
+
+mod common;
+
+#[tokio::test]
+async fn predict_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "predict_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let phrases = ["i", "want", "juice", "water", "go", "home"];
+    for phrase in phrases.iter() {
+        let (content_type, body) = common::tile_form(
+            phrase,
+            &["core"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/predict_flow/tiles")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+
+    let res = request("PATCH", "/api/user/predict_flow/preferences")
+        .json(&serde_json::json!({"keep_history": true}))
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), 200, "history kept");
+    let speak = |message: &'static [&'static str]| {
+        let request = &request;
+        let api = &api;
+        async move {
+            for phrase in message.iter() {
+                let res = request("POST", "/api/user/predict_flow/message/tablet/tiles")
+                    .json(&AppendTile {
+                        phrase: phrase.to_string(),
+                        board: None,
+                    })
+                    .reply(api)
+                    .await;
+                assert_eq!(res.status(), 200, "{} appended", phrase);
+            }
+            let res = request("POST", "/api/user/predict_flow/message/tablet/speak")
+                .reply(api)
+                .await;
+            assert_eq!(res.status(), 201, "message spoken");
+        }
+    };
+    let predict = |context: &str| {
+        request(
+            "GET",
+            &format!("/api/user/predict_flow/predict?context={}", context),
+        )
+        .reply(&api)
+    };
+    let first = |body: &[u8]| {
+        let predictions = serde_json::from_slice::<Vec<Prediction>>(body).unwrap();
+        predictions
+            .first()
+            .map(|prediction| prediction.tile.phrase.clone())
+            .unwrap_or_default()
+    };
+
+    {
+        // Test predicting from what was said.
+        speak(&["i", "want", "juice"]).await;
+        speak(&["i", "want", "juice"]).await;
+        speak(&["i", "want", "water"]).await;
+
+        let res = predict("i+want").await;
+        assert_eq!(res.status(), 200, "predictions ok");
+        let predictions = serde_json::from_slice::<Vec<Prediction>>(res.body()).unwrap();
+        assert_eq!(predictions[0].tile.phrase, "juice", "most likely first");
+        assert_eq!(predictions[1].tile.phrase, "water", "then less likely");
+        assert!(predictions[0].score > predictions[1].score);
+
+        let res = predict("").await;
+        assert_eq!(first(res.body()), "i", "messages start like before");
+
+        let res = request("GET", "/api/user/predict_flow/predict?limit=0")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "limit checked");
+    }
+
+    {
+        // Test the fallback model filling in for contexts the user has never said.
+        let res = predict("can+we+go").await;
+        assert_eq!(first(res.body()), "home", "everyday sentences used");
+    }
+
+    {
+        // Test learning from each message as it is spoken.
+        speak(&["i", "want", "water"]).await;
+        speak(&["i", "want", "water"]).await;
+        let res = predict("i+want").await;
+        assert_eq!(first(res.body()), "water", "model updated");
+    }
+
+    {
+        // Test a lost model is rebuilt from the whole history by the next message.
+        let conn = db::get_db_conn(&pool).await.unwrap();
+        for table in &["ngrams", "ngram_models"] {
+            conn.execute(
+                format!(
+                    "DELETE FROM {} WHERE user_id = (SELECT id FROM users WHERE username = $1)",
+                    table
+                )
+                .as_str(),
+                &[&"predict_flow"],
+            )
+            .await
+            .unwrap();
+        }
+        speak(&["go", "home"]).await;
+        let res = predict("i+want").await;
+        let predictions = serde_json::from_slice::<Vec<Prediction>>(res.body()).unwrap();
+        assert_eq!(
+            predictions
+                .iter()
+                .take(2)
+                .map(|prediction| prediction.tile.phrase.as_str())
+                .collect::<Vec<_>>(),
+            vec!["water", "juice"],
+            "history learned again"
+        );
+    }
+}
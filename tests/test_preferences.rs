@@ -0,0 +1,189 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use open_comm::{
+    app, auth, db,
+    preferences::{Grid, LabelPosition, Preferences},
+    user, Config, JWTConfig,
+};
+use serde_json::json;
+
+mod common;
+
+#[tokio::test]
+async fn preferences_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "preferences_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let send = |method: &str, body: serde_json::Value| {
+        let api = api.clone();
+        let token = token.clone();
+        let method = method.to_string();
+        async move {
+            warp::test::request()
+                .method(method.as_str())
+                .path("/api/user/preferences_flow/preferences")
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&body)
+                .reply(&api)
+                .await
+        }
+    };
+
+    {
+        // Test defaults.
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/preferences_flow/preferences")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "preferences ok");
+        assert_eq!(
+            serde_json::from_slice::<Preferences>(res.body()).unwrap(),
+            Preferences::default(),
+            "new users get defaults"
+        );
+    }
+
+    {
+        // Test changing preferences.
+        let res = send(
+            "PUT",
+            json!({"version": 1, "voice": "en-gb", "grid": {"columns": 6, "rows": 4}}),
+        )
+        .await;
+        assert_eq!(res.status(), 200, "preferences replaced");
+        let preferences = serde_json::from_slice::<Preferences>(res.body()).unwrap();
+        assert_eq!(
+            preferences,
+            Preferences {
+                voice: "en-gb".to_string(),
+                grid: Grid {
+                    columns: 6,
+                    rows: 4
+                },
+                ..Default::default()
+            },
+            "missing settings take defaults"
+        );
+
+        let res = send(
+            "PATCH",
+            json!({"rate": 140, "high_contrast": true, "label_position": "above"}),
+        )
+        .await;
+        assert_eq!(res.status(), 200, "preferences updated");
+        let preferences = serde_json::from_slice::<Preferences>(res.body()).unwrap();
+        assert_eq!(preferences.voice, "en-gb", "other settings kept");
+        assert_eq!(preferences.rate, 140, "rate changed");
+        assert!(preferences.high_contrast, "high contrast changed");
+        assert_eq!(preferences.label_position, LabelPosition::Above);
+
+        for bad in [
+            json!({"rate": 10}),
+            json!({"pitch": 100}),
+            json!({"voice": "--help"}),
+            json!({"font_scale": 10.0}),
+            json!({"grid": {"columns": 0, "rows": 3}}),
+            json!({"colour": "red"}),
+            json!({"version": 99}),
+            json!([]),
+        ]
+        .iter()
+        {
+            let res = send("PATCH", bad.clone()).await;
+            assert_eq!(res.status(), 400, "{} rejected", bad);
+        }
+    }
+
+    {
+        // Test settings no longer known are ignored in stored documents.
+        db::get_db_conn(&pool)
+            .await
+            .unwrap()
+            .execute(
+                r#"
+                UPDATE preferences SET document = document || '{"retired": true}'
+                WHERE user_id = (SELECT id FROM users WHERE username = 'preferences_flow')
+                "#,
+                &[],
+            )
+            .await
+            .unwrap();
+        let res = send("GET", json!(null)).await;
+        assert_eq!(res.status(), 200, "stored document still read");
+        let res = send("PATCH", json!({"pitch": 60})).await;
+        assert_eq!(res.status(), 200, "stored document still updated");
+        let preferences = serde_json::from_slice::<Preferences>(res.body()).unwrap();
+        assert_eq!(preferences.rate, 140, "stored settings kept");
+        assert_eq!(preferences.pitch, 60, "pitch changed");
+        let res = send("PATCH", json!({"retired": true})).await;
+        assert_eq!(res.status(), 400, "unknown settings still rejected");
+    }
+
+    {
+        // Test including preferences with the user.
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/preferences_flow")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        let user = serde_json::from_slice::<user::User>(res.body()).unwrap();
+        assert_eq!(user.preferences, None, "preferences left out by default");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/preferences_flow?include=preferences")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "user ok");
+        let user = serde_json::from_slice::<user::User>(res.body()).unwrap();
+        let preferences = user.preferences.expect("preferences included");
+        assert_eq!(preferences.rate, 140, "stored preferences included");
+    }
+}
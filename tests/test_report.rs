@@ -0,0 +1,172 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use open_comm::{
+    app, auth,
+    report::{Report, TileCount},
+    Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn report_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a child and a stranger.
+    let mut tokens = Vec::new();
+    for username in &["report_child", "report_stranger"] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: username.to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        tokens.push(
+            serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+                .unwrap()
+                .token,
+        );
+    }
+    let (child, stranger) = (tokens[0].clone(), tokens[1].clone());
+
+    // Two days of logged usage.
+    let conn = open_comm::db::get_db_conn(&pool).await.unwrap();
+    for (kind, text, at) in &[
+        ("tile", "I", "2026-03-01 09:00:00Z"),
+        ("tile", "want", "2026-03-01 09:00:01Z"),
+        ("tile", "juice", "2026-03-01 09:00:02Z"),
+        ("utterance", "I want juice", "2026-03-01 09:00:03Z"),
+        ("tile", "want", "2026-03-02 23:59:00Z"),
+        ("tile", "more", "2026-03-02 23:59:01Z"),
+        ("utterance", "Want more", "2026-03-02 23:59:02Z"),
+        ("tile", "more", "2026-03-04 00:00:00Z"),
+    ] {
+        conn.execute(
+            r#"
+            INSERT INTO usage_events (user_id, kind, phrase, text, occurred_at)
+            SELECT id, $2, CASE WHEN $2 = 'tile' THEN $3 END, $3, $4::TEXT::TIMESTAMPTZ
+            FROM users WHERE username = $1
+            "#,
+            &[&"report_child", kind, text, at],
+        )
+        .await
+        .unwrap();
+    }
+
+    let fetch = |token: &str, query: &str| {
+        warp::test::request()
+            .method("GET")
+            .path(format!("/api/user/report_child/report{}", query).as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+    };
+
+    {
+        // Test the figures.
+        let res = fetch(&child, "?from=2026-03-01&to=2026-03-03").await;
+        assert_eq!(res.status(), 200, "report ok");
+        let report = serde_json::from_slice::<Report>(res.body()).unwrap();
+        assert_eq!(report.totals.words, 5, "words from tiles in range");
+        assert_eq!(report.totals.unique_words, 4, "unique vocabulary");
+        assert_eq!(report.totals.core_words, 4, "core words");
+        assert_eq!(report.totals.core_ratio, Some(0.8), "core ratio");
+        assert_eq!(report.totals.utterances, 2, "utterances");
+        assert_eq!(
+            report.totals.mean_length_of_utterance,
+            Some(2.5),
+            "mean length of utterance"
+        );
+        assert!(
+            (report.words_per_day - 5.0 / 3.0).abs() < 1e-9,
+            "words per day"
+        );
+        assert_eq!(
+            report.most_used[0],
+            TileCount {
+                phrase: "want".to_string(),
+                count: 2
+            },
+            "most used tile first"
+        );
+        assert_eq!(report.most_used.len(), 4, "every tile used");
+        assert_eq!(report.days.len(), 3, "every day in range");
+        assert_eq!(report.days[0].figures.words, 3, "first day");
+        assert_eq!(
+            report.days[0].figures.mean_length_of_utterance,
+            Some(3.0),
+            "first day's utterance"
+        );
+        assert_eq!(report.days[1].figures.words, 2, "second day");
+        assert_eq!(report.days[2].figures.words, 0, "quiet day");
+        assert_eq!(report.days[2].figures.mean_length_of_utterance, None);
+    }
+
+    {
+        // Test CSV.
+        let res = fetch(&child, "?from=2026-03-01&to=2026-03-03&format=csv").await;
+        assert_eq!(res.status(), 200, "CSV ok");
+        assert_eq!(res.headers()["Content-Type"], "text/csv; charset=utf-8");
+        let csv = String::from_utf8_lossy(res.body()).to_string();
+        let lines = csv.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines,
+            vec![
+                "date,words,unique_words,core_words,core_ratio,utterances,mean_length_of_utterance",
+                "2026-03-01,3,3,2,0.667,1,3.000",
+                "2026-03-02,2,2,2,1.000,1,2.000",
+                "2026-03-03,0,0,0,,0,",
+            ],
+            "one row per day"
+        );
+    }
+
+    {
+        // Test invalid requests.
+        assert_eq!(
+            fetch(&child, "?from=2026-03-03&to=2026-03-01")
+                .await
+                .status(),
+            400,
+            "backwards range"
+        );
+        assert_eq!(
+            fetch(&child, "?from=2025-01-01&to=2026-03-01")
+                .await
+                .status(),
+            400,
+            "range too long"
+        );
+        assert_eq!(fetch(&stranger, "").await.status(), 401, "stranger refused");
+    }
+}
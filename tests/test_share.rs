@@ -0,0 +1,222 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use open_comm::{app, auth, share, tile, Config, JWTConfig};
+
+mod common;
+
+#[tokio::test]
+async fn share_flow() {
+    let api = app(
+        common::db_pool().await,
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "share_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    // Fill two boards.
+    for (boundary, body) in &[
+        (
+            "------------------------0af30d233b54bac0",
+            &include_bytes!("tile_create.bin")[..],
+        ),
+        (
+            "------------------------0b56506eb827d2ac",
+            &include_bytes!("tile_create2.bin")[..],
+        ),
+    ] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/share_flow/tiles")
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .header("Authorization", format!("Bearer {}", token))
+            .body(*body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+
+    // Share the favorites board behind a PIN.
+    let share = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/share_flow/shares")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&share::NewShare {
+                category: Some("favorite".to_string()),
+                expires_in: None,
+                pin: Some("1234".to_string()),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "share created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        let share = serde_json::from_str::<share::Share>(body.as_ref()).unwrap();
+        assert!(share.has_pin, "share is protected by a PIN");
+        share
+    };
+
+    {
+        // Test viewing the shared board.
+        let res = warp::test::request()
+            .method("GET")
+            .path(share.url.as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401, "shared board requires the PIN");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(format!("{}?pin=1234", share.url).as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "shared board ok");
+        let body = String::from_utf8_lossy(res.body());
+        let page = serde_json::from_str::<tile::TilePage>(body.as_ref()).unwrap();
+        assert_eq!(page.tiles.len(), 1, "only the shared board is visible");
+        assert_eq!(page.tiles[0].phrase, "pizza");
+        assert!(
+            !page.tiles[0].image.contains("1234"),
+            "media links don't reveal the PIN"
+        );
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(page.tiles[0].image.as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "shared images are readable");
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/share_flow/shares")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&share::NewShare {
+                category: Some("drinks".to_string()),
+                expires_in: Some(60),
+                pin: None,
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "share created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        let drinks = serde_json::from_str::<share::Share>(body.as_ref()).unwrap();
+        let image = page.tiles[0].image.split('?').next().unwrap();
+        let res = warp::test::request()
+            .method("GET")
+            .path(format!("{}?share={}", image, drinks.token).as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404, "images off the board are not shared");
+    }
+
+    {
+        // Test wrong PINs lock the share.
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/share_flow/shares")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&share::NewShare {
+                category: None,
+                expires_in: Some(60),
+                pin: Some("5678".to_string()),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "share created new resource");
+        let locked = serde_json::from_slice::<share::Share>(res.body()).unwrap();
+        let view = |pin: &str| {
+            warp::test::request()
+                .method("GET")
+                .path(format!("{}?pin={}", locked.url, pin).as_str())
+                .reply(&api)
+        };
+        assert_eq!(view("0000").await.status(), 401, "wrong PIN refused");
+        assert_eq!(view("5678").await.status(), 200, "right PIN accepted");
+        for _ in 0..5 {
+            assert_eq!(view("0000").await.status(), 401, "wrong PIN refused");
+        }
+        assert_eq!(view("5678").await.status(), 401, "share locked");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path(format!("/api/user/share_flow/shares/{}", locked.token).as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "share revoked");
+    }
+
+    {
+        // Test revoking the share.
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/share_flow/shares")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "share query ok");
+        let body = String::from_utf8_lossy(res.body());
+        let shares = serde_json::from_str::<Vec<share::Share>>(body.as_ref()).unwrap();
+        assert_eq!(shares.len(), 2, "shares are listed");
+        assert_eq!(shares[1], share, "share is listed");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path(format!("/api/user/share_flow/shares/{}", share.token).as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "share revoked");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(format!("{}?pin=1234", share.url).as_str())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404, "revoked share is gone");
+    }
+}
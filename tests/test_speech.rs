@@ -0,0 +1,255 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    lexicon::Pronunciation,
+    speech::{Engine, Espeak, SpeechConfig, Voice},
+    Config, Error, JWTConfig,
+};
+
+mod common;
+
+/// Says what it was asked to, in writing, and counts how often it was asked.
+#[derive(Default)]
+struct FakeEngine {
+    calls: AtomicUsize,
+}
+
+#[async_trait]
+impl Engine for FakeEngine {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    fn content_type(&self) -> &str {
+        "audio/wav"
+    }
+
+    async fn synthesize(&self, text: &str, voice: &Voice) -> Result<Vec<u8>, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(format!("{}/{}:{}", voice.name, voice.rate, text).into_bytes())
+    }
+}
+
+#[tokio::test]
+async fn speech_flow() {
+    let pool = common::db_pool().await;
+    let engine = Arc::new(FakeEngine::default());
+    let cache = tempfile::tempdir().unwrap();
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            speech: SpeechConfig {
+                engine: Some(engine.clone()),
+                cache: Some(cache.path().to_path_buf()),
+                cache_bytes: None,
+            },
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "speech_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let fetch = |path: &str| {
+        let api = api.clone();
+        let token = token.clone();
+        let path = path.to_string();
+        async move {
+            warp::test::request()
+                .method("GET")
+                .path(path.as_str())
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&api)
+                .await
+        }
+    };
+
+    {
+        // Test speaking utterances.
+        let res = fetch("/api/user/speech_flow/speech?text=good%20morning").await;
+        assert_eq!(res.status(), 200, "utterance spoken");
+        assert_eq!(
+            res.headers()["Content-Type"],
+            "audio/wav",
+            "typed by engine"
+        );
+        assert_eq!(res.body().as_ref(), b"en/175:good morning", "default voice");
+
+        let res = fetch("/api/user/speech_flow/speech?text=good%20morning").await;
+        assert_eq!(res.body().as_ref(), b"en/175:good morning", "cached");
+        assert_eq!(engine.calls.load(Ordering::SeqCst), 1, "spoken only once");
+
+        let res =
+            fetch("/api/user/speech_flow/speech?text=good%20morning&voice=en-us&rate=200").await;
+        assert_eq!(
+            res.body().as_ref(),
+            b"en-us/200:good morning",
+            "voice chosen"
+        );
+        assert_eq!(
+            engine.calls.load(Ordering::SeqCst),
+            2,
+            "voice is in the key"
+        );
+
+        for bad in [
+            "/api/user/speech_flow/speech",
+            "/api/user/speech_flow/speech?text=%20",
+            "/api/user/speech_flow/speech?text=hi&rate=1000",
+            "/api/user/speech_flow/speech?text=hi&voice=--help",
+        ]
+        .iter()
+        {
+            assert_eq!(fetch(bad).await.status(), 400, "{} rejected", bad);
+        }
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/speech_flow/speech?text=hi")
+            .reply(&api)
+            .await;
+        assert!(res.status().is_client_error(), "speech needs a token");
+    }
+
+    {
+        // Test speaking tiles.
+        let (content_type, mut body) = common::tile_form(
+            "hello",
+            &["greetings"],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let boundary = "------------------------tileform";
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        body.truncate(body.len() - tail.len());
+        body.extend_from_slice(
+            format!(
+                "\r\n--{b}\r\nContent-Disposition: form-data; name=\"speech\"\r\n\r\n\
+                 hello there{}",
+                tail,
+                b = boundary
+            )
+            .as_bytes(),
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/speech_flow/tiles")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+
+        let res = fetch("/api/user/speech_flow/tiles/hello/speech").await;
+        assert_eq!(res.status(), 200, "tile spoken");
+        assert_eq!(
+            res.body().as_ref(),
+            b"en/175:hello there",
+            "speech text used"
+        );
+        assert_eq!(
+            res.headers()["Cache-Control"],
+            "private, no-cache",
+            "tile speech is revalidated"
+        );
+        let etag = res.headers()["ETag"].to_str().unwrap().to_string();
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/speech_flow/tiles/hello/speech")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("If-None-Match", etag)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 304, "unchanged tile speech not resent");
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/speech_flow/pronunciations")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&Pronunciation {
+                word: "hello".to_string(),
+                replacement: "hullo".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "pronunciation added");
+        let res = fetch("/api/user/speech_flow/tiles/hello/speech").await;
+        assert_eq!(
+            res.body().as_ref(),
+            b"en/175:hullo there",
+            "pronunciation used"
+        );
+
+        let res = warp::test::request()
+            .method("PATCH")
+            .path("/api/user/speech_flow/preferences")
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({"voice": "en-gb", "rate": 120}))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "preferences updated");
+        let res = fetch("/api/user/speech_flow/tiles/hello/speech").await;
+        assert_eq!(
+            res.body().as_ref(),
+            b"en-gb/120:hullo there",
+            "preferred voice used"
+        );
+
+        let res = fetch("/api/user/speech_flow/tiles/missing/speech").await;
+        assert_eq!(res.status(), 404, "unknown tile");
+    }
+
+    {
+        // Test a missing eSpeak NG installation.
+        let espeak = Espeak::new("/nonexistent/espeak-ng");
+        match espeak.synthesize("hi", &Voice::default()).await {
+            Err(Error::SpeechUnavailable(_)) => (),
+            _ => panic!("missing eSpeak NG is reported"),
+        }
+    }
+}
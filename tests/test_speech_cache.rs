@@ -0,0 +1,125 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use open_comm::{
+    app, auth,
+    speech::{Engine, SpeechConfig, Voice},
+    Config, Error, JWTConfig,
+};
+
+mod common;
+
+/// Says what it was asked to, in writing, and counts how often it was asked.
+#[derive(Default)]
+struct FakeEngine {
+    calls: AtomicUsize,
+}
+
+#[async_trait]
+impl Engine for FakeEngine {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    fn content_type(&self) -> &str {
+        "audio/wav"
+    }
+
+    async fn synthesize(&self, text: &str, voice: &Voice) -> Result<Vec<u8>, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(format!("{}/{}:{}", voice.name, voice.rate, text).into_bytes())
+    }
+}
+
+#[tokio::test]
+async fn speech_cache_flow() {
+    let pool = common::db_pool().await;
+    let engine = Arc::new(FakeEngine::default());
+    let cache = tempfile::tempdir().unwrap();
+    // Room for two of the ten byte recordings below.
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            speech: SpeechConfig {
+                engine: Some(engine.clone()),
+                cache: Some(cache.path().to_path_buf()),
+                cache_bytes: Some(25),
+            },
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "speech_cache_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    // Test the least recently used recordings are forgotten once the cache is full.
+    let mut expected_calls = 0;
+    for (text, spoken) in [
+        ("one", true),
+        ("two", true),
+        ("one", false),
+        ("six", true),
+        ("one", false),
+        ("two", true),
+    ]
+    .iter()
+    {
+        let res = warp::test::request()
+            .method("GET")
+            .path(format!("/api/user/speech_cache_flow/speech?text={}", text).as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "utterance spoken");
+        assert_eq!(res.body().as_ref(), format!("en/175:{}", text).as_bytes());
+        if *spoken {
+            expected_calls += 1;
+        }
+        assert_eq!(
+            engine.calls.load(Ordering::SeqCst),
+            expected_calls,
+            "least recently used recording forgotten before {}",
+            text
+        );
+    }
+}
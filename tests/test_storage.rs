@@ -0,0 +1,152 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use open_comm::{app, auth, db, storage, tile, Config, JWTConfig};
+
+mod common;
+
+#[tokio::test]
+async fn storage_flow() {
+    let pool = common::db_pool().await;
+    let dir = tempfile::tempdir().unwrap();
+    let fs_config = storage::StorageConfig::Filesystem(dir.path().to_path_buf());
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            storage: fs_config.clone(),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "storage_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    // Images uploaded with the filesystem backend land in files named after their hash.
+    let image = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/storage_flow/tiles")
+            .header(
+                "Content-Type",
+                "multipart/form-data; boundary=------------------------0af30d233b54bac0",
+            )
+            .header("Authorization", format!("Bearer {}", token))
+            .body(&include_bytes!("tile_create.bin")[..])
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+        serde_json::from_slice::<tile::Tile>(res.body())
+            .unwrap()
+            .image
+    };
+    let hash = image.trim_start_matches("/api/image/").to_owned();
+    let file = dir.path().join(&hash[..2]).join(&hash);
+    let contents = std::fs::read(&file).expect("image stored as a file");
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(image.as_str())
+        .header("Authorization", format!("Bearer {}", token))
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), 200, "image read from the filesystem");
+    assert_eq!(res.body().as_ref(), contents.as_slice(), "image intact");
+
+    // Move everything into the database and back again.
+    let conn = db::get_db_conn(&pool).await.unwrap();
+    let fs_storage = storage::open(&fs_config, pool.clone()).await.unwrap();
+    let pg_storage = storage::open(&storage::StorageConfig::Postgres, pool.clone())
+        .await
+        .unwrap();
+    let moved = storage::migrate(&pool, fs_storage.as_ref(), pg_storage.as_ref())
+        .await
+        .unwrap();
+    assert_eq!(moved, 1, "every image migrated");
+    assert!(!file.exists(), "migrated image removed from the filesystem");
+    assert_eq!(
+        pg_storage.get(&hash).await.unwrap(),
+        contents,
+        "image intact in the database"
+    );
+
+    let moved = storage::migrate(&pool, pg_storage.as_ref(), fs_storage.as_ref())
+        .await
+        .unwrap();
+    assert_eq!(moved, 1, "every image migrated back");
+    let rows = conn
+        .query("SELECT 1 FROM blobs WHERE hash = $1", &[&hash])
+        .await
+        .unwrap();
+    assert!(rows.is_empty(), "migrated image removed from the database");
+    assert_eq!(
+        std::fs::read(&file).unwrap(),
+        contents,
+        "image intact on the filesystem"
+    );
+
+    // Migrating a backend onto itself is refused before anything moves.
+    let same = format!("fs:{}", dir.path().display());
+    let same_written_differently = format!("fs:{}/.", dir.path().display());
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_open-comm"))
+        .args(["migrate-images", &same, &same_written_differently])
+        .env("DATABASE_URL", common::db_url())
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "migrating onto itself fails");
+    assert!(file.exists(), "image kept");
+    assert!(storage::same_backend(
+        &storage::StorageConfig::Postgres,
+        &storage::StorageConfig::Postgres
+    )
+    .await
+    .unwrap());
+    assert!(
+        !storage::same_backend(&fs_config, &storage::StorageConfig::Postgres)
+            .await
+            .unwrap()
+    );
+
+    // Deleting the last tile using an image drops its file too.
+    let res = warp::test::request()
+        .method("DELETE")
+        .path("/api/user/storage_flow/tiles/pizza")
+        .header("Authorization", format!("Bearer {}", token))
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), 200, "tile delete ok");
+    assert!(!file.exists(), "unreferenced image is collected");
+}
@@ -0,0 +1,76 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use open_comm::svg;
+
+fn sanitize(svg: &str) -> String {
+    String::from_utf8(svg::sanitize(svg.as_bytes()).expect("well-formed SVG")).unwrap()
+}
+
+#[test]
+fn svg_sanitize() {
+    assert_eq!(
+        sanitize(
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(1)</script><rect width="1"/></svg>"#
+        ),
+        r#"<svg xmlns="http://www.w3.org/2000/svg"><rect width="1"/></svg>"#,
+        "scripts are removed"
+    );
+    assert_eq!(
+        sanitize(r#"<svg onload="alert(1)"><g onClick='alert(2)' fill="red"/></svg>"#),
+        r#"<svg><g fill="red"/></svg>"#,
+        "event handlers are removed"
+    );
+    assert_eq!(
+        sanitize(
+            r#"<svg><foreignObject><body xmlns="http://www.w3.org/1999/xhtml"><iframe/></body></foreignObject><path d="M0 0"/></svg>"#
+        ),
+        r#"<svg><path d="M0 0"/></svg>"#,
+        "foreign objects are removed"
+    );
+    assert_eq!(
+        sanitize(
+            r##"<svg><use xlink:href="http://evil.example/x.svg#a"/><use href="#b"/><a href="javascript:alert(1)"><image href="data:image/png;base64,AA=="/></a></svg>"##
+        ),
+        r##"<svg><use/><use href="#b"/><a><image href="data:image/png;base64,AA=="/></a></svg>"##,
+        "only internal references and raster data are kept"
+    );
+    assert_eq!(
+        sanitize(
+            r##"<svg><style>@import url(http://evil.example/a.css);</style><style>.a{fill:url(#g)}</style><rect style="fill:url(http://evil.example/)" fill="url(#g)"/></svg>"##
+        ),
+        r##"<svg><style></style><style>.a{fill:url(#g)}</style><rect fill="url(#g)"/></svg>"##,
+        "styles may not load anything"
+    );
+    assert_eq!(
+        sanitize(r##"<svg><a><set attributeName="href" to="javascript:alert(1)"/></a></svg>"##),
+        r##"<svg><a></a></svg>"##,
+        "links can't be animated"
+    );
+    assert_eq!(
+        sanitize(
+            r#"<?xml version="1.0"?><!DOCTYPE svg [<!ENTITY ns "http://www.w3.org/2000/svg"><!ENTITY a "&ns;&ns;">]><!-- drawn by hand --><svg xmlns="&ns;">&a;</svg>"#
+        ),
+        r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg">&a;</svg>"#,
+        "simple entities are expanded and nothing else"
+    );
+    assert!(
+        svg::sanitize(b"<svg><g></svg>").is_err(),
+        "malformed documents are rejected"
+    );
+}
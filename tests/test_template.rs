@@ -0,0 +1,170 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    template::{Rendered, Selections},
+    tile, Config, JWTConfig,
+};
+
+mod common;
+
+/// Builds a multipart form for a template tile saying `speech`, with the category of each of
+/// its `slots` as JSON, returning its content type and body.
+fn template_form(phrase: &str, speech: &str, slots: &str) -> (String, Vec<u8>) {
+    let boundary = "------------------------templateform";
+    let mut body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"phrase\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"categories\"\r\n\r\n[\"templates\"]\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"speech\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"slots\"\r\n\r\n{}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"image\"\r\n\
+         Content-Type: image/png\r\n\r\n",
+        phrase,
+        speech,
+        slots,
+        b = boundary,
+    )
+    .into_bytes();
+    body.extend_from_slice(&common::blank_image(8, 8, ImageFormat::Png));
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+#[tokio::test]
+async fn template_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a new user.
+    let token = {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: "template_flow".to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+            .unwrap()
+            .token
+    };
+
+    let request = |method: &str, path: &str| {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Bearer {}", token))
+    };
+    for (phrase, category) in [("apple", "food"), ("juice", "drinks"), ("park", "places")].iter() {
+        let (content_type, body) = common::tile_form(
+            phrase,
+            &[category],
+            &common::blank_image(8, 8, ImageFormat::Png),
+            "image/png",
+        );
+        let res = request("POST", "/api/user/template_flow/tiles")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "new tile created new resource");
+    }
+    let create = |speech: &str, slots: &str| {
+        let (content_type, body) = template_form("want", speech, slots);
+        request("POST", "/api/user/template_flow/tiles")
+            .header("Content-Type", content_type)
+            .body(body)
+            .reply(&api)
+    };
+    let render = |slots: &[(&str, &str)]| {
+        request("POST", "/api/user/template_flow/tiles/want/render")
+            .json(&Selections {
+                slots: slots
+                    .iter()
+                    .map(|(slot, phrase)| (slot.to_string(), phrase.to_string()))
+                    .collect(),
+            })
+            .reply(&api)
+    };
+
+    {
+        // Test creating templates.
+        let res = create("I want {thing} please", r#"{"thing": "toys"}"#).await;
+        assert_eq!(res.status(), 400, "slot categories must exist");
+        let res = create("I want {thing} please", r#"{"food": "food"}"#).await;
+        assert_eq!(res.status(), 400, "slots must be named in the text");
+        let res = create("I want {thing} please", r#"{}"#).await;
+        assert_eq!(res.status(), 201, "without slots braces are text");
+        let res = request("DELETE", "/api/user/template_flow/tiles/want")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile deleted");
+
+        let res = create(
+            "I want {food} and {drink} please",
+            r#"{"food": "food", "drink": "drinks"}"#,
+        )
+        .await;
+        assert_eq!(res.status(), 201, "template created");
+        let template = serde_json::from_slice::<tile::Tile>(res.body()).unwrap();
+        assert_eq!(template.slots["food"], "food");
+        assert_eq!(template.slots["drink"], "drinks");
+    }
+
+    {
+        // Test rendering.
+        let res = render(&[("food", "apple"), ("drink", "juice")]).await;
+        assert_eq!(res.status(), 200, "template rendered");
+        assert_eq!(
+            serde_json::from_slice::<Rendered>(res.body()).unwrap().text,
+            "I want apple and juice please"
+        );
+
+        let res = render(&[("food", "apple")]).await;
+        assert_eq!(res.status(), 400, "every slot filled");
+        let res = render(&[("food", "park"), ("drink", "juice")]).await;
+        assert_eq!(res.status(), 400, "slots filled from their category");
+        let res = render(&[("food", "apple"), ("drink", "juice"), ("place", "park")]).await;
+        assert_eq!(res.status(), 400, "unknown slots rejected");
+        let res = request("POST", "/api/user/template_flow/tiles/apple/render")
+            .json(&Selections::default())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "only templates rendered");
+        let res = request("POST", "/api/user/template_flow/tiles/nothing/render")
+            .json(&Selections::default())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404, "unknown template");
+    }
+}
@@ -16,7 +16,7 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use open_comm::{app, auth, tile, JWTConfig};
+use open_comm::{app, auth, db, tile, Config, JWTConfig};
 
 mod common;
 
@@ -24,7 +24,10 @@ mod common;
 async fn tile_flow() {
     let api = app(
         common::db_pool().await,
-        Some(JWTConfig::Secret(common::secret())),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
     )
     .await
     .expect("app initialized");
@@ -119,9 +122,9 @@ async fn tile_flow() {
             .await;
         assert_eq!(res.status(), 200, "tile query ok");
         let body = String::from_utf8_lossy(res.body());
-        let maybe_tiles = serde_json::from_str::<Vec<tile::Tile>>(body.as_ref());
-        assert!(maybe_tiles.is_ok(), "tile query responds with valid data");
-        let tile = maybe_tiles.unwrap();
+        let maybe_page = serde_json::from_str::<tile::TilePage>(body.as_ref());
+        assert!(maybe_page.is_ok(), "tile query responds with valid data");
+        let tile = maybe_page.unwrap().tiles;
         assert_eq!(
             tile[0].phrase, "pizza",
             "tile query responds with correct phrase"
@@ -144,9 +147,9 @@ async fn tile_flow() {
             .await;
         assert_eq!(res.status(), 200, "tile query ok");
         let body = String::from_utf8_lossy(res.body());
-        let maybe_tiles = serde_json::from_str::<Vec<tile::Tile>>(body.as_ref());
-        assert!(maybe_tiles.is_ok(), "tile query responds with valid data");
-        let tile = maybe_tiles.unwrap();
+        let maybe_page = serde_json::from_str::<tile::TilePage>(body.as_ref());
+        assert!(maybe_page.is_ok(), "tile query responds with valid data");
+        let tile = maybe_page.unwrap().tiles;
         assert_eq!(
             tile[0].phrase, "pizza",
             "tile query responds with correct phrase"
@@ -158,6 +161,165 @@ async fn tile_flow() {
         );
     }
 
+    {
+        // Test search.
+        let search = |query: &'static str| {
+            let api = api.clone();
+            let token = token.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("GET")
+                    .path(format!("/api/user/tile_flow/tiles?phrase={}", query).as_str())
+                    .header("Accept", "application/json")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .reply(&api)
+                    .await;
+                assert_eq!(res.status(), 200, "tile search ok");
+                let body = String::from_utf8_lossy(res.body());
+                serde_json::from_str::<tile::TilePage>(body.as_ref())
+                    .unwrap()
+                    .tiles
+                    .into_iter()
+                    .map(|t| t.phrase)
+                    .collect::<Vec<String>>()
+            }
+        };
+        assert_eq!(search("PIZ").await, vec!["pizza"], "search ignores case");
+        assert_eq!(
+            search("sp%C3%ADnach").await,
+            vec!["spinach"],
+            "search ignores accents"
+        );
+        assert_eq!(
+            search("piza").await,
+            vec!["pizza"],
+            "search tolerates typos"
+        );
+        let mut favorites = search("favorite").await;
+        favorites.sort();
+        assert_eq!(
+            favorites,
+            vec!["pizza", "spinach"],
+            "search matches categories"
+        );
+        assert!(search("%25").await.is_empty(), "search escapes wildcards");
+    }
+
+    {
+        // Test pagination.
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/tile_flow/tiles?limit=1")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile page ok");
+        let body = String::from_utf8_lossy(res.body());
+        let page = serde_json::from_str::<tile::TilePage>(body.as_ref()).unwrap();
+        assert_eq!(page.total, 2, "tile page counts every match");
+        assert_eq!(page.tiles.len(), 1, "tile page respects the limit");
+        assert_eq!(page.tiles[0].phrase, "pizza", "tile page sorts by phrase");
+        let cursor = page.next_cursor.expect("tile page has a next cursor");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(format!("/api/user/tile_flow/tiles?limit=1&cursor={}", cursor).as_str())
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "next tile page ok");
+        let body = String::from_utf8_lossy(res.body());
+        let page = serde_json::from_str::<tile::TilePage>(body.as_ref()).unwrap();
+        assert_eq!(page.tiles[0].phrase, "spinach", "next tile page continues");
+        assert_eq!(page.next_cursor, None, "last tile page has no cursor");
+
+        // A cursor only makes sense for the sort it came from.
+        let res = warp::test::request()
+            .method("GET")
+            .path(format!("/api/user/tile_flow/tiles?sort=most_used&cursor={}", cursor).as_str())
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400, "mismatched cursor is rejected");
+    }
+
+    {
+        // Test paging through a search, whose relevance scores are fractional.
+        let mut phrases = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let path = match &cursor {
+                Some(cursor) => format!(
+                    "/api/user/tile_flow/tiles?phrase=pi&limit=1&cursor={}",
+                    cursor
+                ),
+                None => "/api/user/tile_flow/tiles?phrase=pi&limit=1".to_string(),
+            };
+            let res = warp::test::request()
+                .method("GET")
+                .path(path.as_str())
+                .header("Accept", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200, "search page ok");
+            let page = serde_json::from_slice::<tile::TilePage>(res.body()).unwrap();
+            assert_eq!(page.total, 2, "search page counts every match");
+            phrases.extend(page.tiles.into_iter().map(|tile| tile.phrase));
+            cursor = page.next_cursor;
+            if cursor.is_none() || phrases.len() > 2 {
+                break;
+            }
+        }
+        phrases.sort();
+        assert_eq!(
+            phrases,
+            vec!["pizza", "spinach"],
+            "search pages neither repeat nor skip tiles"
+        );
+    }
+
+    {
+        // Test sort orders.
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/tile_flow/tiles?sort=recently_created")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "sorted tile query ok");
+        let body = String::from_utf8_lossy(res.body());
+        let page = serde_json::from_str::<tile::TilePage>(body.as_ref()).unwrap();
+        assert_eq!(
+            page.tiles
+                .iter()
+                .map(|t| t.phrase.as_str())
+                .collect::<Vec<_>>(),
+            vec!["spinach", "pizza"],
+            "newest tiles come first"
+        );
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/tile_flow/tiles?sort=manual")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "sorted tile query ok");
+        let body = String::from_utf8_lossy(res.body());
+        let page = serde_json::from_str::<tile::TilePage>(body.as_ref()).unwrap();
+        assert_eq!(
+            page.tiles.iter().map(|t| t.position).collect::<Vec<_>>(),
+            vec![0, 1],
+            "new tiles are appended to the manual order"
+        );
+    }
+
     {
         // Test query phrase unauth.
         let res = warp::test::request()
@@ -209,4 +371,39 @@ async fn tile_flow() {
             .await;
         assert_eq!(res.status(), 200, "tile delete ok");
     }
+
+    {
+        // Test images are shared between tiles and collected with the last of them.
+        let conn = db::get_db_conn(&common::db_pool().await).await.unwrap();
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/tile_flow/tiles?phrase=spinach")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        let page = serde_json::from_slice::<tile::TilePage>(res.body()).unwrap();
+        let hash = page.tiles[0]
+            .image
+            .trim_start_matches("/api/image/")
+            .to_owned();
+        let row = conn
+            .query_one("SELECT ref_count FROM images WHERE hash = $1", &[&hash])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i32>(0), 1, "deleted tile releases its image");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/user/tile_flow/tiles/spinach")
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile delete ok");
+        let rows = conn
+            .query("SELECT 1 FROM images WHERE hash = $1", &[&hash])
+            .await
+            .unwrap();
+        assert!(rows.is_empty(), "unreferenced image is collected");
+    }
 }
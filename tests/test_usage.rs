@@ -0,0 +1,226 @@
+/*
+ * Copyright (C) 2020 Oakes, Gregory <gregoryoakes@fastmail.com>
+ * Author: Oakes, Gregory <gregory.oakes@fastmail.com>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use image::ImageFormat;
+use open_comm::{
+    app, auth,
+    message::AppendTile,
+    usage::{Activation, UsageKind, UsagePage},
+    Config, JWTConfig,
+};
+
+mod common;
+
+#[tokio::test]
+async fn usage_flow() {
+    let pool = common::db_pool().await;
+    let api = app(
+        pool.clone(),
+        Config {
+            jwt: Some(JWTConfig::Secret(common::secret())),
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("app initialized");
+
+    // Register a child, their therapist and a stranger.
+    let mut tokens = Vec::new();
+    for username in &["usage_child", "usage_therapist", "usage_stranger"] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/register")
+            .header("Content-Type", "application/json")
+            .json(&auth::Register {
+                username: username.to_string(),
+                password: "bar".to_string(),
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "registration created new resource");
+        let body = String::from_utf8_lossy(res.body());
+        tokens.push(
+            serde_json::from_str::<auth::RegisterResp>(body.as_ref())
+                .unwrap()
+                .token,
+        );
+    }
+    let (child, therapist, stranger) = (tokens[0].clone(), tokens[1].clone(), tokens[2].clone());
+
+    let res = warp::test::request()
+        .method("PUT")
+        .path("/api/user/usage_child/caregivers/usage_therapist")
+        .header("Authorization", format!("Bearer {}", child))
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), 200, "caregiver added");
+
+    let (content_type, body) = common::tile_form(
+        "juice",
+        &["drinks"],
+        &common::blank_image(8, 8, ImageFormat::Png),
+        "image/png",
+    );
+    let res = warp::test::request()
+        .method("POST")
+        .path("/api/user/usage_child/tiles")
+        .header("Content-Type", content_type)
+        .header("Authorization", format!("Bearer {}", child))
+        .body(body)
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), 201, "new tile created new resource");
+
+    let activate = |phrase: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/user/usage_child/usage")
+            .header("Authorization", format!("Bearer {}", child))
+            .json(&Activation {
+                phrase: phrase.to_string(),
+                board: Some("drinks".to_string()),
+                device: Some("tablet".to_string()),
+            })
+            .reply(&api)
+    };
+    let usage = |token: &str, query: &str| {
+        warp::test::request()
+            .method("GET")
+            .path(format!("/api/user/usage_child/usage{}", query).as_str())
+            .header("Authorization", format!("Bearer {}", token))
+            .reply(&api)
+    };
+    let conn = open_comm::db::get_db_conn(&pool).await.unwrap();
+    let use_count = || async {
+        conn.query_one(
+            r#"
+            SELECT use_count FROM tile_uses JOIN tiles ON tiles.id = tile_uses.tile_id
+            WHERE phrase = 'juice'
+            "#,
+            &[],
+        )
+        .await
+        .unwrap()
+        .get::<_, i32>(0)
+    };
+
+    {
+        // Test nothing is logged without consent.
+        assert_eq!(activate("juice").await.status(), 200, "tile activated");
+        assert_eq!(activate("water").await.status(), 404, "unknown tile");
+        let res = usage(&child, "").await;
+        assert_eq!(res.status(), 200, "usage ok");
+        let page = serde_json::from_slice::<UsagePage>(res.body()).unwrap();
+        assert!(page.events.is_empty(), "usage not logged before opting in");
+        assert_eq!(use_count().await, 1, "use still counted");
+    }
+
+    {
+        // Test logging tiles and utterances.
+        let res = warp::test::request()
+            .method("PATCH")
+            .path("/api/user/usage_child/preferences")
+            .header("Authorization", format!("Bearer {}", child))
+            .json(&serde_json::json!({"log_usage": true}))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "opted in");
+
+        assert_eq!(activate("juice").await.status(), 200, "tile activated");
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/usage_child/message/tablet/tiles")
+            .header("Authorization", format!("Bearer {}", child))
+            .json(&AppendTile {
+                phrase: "juice".to_string(),
+                board: None,
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "tile appended");
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/user/usage_child/message/tablet/speak")
+            .header("Authorization", format!("Bearer {}", child))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 201, "message spoken");
+        assert_eq!(use_count().await, 3, "appending counts as use");
+
+        let page = serde_json::from_slice::<UsagePage>(usage(&child, "").await.body()).unwrap();
+        let kinds = page.events.iter().map(|e| e.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![UsageKind::Utterance, UsageKind::Tile, UsageKind::Tile],
+            "newest first"
+        );
+        assert_eq!(page.events[0].text, "Juice", "utterance text logged");
+        assert_eq!(
+            page.events[2].board.as_deref(),
+            Some("drinks"),
+            "board logged"
+        );
+        assert_eq!(
+            page.events[2].device.as_deref(),
+            Some("tablet"),
+            "device logged"
+        );
+
+        let page =
+            serde_json::from_slice::<UsagePage>(usage(&child, "?limit=2").await.body()).unwrap();
+        assert_eq!(page.events.len(), 2, "page limited");
+        let cursor = page.next_cursor.expect("another page");
+        let page = serde_json::from_slice::<UsagePage>(
+            usage(&child, &format!("?limit=2&cursor={}", cursor))
+                .await
+                .body(),
+        )
+        .unwrap();
+        assert_eq!(page.events.len(), 1, "rest on the next page");
+        assert_eq!(page.next_cursor, None, "last page");
+
+        let page =
+            serde_json::from_slice::<UsagePage>(usage(&child, "?kind=utterance").await.body())
+                .unwrap();
+        assert_eq!(page.events.len(), 1, "filtered by kind");
+    }
+
+    {
+        // Test who may read the log.
+        assert_eq!(usage(&therapist, "").await.status(), 200, "caregiver reads");
+        assert_eq!(usage(&stranger, "").await.status(), 401, "stranger refused");
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/user/usage_child/usage")
+            .header("Authorization", format!("Bearer {}", child))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "log erased");
+        let page = serde_json::from_slice::<UsagePage>(usage(&child, "").await.body()).unwrap();
+        assert!(page.events.is_empty(), "nothing left");
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/user/usage_child/utterances")
+            .header("Authorization", format!("Bearer {}", child))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200, "history read");
+        assert_eq!(res.body().as_ref(), b"[]", "history erased");
+    }
+}
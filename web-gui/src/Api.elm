@@ -22,6 +22,7 @@ port module Api exposing
     , RegisterForm
     , Tile
     , TileForm
+    , TilePage
     , User
     , addTile
     , application
@@ -180,6 +181,21 @@ tileDecoder =
         |> required "image" Decode.string
 
 
+type alias TilePage =
+    { tiles : List Tile
+    , total : Int
+    , nextCursor : Maybe String
+    }
+
+
+tilePageDecoder : Decoder TilePage
+tilePageDecoder =
+    Decode.succeed TilePage
+        |> required "tiles" (Decode.list tileDecoder)
+        |> required "total" Decode.int
+        |> required "next_cursor" (Decode.nullable Decode.string)
+
+
 addTile : User -> TileForm -> CmdMsg Tile msg -> Cmd msg
 addTile user form msgFromHttp =
     let
@@ -199,12 +215,15 @@ addTile user form msgFromHttp =
         msgFromHttp
 
 
-getTiles : User -> Maybe String -> CmdMsg (List Tile) msg -> Cmd msg
-getTiles user category msgFromHttp =
+{-| Request a page of the tiles in `category`, continuing after `cursor`. Request the page's
+`nextCursor` in turn until there is none to get the rest.
+-}
+getTiles : User -> Maybe String -> Maybe String -> CmdMsg TilePage msg -> Cmd msg
+getTiles user category cursor msgFromHttp =
     get
-        (Endpoint.tile (username user) category)
+        (Endpoint.tile (username user) category cursor)
         (Just user)
-        (Decode.list tileDecoder)
+        tilePageDecoder
         msgFromHttp
 
 
@@ -102,15 +102,23 @@ addTile username =
     url [ "user", username, "tiles" ] []
 
 
-tile : String -> Maybe String -> Endpoint
-tile username category =
+{-| A page of the tiles in `category`, or of every tile, continuing after `cursor`.
+-}
+tile : String -> Maybe String -> Maybe String -> Endpoint
+tile username category cursor =
     let
         query =
-            case category of
-                Just cat ->
-                    [ Builder.string "category" cat ]
-
-                Nothing ->
-                    []
+            List.filterMap identity
+                [ Maybe.map (Builder.string "category") category
+                , Maybe.map (Builder.string "cursor") cursor
+                , Just (Builder.int "limit" tilePageSize)
+                ]
     in
     url [ "user", username, "tiles" ] query
+
+
+{-| The most tiles the server hands out per page.
+-}
+tilePageSize : Int
+tilePageSize =
+    200
@@ -71,7 +71,7 @@ init session category =
       }
     , case session of
         Session.LoggedIn _ user ->
-            Api.getTiles user category (GotTiles cat)
+            Api.getTiles user category Nothing (GotTiles cat Nothing)
 
         Session.Guest _ ->
             Cmd.none
@@ -158,7 +158,7 @@ type Msg
     | SpeakPhrase String
     | FinishedSpeaking String
     | GotAddTileMsg AddTile.Msg
-    | GotTiles String (Result Http.Error (List Api.Tile))
+    | GotTiles String (Maybe String) (Result Http.Error Api.TilePage)
     | AddedTile (Result Http.Error Api.Tile)
     | StartAddingTile
 
@@ -211,14 +211,36 @@ update msg model =
                 _ ->
                     ( { model | addTile = Nothing }, Cmd.none )
 
-        GotTiles cat (Ok tiles) ->
+        GotTiles cat cursor (Ok page) ->
+            let
+                -- Later pages add to the tiles already read, the first replaces them.
+                earlier =
+                    case cursor of
+                        Just _ ->
+                            Maybe.withDefault [] (Dict.get cat model.categories)
+
+                        Nothing ->
+                            []
+
+                category =
+                    if cat == "" then
+                        Nothing
+
+                    else
+                        Just cat
+            in
             ( { model
-                | categories = Dict.insert cat tiles model.categories
+                | categories = Dict.insert cat (earlier ++ page.tiles) model.categories
               }
-            , Cmd.none
+            , case ( Session.user model.session, page.nextCursor ) of
+                ( Just user, Just next ) ->
+                    Api.getTiles user category (Just next) (GotTiles cat (Just next))
+
+                _ ->
+                    Cmd.none
             )
 
-        GotTiles _ (Err _) ->
+        GotTiles _ _ (Err _) ->
             let
                 errMsg =
                     "unable to query tiles"